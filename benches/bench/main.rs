@@ -226,6 +226,11 @@ fn config() -> Config {
                 request_timeout_seconds: 120,
                 http_methods: Vec::new(),
                 cors: None,
+                health: Default::default(),
+                sse: None,
+                max_subscriptions_per_connection: None,
+                max_subscriptions_per_ip: None,
+                ws_ping: None,
             }),
             substrate_api: Some(SubstrateApiConfig {
                 stale_timeout_seconds: 5_000,
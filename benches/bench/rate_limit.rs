@@ -1,6 +1,7 @@
 use criterion::{criterion_group, Criterion};
 use futures_util::future::BoxFuture;
 use futures_util::FutureExt;
+use governor::middleware::StateInformationMiddleware;
 use governor::Jitter;
 use governor::RateLimiter;
 use jsonrpsee::server::middleware::rpc::RpcServiceT;
@@ -37,7 +38,7 @@ pub fn connection_rate_limit(c: &mut Criterion) {
 pub fn ip_rate_limit(c: &mut Criterion) {
     let burst = NonZeroU32::new(1000).unwrap();
     let quota = build_quota(burst, Duration::from_millis(1000));
-    let limiter = RateLimiter::keyed(quota);
+    let limiter = RateLimiter::keyed(quota).with_middleware::<StateInformationMiddleware>();
     let rate_limit = IpRateLimit::new(
         MockService,
         "::1".to_string(),
@@ -213,6 +213,11 @@ pub async fn validate(config: &Config) -> Result<(), anyhow::Error> {
     config.validate(&())?;
 
     if let Some(rate_limit) = config.extensions.rate_limit.as_ref() {
+        // forged X-Forwarded-For headers could get any ip banned
+        if rate_limit.ban.is_some() && rate_limit.use_xff && rate_limit.trusted_proxies.is_empty() {
+            bail!("rate_limit ban with use_xff requires trusted_proxies");
+        }
+
         if let Some(ref rule) = rate_limit.ip {
             for method in &config.rpcs.methods {
                 if method.rate_limit_weight > rule.burst {
@@ -236,6 +241,37 @@ pub async fn validate(config: &Config) -> Result<(), anyhow::Error> {
                 }
             }
         }
+
+        for rule in &rate_limit.methods {
+            for method in &config.rpcs.methods {
+                if rule.methods.contains(&method.method) && method.rate_limit_weight > rule.rule.burst {
+                    bail!(
+                        "`{}` rate_limit_weight is too big for its method rule: {}",
+                        method.method,
+                        method.rate_limit_weight,
+                    );
+                }
+            }
+        }
+    }
+
+    let default_ttl_seconds = config.extensions.cache.as_ref().and_then(|c| c.default_ttl_seconds);
+    for method in &config.rpcs.methods {
+        let Some(cache) = &method.cache else {
+            continue;
+        };
+        let Some(max_age_seconds) = cache.max_age_seconds else {
+            continue;
+        };
+        // stale values are served between ttl_seconds and max_age_seconds, ttl zero means cache forever
+        let ttl_seconds = cache.ttl_seconds.or(default_ttl_seconds).filter(|ttl| *ttl > 0);
+        if !ttl_seconds.is_some_and(|ttl| ttl < max_age_seconds) {
+            bail!(
+                "`{}` cache max_age_seconds must exceed its ttl_seconds: {}",
+                method.method,
+                max_age_seconds,
+            );
+        }
     }
 
     // since endpoints connection test is async
@@ -332,6 +368,22 @@ mod tests {
             .contains("Unable to connect to all endpoints"));
     }
 
+    #[tokio::test]
+    async fn validate_config_fails_for_cache_max_age_within_ttl() {
+        let config = read_config("tests/configs/cache_max_age_within_ttl.yml").expect("Unable to read config file");
+        let result = validate(&config).await;
+        assert!(result.is_err());
+        assert!(result.err().unwrap().to_string().contains("max_age_seconds"));
+    }
+
+    #[tokio::test]
+    async fn validate_config_fails_for_ban_with_untrusted_xff() {
+        let config = read_config("tests/configs/ban_with_untrusted_xff.yml").expect("Unable to read config file");
+        let result = validate(&config).await;
+        assert!(result.is_err());
+        assert!(result.err().unwrap().to_string().contains("trusted_proxies"));
+    }
+
     #[tokio::test]
     async fn validate_config_fails_for_too_big_rate_limit_weight() {
         let config = read_config("tests/configs/big_rate_limit_weight.yml").expect("Unable to read config file");
@@ -7,8 +7,44 @@ use serde::Deserialize;
 pub struct CacheParams {
     #[serde(default)]
     pub size: Option<usize>,
+    /// Bounds the cache by the serialized size of its entries instead of `size`.
+    /// With a shared cache, the most the method may use of it.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    /// With a shared cache, bytes kept for the method even if others need room.
+    #[serde(default)]
+    pub min_bytes: Option<u64>,
+    /// With a shared cache, entries of methods with a higher weight are kept
+    /// longer than recently used entries of other methods. Defaults to 1.
+    #[serde(default)]
+    pub weight: Option<u32>,
     #[serde(default)]
     pub ttl_seconds: Option<u64>,
+    /// Keep responses for finalized blocks in the disk cache, if configured.
+    /// Only for responses that never change once their block is finalized.
+    #[serde(default)]
+    pub persist: bool,
+    /// Keep serving the cached value after `ttl_seconds` while it is refreshed in
+    /// the background, until it is this old.
+    #[serde(default)]
+    pub max_age_seconds: Option<u64>,
+    /// Briefly cache responses that are otherwise never cached, so repeated
+    /// lookups of missing data do not all reach the upstream.
+    #[serde(default)]
+    pub negative: Option<NegativeCacheParams>,
+}
+
+#[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NegativeCacheParams {
+    pub ttl_seconds: u64,
+    /// Codes of errors that do not change when retried, such as invalid params.
+    /// Internal and server busy errors, which include transport errors, are never cached.
+    #[serde(default)]
+    pub error_codes: Vec<i32>,
+    /// Cache null results of requests that do not depend on an unfinalized block.
+    #[serde(default)]
+    pub null: bool,
 }
 
 #[derive(Clone, Deserialize, Debug, Eq, PartialEq)]
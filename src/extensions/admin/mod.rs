@@ -0,0 +1,157 @@
+//! Administrative RPC methods.
+//!
+//! They are not listed by `rpc_methods` and only respond to requests carrying
+//! `Authorization: Bearer <auth_token>`. For WS the header must be sent with
+//! the upgrade request.
+
+use async_trait::async_trait;
+use jsonrpsee::{core::JsonValue, types::ErrorObjectOwned, RpcModule};
+use serde::Deserialize;
+use serde_json::json;
+use std::sync::Arc;
+
+use super::{Extension, ExtensionRegistry};
+use crate::extensions::{cache::Cache, rate_limit::BanList};
+use crate::utils::errors;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct AdminConfig {
+    pub auth_token: String,
+}
+
+pub struct Admin {
+    auth_token: String,
+}
+
+/// Inserted in the request extensions when the request carries the admin token.
+#[derive(Debug, Clone, Copy)]
+pub struct AdminAuthorized;
+
+#[async_trait]
+impl Extension for Admin {
+    type Config = AdminConfig;
+
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        Ok(Self::new(config.clone()))
+    }
+}
+
+impl Admin {
+    pub fn new(config: AdminConfig) -> Self {
+        assert!(!config.auth_token.is_empty(), "admin auth_token must not be empty");
+        Self {
+            auth_token: config.auth_token,
+        }
+    }
+
+    pub fn is_authorized<T>(&self, req: &http::Request<T>) -> bool {
+        req.headers()
+            .get_all(http::header::AUTHORIZATION)
+            .iter()
+            .filter_map(|value| value.to_str().ok()?.strip_prefix("Bearer "))
+            .any(|token| constant_time_eq(token.trim().as_bytes(), self.auth_token.as_bytes()))
+    }
+}
+
+// doesn't leak how many leading bytes of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn ensure_authorized(extensions: &http::Extensions) -> Result<(), ErrorObjectOwned> {
+    match extensions.get::<AdminAuthorized>() {
+        Some(_) => Ok(()),
+        None => Err(errors::failed("Unauthorized")),
+    }
+}
+
+/// `subway_listBans` and `subway_clearBans`.
+pub fn ban_methods(ban_list: Arc<BanList>) -> anyhow::Result<RpcModule<()>> {
+    let mut module = RpcModule::new(());
+
+    let bans = ban_list.clone();
+    module.register_method("subway_listBans", move |_, _, extensions| {
+        ensure_authorized(extensions)?;
+        let list = bans
+            .list()
+            .into_iter()
+            .map(|(ip, remaining)| json!({ "ip": ip, "expires_in_secs": remaining.as_secs() }))
+            .collect::<Vec<_>>();
+        Ok::<JsonValue, ErrorObjectOwned>(JsonValue::Array(list))
+    })?;
+
+    module.register_method("subway_clearBans", move |params, _, extensions| {
+        ensure_authorized(extensions)?;
+        let ip = params.sequence().optional_next::<String>()?;
+        Ok::<usize, ErrorObjectOwned>(ban_list.clear(ip.as_deref()))
+    })?;
+
+    Ok(module)
+}
+
+/// `subway_cacheStats`, `subway_cacheFlush` and `subway_cacheEvict`.
+pub fn cache_methods(cache: Arc<Cache>) -> anyhow::Result<RpcModule<()>> {
+    let mut module = RpcModule::new(());
+
+    let stats = cache.clone();
+    module.register_async_method("subway_cacheStats", move |_, _, extensions| {
+        let cache = stats.clone();
+        async move {
+            ensure_authorized(&extensions)?;
+            let stats = cache.stats().await;
+            Ok::<JsonValue, ErrorObjectOwned>(json!(stats))
+        }
+    })?;
+
+    let flush = cache.clone();
+    module.register_async_method("subway_cacheFlush", move |params, _, extensions| {
+        let cache = flush.clone();
+        async move {
+            ensure_authorized(&extensions)?;
+            let method = params.sequence().optional_next::<String>()?;
+            Ok::<usize, ErrorObjectOwned>(cache.flush(method.as_deref()).await)
+        }
+    })?;
+
+    // params are the ones cached, i.e. after `block_tag` and `inject_params` rewrote them
+    module.register_async_method("subway_cacheEvict", move |params, _, extensions| {
+        let cache = cache.clone();
+        async move {
+            ensure_authorized(&extensions)?;
+            let mut params = params.sequence();
+            let method = params.next::<String>()?;
+            let method_params = params.optional_next::<Vec<JsonValue>>()?.unwrap_or_default();
+            cache
+                .evict(&method, &method_params)
+                .await
+                .map_err(errors::invalid_params)
+        }
+    })?;
+
+    Ok(module)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_bearer_token() {
+        let admin = Admin::new(AdminConfig {
+            auth_token: "secret".to_string(),
+        });
+        let request = |auth: Option<&str>| {
+            let mut builder = http::Request::builder();
+            if let Some(auth) = auth {
+                builder = builder.header(http::header::AUTHORIZATION, auth);
+            }
+            builder.body(()).unwrap()
+        };
+
+        assert!(admin.is_authorized(&request(Some("Bearer secret"))));
+        assert!(!admin.is_authorized(&request(Some("Bearer secre"))));
+        assert!(!admin.is_authorized(&request(Some("Bearer secrets"))));
+        assert!(!admin.is_authorized(&request(Some("secret"))));
+        assert!(!admin.is_authorized(&request(None)));
+    }
+}
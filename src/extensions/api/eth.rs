@@ -1,9 +1,18 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use jsonrpsee::core::JsonValue;
 use serde::Deserialize;
-use tokio::{sync::watch, task::JoinHandle};
+use tokio::{
+    sync::{broadcast, watch},
+    task::JoinHandle,
+};
 
 use crate::extensions::{
     api::{BaseApi, ValueHandle},
@@ -14,6 +23,7 @@ use crate::extensions::{
 pub struct EthApi {
     inner: BaseApi,
     stale_timeout: Duration,
+    finalized_head_supported: Arc<AtomicBool>,
     background_tasks: Vec<JoinHandle<()>>,
 }
 
@@ -47,6 +57,7 @@ impl EthApi {
         let mut this = Self {
             inner: BaseApi::new(head_rx, finalized_head_rx),
             stale_timeout,
+            finalized_head_supported: Arc::new(AtomicBool::new(true)),
             background_tasks: Vec::new(),
         };
 
@@ -64,11 +75,36 @@ impl EthApi {
     }
 
     pub fn current_head(&self) -> Option<(JsonValue, u64)> {
-        self.inner.head_rx.borrow().to_owned()
+        self.inner.current_head()
     }
 
     pub fn current_finalized_head(&self) -> Option<(JsonValue, u64)> {
-        self.inner.finalized_head_rx.borrow().to_owned()
+        self.inner.current_finalized_head()
+    }
+
+    pub fn head_age(&self) -> Option<Duration> {
+        self.inner.head_age()
+    }
+
+    pub fn head_hash(&self, number: u64) -> Option<JsonValue> {
+        self.inner.head_hash(number)
+    }
+
+    pub fn subscribe_heads(&self) -> watch::Receiver<Option<(JsonValue, u64)>> {
+        self.inner.subscribe_heads()
+    }
+
+    pub fn subscribe_orphaned_heads(&self) -> broadcast::Receiver<(JsonValue, u64)> {
+        self.inner.subscribe_orphaned_heads()
+    }
+
+    pub fn stale_timeout(&self) -> Duration {
+        self.stale_timeout
+    }
+
+    /// Returns false if the upstream does not support finalized head subscription.
+    pub fn finalized_head_supported(&self) -> bool {
+        self.finalized_head_supported.load(Ordering::Relaxed)
     }
 
     fn start_background_task(
@@ -78,6 +114,9 @@ impl EthApi {
         finalized_head_tx: watch::Sender<Option<(JsonValue, u64)>>,
     ) {
         let stale_timeout = self.stale_timeout;
+        let head_updated_at = self.inner.head_updated_at.clone();
+        let recent_heads = self.inner.recent_heads.clone();
+        let finalized_head_supported = self.finalized_head_supported.clone();
 
         let client2 = client.clone();
         self.background_tasks.push(tokio::spawn(async move {
@@ -96,7 +135,13 @@ impl EthApi {
                     let hash = super::get_hash(&head)?;
 
                     tracing::debug!("New head: {number} {hash}");
-                    head_tx.send_replace(Some((hash, number)));
+                    super::send_new_head(
+                        &head_tx,
+                        &head_updated_at,
+                        &recent_heads,
+                        (hash, number),
+                        super::get_parent_hash(&head),
+                    );
 
                     let mut sub = client
                         .subscribe("eth_subscribe", ["newHeads".into()].into(), "eth_unsubscribe")
@@ -115,7 +160,13 @@ impl EthApi {
                                     let hash = super::get_hash(&val)?;
 
                                     tracing::debug!("New head: {number} {hash}");
-                                    head_tx.send_replace(Some((hash, number)));
+                                    super::send_new_head(
+                                        &head_tx,
+                                        &head_updated_at,
+                                        &recent_heads,
+                                        (hash, number),
+                                        super::get_parent_hash(&val),
+                                    );
                                 } else {
                                     break;
                                 }
@@ -189,6 +240,7 @@ impl EthApi {
                     if msg.contains("methodnotfound") || msg.contains("invalid") {
                         tracing::warn!("finalized head subscription is not supported: {e}");
                         // finalized head subscription is not supported
+                        finalized_head_supported.store(false, Ordering::Relaxed);
                         break;
                     }
                     tracing::error!("Error in background task: {e}");
@@ -1,5 +1,10 @@
 use jsonrpsee::core::JsonValue;
-use tokio::sync::watch;
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+use tokio::sync::{broadcast, watch};
 
 #[cfg(test)]
 mod tests;
@@ -15,6 +20,8 @@ pub use value_handle::ValueHandle;
 pub(crate) struct BaseApi {
     pub head_rx: watch::Receiver<Option<(JsonValue, u64)>>,
     pub finalized_head_rx: watch::Receiver<Option<(JsonValue, u64)>>,
+    pub head_updated_at: Arc<RwLock<Option<Instant>>>,
+    pub recent_heads: RecentHeads,
 }
 
 impl BaseApi {
@@ -25,6 +32,8 @@ impl BaseApi {
         Self {
             head_rx,
             finalized_head_rx,
+            head_updated_at: Arc::new(RwLock::new(None)),
+            recent_heads: RecentHeads::new(),
         }
     }
 
@@ -35,6 +44,110 @@ impl BaseApi {
     pub fn get_finalized_head(&self) -> ValueHandle<(JsonValue, u64)> {
         ValueHandle::new(self.finalized_head_rx.clone())
     }
+
+    pub fn current_head(&self) -> Option<(JsonValue, u64)> {
+        self.head_rx.borrow().to_owned()
+    }
+
+    pub fn current_finalized_head(&self) -> Option<(JsonValue, u64)> {
+        self.finalized_head_rx.borrow().to_owned()
+    }
+
+    /// Time elapsed since the last new head was received, if any.
+    pub fn head_age(&self) -> Option<Duration> {
+        self.head_updated_at
+            .read()
+            .expect("head_updated_at lock poisoned")
+            .map(|at| at.elapsed())
+    }
+
+    /// Hash of the recent head with the given number, if it is still known.
+    pub fn head_hash(&self, number: u64) -> Option<JsonValue> {
+        self.recent_heads.hash(number)
+    }
+
+    /// Changes whenever a new head is received, intermediate heads may be skipped.
+    pub fn subscribe_heads(&self) -> watch::Receiver<Option<(JsonValue, u64)>> {
+        self.head_rx.clone()
+    }
+
+    /// Heads replaced by a head at the same or a lower height, i.e. orphaned by a reorg.
+    pub fn subscribe_orphaned_heads(&self) -> broadcast::Receiver<(JsonValue, u64)> {
+        self.recent_heads.orphaned_tx.subscribe()
+    }
+}
+
+/// Number of recent heads kept to notice reorgs.
+const RECENT_HEADS: usize = 256;
+
+/// Hashes of the recent heads by number.
+#[derive(Clone)]
+pub(crate) struct RecentHeads {
+    heads: Arc<RwLock<BTreeMap<u64, JsonValue>>>,
+    orphaned_tx: broadcast::Sender<(JsonValue, u64)>,
+}
+
+impl RecentHeads {
+    pub fn new() -> Self {
+        let (orphaned_tx, _) = broadcast::channel(RECENT_HEADS);
+        Self {
+            heads: Default::default(),
+            orphaned_tx,
+        }
+    }
+
+    pub fn hash(&self, number: u64) -> Option<JsonValue> {
+        self.heads
+            .read()
+            .expect("recent heads lock poisoned")
+            .get(&number)
+            .cloned()
+    }
+
+    /// Records a new head, publishing the heads it replaces.
+    ///
+    /// Upstreams may only announce the new tip of a deep reorg, so the replaced
+    /// heads below it are found by checking its parent. As the fork point is
+    /// unknown, all heads below a replaced parent are treated as orphaned too.
+    fn insert(&self, (hash, number): (JsonValue, u64), parent_hash: Option<JsonValue>) {
+        let orphaned = {
+            let mut heads = self.heads.write().expect("recent heads lock poisoned");
+            let mut replaced = heads.split_off(&number);
+            if let (Some(parent_hash), Some(parent)) = (parent_hash, number.checked_sub(1)) {
+                if heads.get(&parent).is_some_and(|h| *h != parent_hash) {
+                    replaced.append(&mut heads);
+                    heads.insert(parent, parent_hash);
+                }
+            }
+            heads.insert(number, hash.clone());
+            while heads.len() > RECENT_HEADS {
+                heads.pop_first();
+            }
+            replaced
+                .into_iter()
+                .filter(|(n, h)| *n != number || *h != hash)
+                .map(|(n, h)| (h, n))
+                .collect::<Vec<_>>()
+        };
+        for head in orphaned {
+            tracing::debug!("Orphaned head: {} {}", head.1, head.0);
+            // nobody listening is fine
+            let _ = self.orphaned_tx.send(head);
+        }
+    }
+}
+
+/// Publishes a new head and records when it was received.
+pub(crate) fn send_new_head(
+    tx: &watch::Sender<Option<(JsonValue, u64)>>,
+    updated_at: &RwLock<Option<Instant>>,
+    recent_heads: &RecentHeads,
+    head: (JsonValue, u64),
+    parent_hash: Option<JsonValue>,
+) {
+    recent_heads.insert(head.clone(), parent_hash);
+    tx.send_replace(Some(head));
+    *updated_at.write().expect("head_updated_at lock poisoned") = Some(Instant::now());
 }
 
 pub(crate) fn get_number(val: &JsonValue) -> anyhow::Result<u64> {
@@ -54,6 +167,11 @@ pub(crate) fn get_hash(val: &JsonValue) -> anyhow::Result<JsonValue> {
     Err(anyhow::Error::msg("Hash not found"))
 }
 
+/// The `parentHash` of a header or block, if present.
+pub(crate) fn get_parent_hash(val: &JsonValue) -> Option<JsonValue> {
+    Some(val["parentHash"].to_owned()).filter(JsonValue::is_string)
+}
+
 pub(crate) fn validate_new_head(
     tx: &watch::Sender<Option<(JsonValue, u64)>>,
     number: u64,
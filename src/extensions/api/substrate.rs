@@ -3,7 +3,10 @@ use std::{sync::Arc, time::Duration};
 use async_trait::async_trait;
 use jsonrpsee::core::JsonValue;
 use serde::Deserialize;
-use tokio::{sync::watch, task::JoinHandle};
+use tokio::{
+    sync::{broadcast, watch},
+    task::JoinHandle,
+};
 
 use crate::extensions::{
     api::{BaseApi, ValueHandle},
@@ -65,6 +68,34 @@ impl SubstrateApi {
         self.inner.get_finalized_head()
     }
 
+    pub fn current_head(&self) -> Option<(JsonValue, u64)> {
+        self.inner.current_head()
+    }
+
+    pub fn current_finalized_head(&self) -> Option<(JsonValue, u64)> {
+        self.inner.current_finalized_head()
+    }
+
+    pub fn head_age(&self) -> Option<Duration> {
+        self.inner.head_age()
+    }
+
+    pub fn head_hash(&self, number: u64) -> Option<JsonValue> {
+        self.inner.head_hash(number)
+    }
+
+    pub fn subscribe_heads(&self) -> watch::Receiver<Option<(JsonValue, u64)>> {
+        self.inner.subscribe_heads()
+    }
+
+    pub fn subscribe_orphaned_heads(&self) -> broadcast::Receiver<(JsonValue, u64)> {
+        self.inner.subscribe_orphaned_heads()
+    }
+
+    pub fn stale_timeout(&self) -> Duration {
+        self.stale_timeout
+    }
+
     fn start_background_task(
         &mut self,
         head_tx: watch::Sender<Option<(JsonValue, u64)>>,
@@ -72,6 +103,8 @@ impl SubstrateApi {
     ) {
         let client = self.client.clone();
         let stale_timeout = self.stale_timeout;
+        let head_updated_at = self.inner.head_updated_at.clone();
+        let recent_heads = self.inner.recent_heads.clone();
 
         self.background_tasks.push(tokio::spawn(async move {
             let mut interval = tokio::time::interval(stale_timeout);
@@ -101,7 +134,13 @@ impl SubstrateApi {
                                         .await?;
 
                                     tracing::debug!("New head: {number} {hash}");
-                                    head_tx.send_replace(Some((hash, number)));
+                                    super::send_new_head(
+                                        &head_tx,
+                                        &head_updated_at,
+                                        &recent_heads,
+                                        (hash, number),
+                                        super::get_parent_hash(&val),
+                                    );
                                 } else {
                                     break;
                                 }
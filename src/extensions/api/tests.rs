@@ -163,6 +163,69 @@ async fn get_head_finalized_head() {
     server.stop().unwrap();
 }
 
+#[tokio::test]
+async fn publishes_orphaned_heads() {
+    let (api, server, mut head_rx, _, mut block_rx) = create_api().await;
+    let mut orphaned = api.subscribe_orphaned_heads();
+
+    let head_sub = head_rx.recv().await.unwrap();
+    for (number, hash) in [("0x01", "0xaa"), ("0x02", "0xbb"), ("0x02", "0xbb"), ("0x02", "0xb2")] {
+        head_sub.send(json!({ "number": number })).await;
+        block_rx.recv().await.unwrap().respond(json!(hash));
+    }
+
+    // replaced at the same height
+    assert_eq!(orphaned.recv().await.unwrap(), (json!("0xbb"), 0x02));
+    assert_eq!(api.head_hash(0x02), Some(json!("0xb2")));
+
+    // a lower head orphans the heads above it
+    head_sub.send(json!({ "number": "0x01" })).await;
+    block_rx.recv().await.unwrap().respond(json!("0xa1"));
+    assert_eq!(orphaned.recv().await.unwrap(), (json!("0xaa"), 0x01));
+    assert_eq!(orphaned.recv().await.unwrap(), (json!("0xb2"), 0x02));
+    assert_eq!(api.head_hash(0x01), Some(json!("0xa1")));
+    assert_eq!(api.head_hash(0x02), None);
+    assert!(orphaned.try_recv().is_err());
+
+    server.stop().unwrap();
+}
+
+#[tokio::test]
+async fn publishes_heads_orphaned_below_a_reorged_tip() {
+    let (api, server, mut head_rx, _, mut block_rx) = create_api().await;
+    let mut orphaned = api.subscribe_orphaned_heads();
+
+    let head_sub = head_rx.recv().await.unwrap();
+    for (number, hash, parent) in [
+        ("0x01", "0xaa", "0x00"),
+        ("0x02", "0xbb", "0xaa"),
+        ("0x03", "0xcc", "0xbb"),
+    ] {
+        head_sub.send(json!({ "number": number, "parentHash": parent })).await;
+        block_rx.recv().await.unwrap().respond(json!(hash));
+    }
+
+    // a depth 2 reorg announced only by its new tip
+    head_sub.send(json!({ "number": "0x03", "parentHash": "0xb2" })).await;
+    block_rx.recv().await.unwrap().respond(json!("0xc2"));
+    assert_eq!(orphaned.recv().await.unwrap(), (json!("0xaa"), 0x01));
+    assert_eq!(orphaned.recv().await.unwrap(), (json!("0xbb"), 0x02));
+    assert_eq!(orphaned.recv().await.unwrap(), (json!("0xcc"), 0x03));
+    assert_eq!(api.head_hash(0x01), None);
+    assert_eq!(api.head_hash(0x02), Some(json!("0xb2")));
+    assert_eq!(api.head_hash(0x03), Some(json!("0xc2")));
+    assert!(orphaned.try_recv().is_err());
+
+    // a matching parent orphans nothing
+    head_sub.send(json!({ "number": "0x04", "parentHash": "0xc2" })).await;
+    block_rx.recv().await.unwrap().respond(json!("0xdd"));
+    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+    assert_eq!(api.head_hash(0x04), Some(json!("0xdd")));
+    assert!(orphaned.try_recv().is_err());
+
+    server.stop().unwrap();
+}
+
 #[tokio::test]
 async fn rotate_endpoint_on_stale() {
     let (addr, server, mut head_rx, _, mut block_rx) = create_server().await;
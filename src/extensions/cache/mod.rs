@@ -1,10 +1,44 @@
+use anyhow::Context as _;
 use async_trait::async_trait;
-use serde::Deserialize;
+use blake2::{Blake2b512, Digest};
+use jsonrpsee::core::JsonValue;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
 
 use super::{Extension, ExtensionRegistry};
+use crate::config::MethodParam;
+use crate::middlewares::methods::cache::canonical_params;
+use crate::utils::{
+    redis::RedisClient, Cache as MethodCache, CacheBackend, CacheKey, Capacity, DiskStore, DiskTier, MemoryBackend,
+    Persist, RedisBackend, SharedBackend, SharedLimits, SharedStore,
+};
+
+mod prewarm;
+
+pub use prewarm::{new_heads, parse_recorded, PrewarmCall, PrewarmConfig, Prewarmer};
 
 pub struct Cache {
     pub config: CacheConfig,
+    redis: Option<Arc<RedisClient>>,
+    disk: Option<Arc<DiskStore>>,
+    shared: Option<Arc<SharedStore>>,
+    methods: RwLock<BTreeMap<String, CachedMethod>>,
+}
+
+/// The cache of a method and its params, to compute the keys of evicted requests.
+type CachedMethod = (MethodCache<Blake2b512>, Vec<MethodParam>);
+
+/// Cache usage of a method, `entries` and `bytes` are unknown for the redis backend.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct MethodStats {
+    pub entries: Option<u64>,
+    pub bytes: Option<u64>,
+    pub hits: u64,
+    pub misses: u64,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -13,6 +47,66 @@ pub struct CacheConfig {
     #[serde(default)]
     pub default_ttl_seconds: Option<u64>,
     pub default_size: usize,
+    /// Bounds each method's cache by the serialized size of its entries instead
+    /// of `default_size`, unless the method sets its own `size`.
+    #[serde(default)]
+    pub default_max_bytes: Option<u64>,
+    /// Where the cached responses are kept.
+    #[serde(default)]
+    pub backend: BackendConfig,
+    /// Keeps responses of methods with `persist` enabled on disk, across restarts.
+    #[serde(default)]
+    pub disk: Option<DiskConfig>,
+    /// One in memory cache for all methods instead of one per method, each
+    /// method's `size` is ignored.
+    #[serde(default)]
+    pub shared: Option<SharedConfig>,
+    /// Requests made ahead of clients, to have their responses cached.
+    #[serde(default)]
+    pub prewarm: Option<PrewarmConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SharedConfig {
+    pub max_bytes: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct DiskConfig {
+    /// Directory holding the database.
+    pub path: String,
+    /// Bound on the names and values of the entries, the database file takes somewhat more.
+    #[serde(default = "default_max_size_mb")]
+    pub max_size_mb: u64,
+}
+
+fn default_max_size_mb() -> u64 {
+    1024
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum BackendConfig {
+    /// Each replica caches on its own, `size` entries or `max_bytes` per method.
+    #[default]
+    Memory,
+    /// Responses are shared by all replicas using the same Redis-protocol server.
+    /// `size` and `max_bytes` are ignored, the server evicts entries according to its memory policy.
+    Redis {
+        url: String,
+        #[serde(default = "default_key_prefix")]
+        key_prefix: String,
+        #[serde(default = "default_timeout_millis")]
+        timeout_millis: u64,
+    },
+}
+
+fn default_key_prefix() -> String {
+    "subway:cache".to_string()
+}
+
+fn default_timeout_millis() -> u64 {
+    500
 }
 
 #[async_trait]
@@ -20,12 +114,204 @@ impl Extension for Cache {
     type Config = CacheConfig;
 
     async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
-        Ok(Self::new(config.clone()))
+        let disk = match &config.disk {
+            Some(disk) => Some(Arc::new(
+                DiskStore::open(&disk.path, disk.max_size_mb * 1024 * 1024)
+                    .await
+                    .context("Unable to open disk cache")?,
+            )),
+            None => None,
+        };
+        Ok(Self::new(config.clone()).with_disk(disk))
     }
 }
 
 impl Cache {
     pub fn new(config: CacheConfig) -> Self {
-        Self { config }
+        let redis = match &config.backend {
+            BackendConfig::Memory => None,
+            BackendConfig::Redis {
+                url, timeout_millis, ..
+            } => Some(Arc::new(
+                RedisClient::new(url, Duration::from_millis(*timeout_millis)).expect("Invalid cache redis url"),
+            )),
+        };
+        let shared = match (&config.backend, &config.shared) {
+            (BackendConfig::Memory, Some(shared)) => Some(Arc::new(SharedStore::new(shared.max_bytes))),
+            _ => None,
+        };
+        Self {
+            config,
+            redis,
+            disk: None,
+            shared,
+            methods: Default::default(),
+        }
+    }
+
+    /// Keeps the responses of methods with `persist` enabled in `disk` too.
+    pub fn with_disk(mut self, disk: Option<Arc<DiskStore>>) -> Self {
+        self.disk = disk;
+        self
+    }
+
+    /// Creates the backend for the cache of a method. Values accepted by
+    /// `persist` are also kept on disk if the disk cache is enabled. With a
+    /// shared cache, `limits` apply instead of `capacity`.
+    pub fn backend<D: Digest + Send + Sync + 'static>(
+        &self,
+        method: &str,
+        capacity: Capacity,
+        limits: SharedLimits,
+        ttl: Option<Duration>,
+        persist: Option<Persist>,
+    ) -> Arc<dyn CacheBackend<D>> {
+        let backend: Arc<dyn CacheBackend<D>> = match (&self.config.backend, &self.redis, &self.shared) {
+            (BackendConfig::Redis { key_prefix, .. }, Some(client), _) => {
+                Arc::new(RedisBackend::new(client.clone(), format!("{key_prefix}:{method}"), ttl))
+            }
+            (_, _, Some(shared)) => Arc::new(SharedBackend::new(shared.clone(), method, limits, ttl)),
+            _ => Arc::new(MemoryBackend::with_capacity(capacity, ttl)),
+        };
+        match (&self.disk, persist) {
+            (Some(disk), Some(persist)) => Arc::new(DiskTier::new(method, backend, disk.clone(), persist)),
+            _ => backend,
+        }
+    }
+
+    /// Makes the cache of a method available to [`Self::stats`], [`Self::flush`] and [`Self::evict`].
+    pub fn register(&self, method: &str, cache: MethodCache<Blake2b512>, params: Vec<MethodParam>) {
+        self.methods
+            .write()
+            .expect("cache methods lock poisoned")
+            .insert(method.to_string(), (cache, params));
+    }
+
+    pub fn is_cached(&self, method: &str) -> bool {
+        self.methods
+            .read()
+            .expect("cache methods lock poisoned")
+            .contains_key(method)
+    }
+
+    fn method_caches(&self, method: Option<&str>) -> Vec<(String, MethodCache<Blake2b512>)> {
+        self.methods
+            .read()
+            .expect("cache methods lock poisoned")
+            .iter()
+            .filter(|(name, _)| method.map_or(true, |method| method == name.as_str()))
+            .map(|(name, (cache, _))| (name.clone(), cache.clone()))
+            .collect()
+    }
+
+    pub async fn stats(&self) -> BTreeMap<String, MethodStats> {
+        let mut stats = BTreeMap::new();
+        for (method, cache) in self.method_caches(None) {
+            let usage = cache.usage().await;
+            stats.insert(
+                method,
+                MethodStats {
+                    entries: usage.map(|usage| usage.entries),
+                    bytes: usage.map(|usage| usage.bytes),
+                    hits: cache.counters().hits(),
+                    misses: cache.counters().misses(),
+                },
+            );
+        }
+        stats
+    }
+
+    /// Removes the cached responses of a method, or of all methods. Returns the
+    /// number of caches flushed.
+    pub async fn flush(&self, method: Option<&str>) -> usize {
+        let caches = self.method_caches(method);
+        for (method, cache) in &caches {
+            tracing::info!("Flushing cache of {method}");
+            cache.clear().await;
+        }
+        caches.len()
+    }
+
+    /// Removes the cached response of a request, returns whether there was one.
+    ///
+    /// The key is computed from `params` as given. Responses are cached after middlewares such
+    /// as `block_tag` and `inject_params` rewrite the params, so pass the rewritten ones, e.g. the
+    /// block hash instead of an omitted or tagged block.
+    pub async fn evict(&self, method: &str, params: &[JsonValue]) -> anyhow::Result<bool> {
+        let (cache, key) = {
+            let methods = self.methods.read().expect("cache methods lock poisoned");
+            let (cache, method_params) = methods
+                .get(method)
+                .ok_or_else(|| anyhow::anyhow!("{method} is not cached"))?;
+            let key = CacheKey::<Blake2b512>::new(&method.to_string(), &canonical_params(method_params, params));
+            (cache.clone(), key)
+        };
+        let cached = cache.get(&key).await.is_some();
+        cache.remove(&key).await;
+        Ok(cached)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonrpsee::core::JsonValue;
+    use serde_json::json;
+    use std::num::NonZeroUsize;
+
+    #[tokio::test]
+    async fn stats_flush_and_evict() {
+        let ext = Cache::new(CacheConfig {
+            default_ttl_seconds: None,
+            default_size: 10,
+            default_max_bytes: None,
+            backend: BackendConfig::Memory,
+            disk: None,
+            shared: None,
+            prewarm: None,
+        });
+        let param = MethodParam {
+            name: "hash".to_string(),
+            ty: "BlockHash".to_string(),
+            optional: false,
+            inject: false,
+        };
+        let new_cache = || MethodCache::<Blake2b512>::new(NonZeroUsize::new(10).unwrap(), None);
+        let (block, header) = (new_cache(), new_cache());
+        ext.register("chain_getBlock", block.clone(), vec![param.clone()]);
+        ext.register("chain_getHeader", header.clone(), vec![param]);
+
+        let key = |method: &str, hash: &str| CacheKey::new(&method.to_string(), &[json!(hash)]);
+        block.insert(key("chain_getBlock", "0xab"), json!("block")).await;
+        block.insert(key("chain_getBlock", "0xcd"), json!("block")).await;
+        header.insert(key("chain_getHeader", "0xab"), json!("header")).await;
+        block.counters().query();
+        block.counters().query();
+        block.counters().miss();
+
+        let stats = ext.stats().await;
+        assert_eq!(
+            stats["chain_getBlock"],
+            MethodStats {
+                entries: Some(2),
+                bytes: stats["chain_getBlock"].bytes,
+                hits: 1,
+                misses: 1,
+            }
+        );
+        assert_eq!(stats["chain_getHeader"].entries, Some(1));
+
+        // params are canonicalised like requests are
+        assert!(ext.evict("chain_getBlock", &[json!("0xAB")]).await.unwrap());
+        assert!(!ext.evict("chain_getBlock", &[json!("0xab")]).await.unwrap());
+        assert!(ext.evict("system_health", &[]).await.is_err());
+        assert_eq!(ext.stats().await["chain_getBlock"].entries, Some(1));
+
+        assert_eq!(ext.flush(Some("chain_getHeader")).await, 1);
+        assert_eq!(header.get(&key("chain_getHeader", "0xab")).await, None::<JsonValue>);
+        assert!(block.get(&key("chain_getBlock", "0xcd")).await.is_some());
+
+        assert_eq!(ext.flush(None).await, 2);
+        assert_eq!(ext.stats().await["chain_getBlock"].entries, Some(0));
     }
 }
@@ -0,0 +1,264 @@
+use futures::{future, stream, StreamExt as _};
+use jsonrpsee::{core::JsonValue, Methods};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::{sync::watch, task::JoinHandle};
+
+use super::Cache;
+use crate::{
+    extensions::api::{EthApi, SubstrateApi},
+    utils::TypeRegistryRef,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct PrewarmConfig {
+    /// Requests replayed on startup, one JSON-RPC request per line.
+    #[serde(default)]
+    pub file: Option<String>,
+    /// Requests made for each new head, `"$hash"` params are replaced by its hash.
+    #[serde(default)]
+    pub on_new_head: Vec<PrewarmCall>,
+    /// Most requests in flight at once.
+    #[serde(default = "default_concurrency")]
+    pub concurrency: usize,
+}
+
+fn default_concurrency() -> usize {
+    4
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct PrewarmCall {
+    pub method: String,
+    #[serde(default)]
+    pub params: Vec<JsonValue>,
+}
+
+impl PrewarmCall {
+    fn for_head(&self, hash: &JsonValue) -> Self {
+        fn replace(value: &JsonValue, hash: &JsonValue) -> JsonValue {
+            match value {
+                JsonValue::String(s) if s == "$hash" => hash.clone(),
+                JsonValue::Array(values) => values.iter().map(|v| replace(v, hash)).collect(),
+                _ => value.clone(),
+            }
+        }
+        Self {
+            method: self.method.clone(),
+            params: self.params.iter().map(|v| replace(v, hash)).collect(),
+        }
+    }
+}
+
+/// Recorded requests, one JSON-RPC request per line. Other lines are skipped.
+pub fn parse_recorded(recorded: &str) -> Vec<PrewarmCall> {
+    recorded
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(call) => Some(call),
+            Err(err) => {
+                tracing::debug!("Skipping recorded request {line}: {err}");
+                None
+            }
+        })
+        .collect()
+}
+
+/// Fills the cache by making requests through the methods' middlewares, as
+/// if they were made by clients.
+pub struct Prewarmer {
+    cache: Arc<Cache>,
+    methods: Methods,
+    concurrency: usize,
+}
+
+impl Prewarmer {
+    pub fn new(cache: Arc<Cache>, methods: impl Into<Methods>, concurrency: usize) -> Self {
+        assert!(concurrency > 0, "Cache prewarm concurrency must not be 0");
+        Self {
+            cache,
+            methods: methods.into(),
+            concurrency,
+        }
+    }
+
+    /// Makes the requests of cached methods, returns how many succeeded.
+    pub async fn warm(&self, calls: Vec<PrewarmCall>) -> usize {
+        async fn call(methods: &Methods, call: PrewarmCall) -> bool {
+            match methods.call::<_, JsonValue>(&call.method, call.params).await {
+                Ok(_) => true,
+                Err(err) => {
+                    tracing::debug!("Failed to prewarm {}: {err}", call.method);
+                    false
+                }
+            }
+        }
+
+        let calls: Vec<_> = calls
+            .into_iter()
+            .filter(|call| {
+                let cached = self.cache.is_cached(&call.method);
+                if !cached {
+                    tracing::debug!("Not prewarming {}, it is not cached", call.method);
+                }
+                cached
+            })
+            .collect();
+        stream::iter(calls)
+            .map(|c| call(&self.methods, c))
+            .buffer_unordered(self.concurrency)
+            .filter(|ok| future::ready(*ok))
+            .count()
+            .await
+    }
+
+    /// Replays the recorded requests of `config.file`, then makes the
+    /// `config.on_new_head` requests for each new head.
+    pub fn start(
+        self,
+        config: &PrewarmConfig,
+        heads: Option<watch::Receiver<Option<(JsonValue, u64)>>>,
+    ) -> JoinHandle<()> {
+        let config = config.clone();
+        tokio::spawn(async move {
+            if let Some(path) = &config.file {
+                match tokio::fs::read_to_string(path).await {
+                    Ok(recorded) => {
+                        let calls = parse_recorded(&recorded);
+                        let total = calls.len();
+                        let warmed = self.warm(calls).await;
+                        tracing::info!("Prewarmed cache with {warmed} of {total} recorded requests");
+                    }
+                    Err(err) => tracing::warn!("Unable to read recorded requests from {path}: {err}"),
+                }
+            }
+
+            if config.on_new_head.is_empty() {
+                return;
+            }
+            let Some(mut heads) = heads else {
+                tracing::warn!("Cache prewarm on_new_head needs the substrate or eth api extension");
+                return;
+            };
+            // heads arriving while a head is being warmed are skipped, except the latest
+            while heads.changed().await.is_ok() {
+                let head = heads.borrow_and_update().clone();
+                let Some((hash, number)) = head else {
+                    continue;
+                };
+                let warmed = self
+                    .warm(config.on_new_head.iter().map(|call| call.for_head(&hash)).collect())
+                    .await;
+                tracing::debug!("Prewarmed {warmed} requests for head {number}");
+            }
+        })
+    }
+}
+
+/// New heads of the substrate or eth api, if any.
+pub async fn new_heads(extensions: &TypeRegistryRef) -> Option<watch::Receiver<Option<(JsonValue, u64)>>> {
+    let extensions = extensions.read().await;
+    if let Some(api) = extensions.get::<SubstrateApi>() {
+        Some(api.subscribe_heads())
+    } else {
+        extensions.get::<EthApi>().map(|api| api.subscribe_heads())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::cache::{BackendConfig, CacheConfig};
+    use crate::utils::Cache as MethodCache;
+    use blake2::Blake2b512;
+    use jsonrpsee::RpcModule;
+    use serde_json::json;
+    use std::{num::NonZeroUsize, time::Duration};
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn parses_recorded_requests() {
+        let recorded = r#"{"jsonrpc":"2.0","id":1,"method":"chain_getBlock","params":["0xab"]}
+
+not a request
+{"jsonrpc":"2.0","id":2,"method":"system_health"}"#;
+        assert_eq!(
+            parse_recorded(recorded),
+            vec![
+                PrewarmCall {
+                    method: "chain_getBlock".to_string(),
+                    params: vec![json!("0xab")],
+                },
+                PrewarmCall {
+                    method: "system_health".to_string(),
+                    params: vec![],
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn warms_cached_methods() {
+        let cache = Arc::new(Cache::new(CacheConfig {
+            default_ttl_seconds: None,
+            default_size: 10,
+            default_max_bytes: None,
+            backend: BackendConfig::Memory,
+            disk: None,
+            shared: None,
+            prewarm: None,
+        }));
+        let method_cache = MethodCache::<Blake2b512>::new(NonZeroUsize::new(10).unwrap(), None);
+        cache.register("state_getStorage", method_cache, vec![]);
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut module = RpcModule::new(());
+        for method in ["state_getStorage", "system_health"] {
+            let tx = tx.clone();
+            module
+                .register_method(method, move |params, _, _| {
+                    let params = params.parse::<Vec<JsonValue>>().unwrap_or_default();
+                    tx.send((method, params)).unwrap();
+                    JsonValue::Null
+                })
+                .unwrap();
+        }
+
+        let call = |method: &str, params: Vec<JsonValue>| PrewarmCall {
+            method: method.to_string(),
+            params,
+        };
+        let config = PrewarmConfig {
+            file: None,
+            on_new_head: vec![call("state_getStorage", vec![json!("0x26aa"), json!("$hash")])],
+            concurrency: 1,
+        };
+        let prewarmer = Prewarmer::new(cache.clone(), module, 1);
+
+        // only cached methods are prewarmed
+        let warmed = prewarmer
+            .warm(vec![
+                call("state_getStorage", vec![json!("0x01")]),
+                call("system_health", vec![]),
+            ])
+            .await;
+        assert_eq!(warmed, 1);
+        assert_eq!(rx.recv().await.unwrap(), ("state_getStorage", vec![json!("0x01")]));
+
+        let (heads_tx, heads_rx) = watch::channel(None);
+        let task = prewarmer.start(&config, Some(heads_rx));
+        heads_tx.send(Some((json!("0xbeef"), 1))).unwrap();
+        assert_eq!(
+            tokio::time::timeout(Duration::from_secs(1), rx.recv())
+                .await
+                .unwrap()
+                .unwrap(),
+            ("state_getStorage", vec![json!("0x26aa"), json!("0xbeef")])
+        );
+        assert!(rx.try_recv().is_err());
+
+        drop(heads_tx);
+        task.await.unwrap();
+    }
+}
@@ -6,7 +6,6 @@ use crate::logger::enable_logger;
 
 use super::*;
 
-use futures::TryFutureExt;
 use jsonrpsee::types::ErrorObject;
 use jsonrpsee::{
     server::{RandomStringIdProvider, RpcModule, ServerBuilder, ServerHandle},
@@ -59,11 +58,22 @@ impl TestServerBuilder {
             .register_subscription(sub_name, method_name, unsub_name, move |params, sink, _, _| {
                 let tx = tx.clone();
                 let params = params.parse::<JsonValue>().unwrap();
-                tokio::spawn(async move {
+                async move {
                     let sink = sink.accept().await.unwrap();
-                    let _ = tx.send(MockSubscription { params, sink }).await;
-                })
-                .map_err(|_| "error".into())
+                    let (close_tx, close_rx) = oneshot::channel();
+                    let _ = tx
+                        .send(MockSubscription {
+                            params,
+                            sink,
+                            close: close_tx,
+                        })
+                        .await;
+                    // ends the subscription with an error notification
+                    match close_rx.await {
+                        Ok(err) => Err(err.into()),
+                        Err(_) => Ok(()),
+                    }
+                }
             })
             .unwrap();
         rx
@@ -118,6 +128,7 @@ impl MockRequest {
 pub struct MockSubscription {
     pub params: JsonValue,
     pub sink: SubscriptionSink,
+    close: oneshot::Sender<String>,
 }
 
 impl MockSubscription {
@@ -133,6 +144,11 @@ impl MockSubscription {
             task.run(&self.sink).await
         }
     }
+
+    /// Closes the subscription from the server side.
+    pub fn close(self, err: &str) {
+        let _ = self.close.send(err.to_string());
+    }
 }
 
 pub async fn dummy_server() -> (
@@ -1,6 +1,6 @@
 use std::{
     sync::{
-        atomic::{AtomicU32, AtomicUsize},
+        atomic::{AtomicBool, AtomicU32, AtomicUsize},
         Arc,
     },
     time::Duration,
@@ -40,6 +40,7 @@ pub struct Client {
     endpoints: Vec<String>,
     sender: tokio::sync::mpsc::Sender<Message>,
     rotation_notify: Arc<Notify>,
+    connected: Arc<AtomicBool>,
     retries: u32,
     background_task: tokio::task::JoinHandle<()>,
 }
@@ -165,6 +166,8 @@ impl Client {
 
         let rotation_notify = Arc::new(Notify::new());
         let rotation_notify_bg = rotation_notify.clone();
+        let connected = Arc::new(AtomicBool::new(false));
+        let connected_bg = connected.clone();
         let endpoints_ = endpoints.clone();
 
         let background_task = tokio::spawn(async move {
@@ -174,7 +177,10 @@ impl Client {
             let current_endpoint = AtomicUsize::new(0);
 
             let connect_backoff_counter2 = connect_backoff_counter.clone();
+            let connected = connected_bg.clone();
             let build_ws = || async {
+                connected.store(false, std::sync::atomic::Ordering::Relaxed);
+
                 let build = || {
                     let current_endpoint = current_endpoint.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                     let url = &endpoints[current_endpoint % endpoints.len()];
@@ -198,6 +204,7 @@ impl Client {
                             let ws = Arc::new(ws);
                             tracing::info!("Endpoint connected");
                             connect_backoff_counter2.store(0, std::sync::atomic::Ordering::Relaxed);
+                            connected.store(true, std::sync::atomic::Ordering::Relaxed);
                             break ws;
                         }
                         Err((e, url)) => {
@@ -387,6 +394,7 @@ impl Client {
                 tokio::select! {
                     _ = ws.on_disconnect() => {
                         tracing::info!("Endpoint disconnected");
+                        connected_bg.store(false, std::sync::atomic::Ordering::Relaxed);
                         tokio::time::sleep(get_backoff_time(&connect_backoff_counter)).await;
                         ws = build_ws().await;
                     }
@@ -417,6 +425,7 @@ impl Client {
             endpoints: endpoints_,
             sender: message_tx,
             rotation_notify,
+            connected,
             retries: retries.unwrap_or(3),
             background_task,
         })
@@ -430,6 +439,11 @@ impl Client {
         &self.endpoints
     }
 
+    /// Returns true if there is an established connection to one of the endpoints.
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
     pub async fn request(&self, method: &str, params: Vec<JsonValue>) -> CallResult {
         async move {
             let (tx, rx) = tokio::sync::oneshot::channel();
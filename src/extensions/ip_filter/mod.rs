@@ -0,0 +1,337 @@
+//! Allow and deny lists of IP ranges, checked for every HTTP request and
+//! WebSocket upgrade before it reaches the JSON-RPC service.
+//!
+//! Behind proxies, forwarded IPs are only used when the request comes from
+//! one of the rate limit `trusted_proxies`. Otherwise the socket IP is checked,
+//! as any client can forge `X-Forwarded-For`.
+//!
+//! The most specific matching range decides whether an IP is allowed, so a
+//! narrow allow entry can carve an exception out of a wider deny entry and
+//! vice versa. IPs matching no entry fall back to `default_action`.
+
+use async_trait::async_trait;
+use ipnet::IpNet;
+use serde::Deserialize;
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::task::JoinHandle;
+
+use super::{Extension, ExtensionRegistry};
+use crate::utils::parse_ip_nets;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct IpFilterConfig {
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+    /// File with one CIDR per line, `#` starts a comment. Merged with `allow`.
+    #[serde(default)]
+    pub allow_file: Option<PathBuf>,
+    /// File with one CIDR per line, `#` starts a comment. Merged with `deny`.
+    #[serde(default)]
+    pub deny_file: Option<PathBuf>,
+    #[serde(default)]
+    pub default_action: Action,
+    /// Skip rate limits for IPs matching an allow entry.
+    #[serde(default)]
+    pub bypass_rate_limit: bool,
+    /// How often the list files are checked for changes.
+    #[serde(default = "default_reload_interval_seconds")]
+    pub reload_interval_seconds: u64,
+}
+
+fn default_reload_interval_seconds() -> u64 {
+    10
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    #[default]
+    Allow,
+    Deny,
+}
+
+/// The outcome of checking an IP against the lists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpAccess {
+    Allowed,
+    /// Allowed by an allow entry and exempt from rate limits.
+    Exempt,
+    Denied,
+}
+
+impl IpAccess {
+    pub fn is_denied(&self) -> bool {
+        matches!(self, IpAccess::Denied)
+    }
+
+    pub fn bypass_rate_limit(&self) -> bool {
+        matches!(self, IpAccess::Exempt)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+struct Rules {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl Rules {
+    /// Returns the action of the most specific entry matching the ip. Deny wins ties.
+    fn matches(&self, ip: &IpAddr) -> Option<Action> {
+        let longest = |nets: &[IpNet]| {
+            nets.iter()
+                .filter(|net| net.contains(ip))
+                .map(|net| net.prefix_len())
+                .max()
+        };
+
+        match (longest(&self.allow), longest(&self.deny)) {
+            (Some(allow), Some(deny)) if allow > deny => Some(Action::Allow),
+            (_, Some(_)) => Some(Action::Deny),
+            (Some(_), None) => Some(Action::Allow),
+            (None, None) => None,
+        }
+    }
+}
+
+pub struct IpFilter {
+    config: IpFilterConfig,
+    rules: Arc<RwLock<Rules>>,
+    reload_task: Option<JoinHandle<()>>,
+}
+
+impl Drop for IpFilter {
+    fn drop(&mut self) {
+        if let Some(task) = self.reload_task.take() {
+            task.abort();
+        }
+    }
+}
+
+#[async_trait]
+impl Extension for IpFilter {
+    type Config = IpFilterConfig;
+
+    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        Self::new(config.clone())
+    }
+}
+
+impl IpFilter {
+    pub fn new(config: IpFilterConfig) -> anyhow::Result<Self> {
+        let rules = Arc::new(RwLock::new(load_rules(&config)?));
+
+        let reload_task = if config.allow_file.is_some() || config.deny_file.is_some() {
+            anyhow::ensure!(
+                config.reload_interval_seconds > 0,
+                "reload_interval_seconds must be greater than 0"
+            );
+            Some(tokio::spawn(watch_files(config.clone(), rules.clone())))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            config,
+            rules,
+            reload_task,
+        })
+    }
+
+    pub fn check(&self, ip: IpAddr) -> IpAccess {
+        let ip = ip.to_canonical();
+        let action = self.rules.read().expect("ip filter lock poisoned").matches(&ip);
+
+        match action {
+            Some(Action::Allow) if self.config.bypass_rate_limit => IpAccess::Exempt,
+            Some(Action::Allow) => IpAccess::Allowed,
+            Some(Action::Deny) => IpAccess::Denied,
+            None => match self.config.default_action {
+                Action::Allow => IpAccess::Allowed,
+                Action::Deny => IpAccess::Denied,
+            },
+        }
+    }
+
+    /// Same as [`IpFilter::check`], for IPs given as strings. Unparsable IPs get the default action.
+    pub fn check_str(&self, ip: &str) -> IpAccess {
+        match IpAddr::from_str(ip) {
+            Ok(ip) => self.check(ip),
+            Err(_) => match self.config.default_action {
+                Action::Allow => IpAccess::Allowed,
+                Action::Deny => IpAccess::Denied,
+            },
+        }
+    }
+}
+
+fn read_list(path: &Path) -> anyhow::Result<Vec<IpNet>> {
+    let content =
+        std::fs::read_to_string(path).map_err(|e| anyhow::anyhow!("Unable to read {}: {e}", path.display()))?;
+
+    parse_ip_nets(
+        content
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or_default().trim())
+            .filter(|line| !line.is_empty()),
+    )
+    .map_err(|e| anyhow::anyhow!("{}: {e}", path.display()))
+}
+
+fn load_rules(config: &IpFilterConfig) -> anyhow::Result<Rules> {
+    let mut allow = parse_ip_nets(config.allow.iter().map(String::as_str))?;
+    let mut deny = parse_ip_nets(config.deny.iter().map(String::as_str))?;
+
+    if let Some(path) = &config.allow_file {
+        allow.extend(read_list(path)?);
+    }
+    if let Some(path) = &config.deny_file {
+        deny.extend(read_list(path)?);
+    }
+
+    Ok(Rules { allow, deny })
+}
+
+fn modified(path: &Option<PathBuf>) -> Option<SystemTime> {
+    path.as_ref()
+        .and_then(|path| std::fs::metadata(path).ok())
+        .and_then(|meta| meta.modified().ok())
+}
+
+/// Reloads the rules whenever one of the list files is modified.
+/// Invalid files are logged and the previous rules are kept.
+async fn watch_files(config: IpFilterConfig, rules: Arc<RwLock<Rules>>) {
+    let mut last_modified = (modified(&config.allow_file), modified(&config.deny_file));
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.reload_interval_seconds));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        let current = (modified(&config.allow_file), modified(&config.deny_file));
+        if current == last_modified {
+            continue;
+        }
+        last_modified = current;
+
+        match load_rules(&config) {
+            Ok(new_rules) => {
+                tracing::info!(
+                    "IP filter reloaded: {} allow, {} deny entries",
+                    new_rules.allow.len(),
+                    new_rules.deny.len()
+                );
+                *rules.write().expect("ip filter lock poisoned") = new_rules;
+            }
+            Err(e) => {
+                tracing::error!("Failed to reload IP filter, keeping previous lists: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(allow: &[&str], deny: &[&str]) -> IpFilterConfig {
+        IpFilterConfig {
+            allow: allow.iter().map(|s| s.to_string()).collect(),
+            deny: deny.iter().map(|s| s.to_string()).collect(),
+            allow_file: None,
+            deny_file: None,
+            default_action: Action::Allow,
+            bypass_rate_limit: false,
+            reload_interval_seconds: 1,
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        IpAddr::from_str(s).unwrap()
+    }
+
+    #[tokio::test]
+    async fn most_specific_entry_wins() {
+        let filter = IpFilter::new(config(
+            &["10.1.0.0/16", "192.168.1.1"],
+            &["10.0.0.0/8", "192.168.0.0/16"],
+        ))
+        .unwrap();
+
+        assert_eq!(filter.check(ip("10.2.3.4")), IpAccess::Denied);
+        assert_eq!(filter.check(ip("10.1.3.4")), IpAccess::Allowed);
+        assert_eq!(filter.check(ip("192.168.1.1")), IpAccess::Allowed);
+        assert_eq!(filter.check(ip("192.168.1.2")), IpAccess::Denied);
+        assert_eq!(filter.check(ip("1.1.1.1")), IpAccess::Allowed);
+        // ipv4 mapped ipv6 addresses are matched as ipv4
+        assert_eq!(filter.check(ip("::ffff:10.2.3.4")), IpAccess::Denied);
+        // deny wins ties
+        let filter = IpFilter::new(config(&["10.0.0.0/8"], &["10.0.0.0/8"])).unwrap();
+        assert_eq!(filter.check(ip("10.2.3.4")), IpAccess::Denied);
+    }
+
+    #[tokio::test]
+    async fn default_action_and_bypass() {
+        let mut cfg = config(&["2001:db8::/32"], &[]);
+        cfg.default_action = Action::Deny;
+        cfg.bypass_rate_limit = true;
+        let filter = IpFilter::new(cfg).unwrap();
+
+        assert_eq!(filter.check_str("2001:db8::1"), IpAccess::Exempt);
+        assert!(filter.check_str("2001:db8::1").bypass_rate_limit());
+        assert_eq!(filter.check_str("1.1.1.1"), IpAccess::Denied);
+        assert_eq!(filter.check_str("not an ip"), IpAccess::Denied);
+    }
+
+    #[tokio::test]
+    async fn invalid_entry_is_rejected() {
+        assert!(IpFilter::new(config(&["10.0.0.0/33"], &[])).is_err());
+        assert!(IpFilter::new(config(&[], &["foo"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn reloads_modified_files() {
+        let path = std::env::temp_dir().join(format!("subway-ip-filter-{}.txt", std::process::id()));
+        std::fs::write(&path, "# blocked\n1.1.1.1\n").unwrap();
+
+        let mut cfg = config(&[], &[]);
+        cfg.deny_file = Some(path.clone());
+        let filter = IpFilter::new(cfg).unwrap();
+
+        assert_eq!(filter.check(ip("1.1.1.1")), IpAccess::Denied);
+        assert_eq!(filter.check(ip("2.2.2.2")), IpAccess::Allowed);
+
+        // make sure the modification time changes
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(&path, "2.2.2.0/24 # blocked\n").unwrap();
+
+        let mut reloaded = false;
+        for _ in 0..30 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if filter.check(ip("2.2.2.2")).is_denied() {
+                reloaded = true;
+                break;
+            }
+        }
+        assert!(reloaded);
+        assert_eq!(filter.check(ip("1.1.1.1")), IpAccess::Allowed);
+
+        // invalid file keeps previous rules
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        std::fs::write(&path, "not an ip\n").unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(filter.check(ip("2.2.2.2")).is_denied());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
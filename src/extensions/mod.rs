@@ -8,10 +8,12 @@ use tokio::sync::RwLock;
 
 use crate::utils::{TypeRegistry, TypeRegistryRef};
 
+pub mod admin;
 pub mod api;
 pub mod cache;
 pub mod client;
 pub mod event_bus;
+pub mod ip_filter;
 pub mod list;
 pub mod merge_subscription;
 pub mod prometheus;
@@ -145,6 +147,8 @@ define_all_extensions! {
     server: server::SubwayServerBuilder,
     event_bus: event_bus::EventBus,
     rate_limit: rate_limit::RateLimitBuilder,
+    ip_filter: ip_filter::IpFilter,
+    admin: admin::Admin,
     prometheus: prometheus::Prometheus,
     validator: validator::Validator,
     whitelist: list::Whitelist,
@@ -1,4 +1,7 @@
-use substrate_prometheus_endpoint::{register, Counter, CounterVec, HistogramOpts, HistogramVec, Opts, Registry, U64};
+use std::time::Duration;
+use substrate_prometheus_endpoint::{
+    register, Counter, CounterVec, GaugeVec, HistogramOpts, HistogramVec, Opts, Registry, U64,
+};
 
 #[derive(Clone)]
 pub enum RpcMetrics {
@@ -38,6 +41,30 @@ impl RpcMetrics {
         }
     }
 
+    /// A call waited `delay`, including `jitter`, for the quota of `rule` to refill.
+    pub fn rate_limit_delayed(&self, rule: &str, method: &str, delay: Duration, jitter: Duration) {
+        if let Self::Prometheus(inner) = self {
+            inner.rate_limit_delayed(rule, method, delay, jitter);
+        }
+    }
+
+    pub fn rate_limit_rejected(&self, rule: &str, method: &str) {
+        if let Self::Prometheus(inner) = self {
+            inner.rate_limit_rejected(rule, method);
+        }
+    }
+
+    /// Number of keys (ips) tracked by a keyed limiter.
+    pub fn rate_limit_keys(&self, limiter: &str, keys: usize) {
+        if let Self::Prometheus(inner) = self {
+            inner.rate_limit_keys.with_label_values(&[limiter]).set(keys as u64);
+        }
+    }
+
+    pub fn is_noop(&self) -> bool {
+        matches!(self, Self::Noop)
+    }
+
     pub fn call_metrics(&self) -> Option<(HistogramVec, CounterVec<U64>, CounterVec<U64>)> {
         if let Self::Prometheus(inner) = self {
             return Some((
@@ -60,6 +87,11 @@ pub struct InnerMetrics {
     call_times: HistogramVec,
     calls_started: CounterVec<U64>,
     calls_finished: CounterVec<U64>,
+    rate_limit_delayed: CounterVec<U64>,
+    rate_limit_rejected: CounterVec<U64>,
+    rate_limit_delay: HistogramVec,
+    rate_limit_jitter: HistogramVec,
+    rate_limit_keys: GaugeVec<U64>,
 }
 
 impl InnerMetrics {
@@ -78,6 +110,22 @@ impl InnerMetrics {
         )
         .unwrap();
 
+        let rate_limit_delayed =
+            CounterVec::new(Opts::new("rate_limit_delayed", "No help"), &["rule", "method"]).unwrap();
+        let rate_limit_rejected =
+            CounterVec::new(Opts::new("rate_limit_rejected", "No help"), &["rule", "method"]).unwrap();
+        let rate_limit_delay = HistogramVec::new(
+            HistogramOpts::new("rate_limit_delay_seconds", "No help"),
+            &["rule", "method"],
+        )
+        .unwrap();
+        let rate_limit_jitter = HistogramVec::new(
+            HistogramOpts::new("rate_limit_jitter_seconds", "No help"),
+            &["rule", "method"],
+        )
+        .unwrap();
+        let rate_limit_keys = GaugeVec::new(Opts::new("rate_limit_keys", "No help"), &["limiter"]).unwrap();
+
         let open_session_count = register(open_counter, registry).unwrap();
         let closed_session_count = register(closed_counter, registry).unwrap();
         let cache_query_counter = register(cache_query_counter, registry).unwrap();
@@ -87,6 +135,12 @@ impl InnerMetrics {
         let calls_started = register(calls_started_counter, registry).unwrap();
         let calls_finished = register(calls_finished_counter, registry).unwrap();
 
+        let rate_limit_delayed = register(rate_limit_delayed, registry).unwrap();
+        let rate_limit_rejected = register(rate_limit_rejected, registry).unwrap();
+        let rate_limit_delay = register(rate_limit_delay, registry).unwrap();
+        let rate_limit_jitter = register(rate_limit_jitter, registry).unwrap();
+        let rate_limit_keys = register(rate_limit_keys, registry).unwrap();
+
         Self {
             cache_miss_counter,
             cache_query_counter,
@@ -95,6 +149,11 @@ impl InnerMetrics {
             calls_started,
             calls_finished,
             call_times,
+            rate_limit_delayed,
+            rate_limit_rejected,
+            rate_limit_delay,
+            rate_limit_jitter,
+            rate_limit_keys,
         }
     }
     fn ws_open(&self) {
@@ -112,4 +171,18 @@ impl InnerMetrics {
     fn cache_miss(&self, method: &str) {
         self.cache_miss_counter.with_label_values(&[method]).inc();
     }
+
+    fn rate_limit_delayed(&self, rule: &str, method: &str, delay: Duration, jitter: Duration) {
+        self.rate_limit_delayed.with_label_values(&[rule, method]).inc();
+        self.rate_limit_delay
+            .with_label_values(&[rule, method])
+            .observe(delay.as_secs_f64());
+        self.rate_limit_jitter
+            .with_label_values(&[rule, method])
+            .observe(jitter.as_secs_f64());
+    }
+
+    fn rate_limit_rejected(&self, rule: &str, method: &str) {
+        self.rate_limit_rejected.with_label_values(&[rule, method]).inc();
+    }
 }
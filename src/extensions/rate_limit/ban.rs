@@ -0,0 +1,180 @@
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BanConfig {
+    /// Number of calls of an ip rejected by `on_limit: reject` within `window_secs` before
+    /// it is banned. Calls queued by `on_limit: queue` are not offenses.
+    pub threshold: u32,
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    #[serde(default = "default_duration_secs")]
+    pub duration_secs: u64,
+}
+
+fn default_window_secs() -> u64 {
+    60
+}
+
+fn default_duration_secs() -> u64 {
+    600
+}
+
+struct Offenses {
+    count: u32,
+    window_start: Instant,
+}
+
+/// Temporarily banned ips, and the offenses of those not banned yet.
+pub struct BanList {
+    threshold: u32,
+    window: Duration,
+    duration: Duration,
+    offenses: Mutex<HashMap<String, Offenses>>,
+    bans: Mutex<HashMap<String, Instant>>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl BanList {
+    pub fn new(config: &BanConfig) -> Self {
+        Self {
+            threshold: config.threshold,
+            window: Duration::from_secs(config.window_secs),
+            duration: Duration::from_secs(config.duration_secs),
+            offenses: Default::default(),
+            bans: Default::default(),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Records that a call of the ip was rejected, banning it once it reaches the threshold.
+    pub fn record_offense(&self, ip: &str) {
+        let now = Instant::now();
+        let mut offenses = self.offenses.lock().expect("ban list lock poisoned");
+
+        let entry = offenses.entry(ip.to_string()).or_insert(Offenses {
+            count: 0,
+            window_start: now,
+        });
+        if now.saturating_duration_since(entry.window_start) > self.window {
+            entry.count = 0;
+            entry.window_start = now;
+        }
+        entry.count += 1;
+
+        if entry.count >= self.threshold {
+            offenses.remove(ip);
+            tracing::warn!(
+                "Banning {ip} for {}s after exceeding rate limits",
+                self.duration.as_secs()
+            );
+            self.bans
+                .lock()
+                .expect("ban list lock poisoned")
+                .insert(ip.to_string(), now + self.duration);
+        }
+
+        // forget offenses from past windows, at most once per window
+        let mut last_sweep = self.last_sweep.lock().expect("ban list lock poisoned");
+        if now.saturating_duration_since(*last_sweep) > self.window {
+            *last_sweep = now;
+            offenses.retain(|_, o| now.saturating_duration_since(o.window_start) <= self.window);
+            self.bans
+                .lock()
+                .expect("ban list lock poisoned")
+                .retain(|_, until| *until > now);
+        }
+    }
+
+    pub fn is_banned(&self, ip: &str) -> bool {
+        let mut bans = self.bans.lock().expect("ban list lock poisoned");
+        match bans.get(ip) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                bans.remove(ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the banned ips with the remaining ban time.
+    pub fn list(&self) -> Vec<(String, Duration)> {
+        let now = Instant::now();
+        let mut bans: Vec<_> = self
+            .bans
+            .lock()
+            .expect("ban list lock poisoned")
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| (ip.clone(), until.saturating_duration_since(now)))
+            .collect();
+        bans.sort();
+        bans
+    }
+
+    /// Lifts the ban of the ip, or all bans. Returns the number of ips unbanned.
+    pub fn clear(&self, ip: Option<&str>) -> usize {
+        let mut offenses = self.offenses.lock().expect("ban list lock poisoned");
+        let mut bans = self.bans.lock().expect("ban list lock poisoned");
+        match ip {
+            Some(ip) => {
+                offenses.remove(ip);
+                bans.remove(ip).map(|_| 1).unwrap_or(0)
+            }
+            None => {
+                offenses.clear();
+                bans.drain().count()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_repeat_offenders() {
+        let bans = BanList::new(&BanConfig {
+            threshold: 3,
+            window_secs: 60,
+            duration_secs: 600,
+        });
+
+        bans.record_offense("1.1.1.1");
+        bans.record_offense("1.1.1.1");
+        bans.record_offense("2.2.2.2");
+        assert!(!bans.is_banned("1.1.1.1"));
+
+        bans.record_offense("1.1.1.1");
+        assert!(bans.is_banned("1.1.1.1"));
+        assert!(!bans.is_banned("2.2.2.2"));
+
+        let list = bans.list();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].0, "1.1.1.1");
+        assert!(list[0].1 > Duration::from_secs(590));
+
+        assert_eq!(bans.clear(Some("2.2.2.2")), 0);
+        assert_eq!(bans.clear(Some("1.1.1.1")), 1);
+        assert!(!bans.is_banned("1.1.1.1"));
+    }
+
+    #[test]
+    fn bans_expire() {
+        let bans = BanList::new(&BanConfig {
+            threshold: 1,
+            window_secs: 60,
+            duration_secs: 0,
+        });
+
+        bans.record_offense("1.1.1.1");
+        assert!(!bans.is_banned("1.1.1.1"));
+        assert!(bans.list().is_empty());
+    }
+}
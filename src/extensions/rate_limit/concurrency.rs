@@ -0,0 +1,295 @@
+use futures::{future::BoxFuture, FutureExt};
+use jsonrpsee::{
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    MethodResponse,
+};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::utils::errors;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct ConcurrencyConfig {
+    /// Max number of requests in flight per ip.
+    pub ip: Option<u32>,
+    /// Max number of requests in flight per connection.
+    pub connection: Option<u32>,
+    #[serde(default)]
+    pub on_limit: OnLimit,
+    /// How long a queued request may wait for a slot before it is rejected. Waits forever if not set.
+    #[serde(default)]
+    pub queue_timeout_millis: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnLimit {
+    /// Wait until the request can proceed.
+    #[default]
+    Queue,
+    /// Reject the request right away.
+    Reject,
+}
+
+/// In flight requests per ip, shared by all connections.
+pub(crate) struct IpSemaphores {
+    limit: usize,
+    semaphores: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl IpSemaphores {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit: limit as usize,
+            semaphores: Default::default(),
+        }
+    }
+
+    fn get(&self, ip: &str) -> Arc<Semaphore> {
+        let mut semaphores = self.semaphores.lock().expect("concurrency lock poisoned");
+        semaphores
+            .entry(ip.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone()
+    }
+
+    /// Drops the semaphore of the ip once no request uses it anymore.
+    fn release(&self, ip: &str, semaphore: Arc<Semaphore>) {
+        let mut semaphores = self.semaphores.lock().expect("concurrency lock poisoned");
+        // one reference is held by the map, the other one is ours
+        if Arc::strong_count(&semaphore) == 2 {
+            semaphores.remove(ip);
+        }
+    }
+
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.semaphores.lock().unwrap().len()
+    }
+}
+
+/// The semaphore of an ip, released from [`IpSemaphores`] on drop.
+/// Permits must be dropped before the slot.
+struct IpSlot {
+    semaphores: Arc<IpSemaphores>,
+    ip: String,
+    semaphore: Option<Arc<Semaphore>>,
+}
+
+impl IpSlot {
+    fn new(semaphores: Arc<IpSemaphores>, ip: String) -> Self {
+        let semaphore = Some(semaphores.get(&ip));
+        Self {
+            semaphores,
+            ip,
+            semaphore,
+        }
+    }
+
+    fn semaphore(&self) -> Arc<Semaphore> {
+        self.semaphore.clone().expect("only taken on drop")
+    }
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        if let Some(semaphore) = self.semaphore.take() {
+            self.semaphores.release(&self.ip, semaphore);
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConcurrencyLimitLayer {
+    ip_addr: String,
+    ip_semaphores: Option<Arc<IpSemaphores>>,
+    connection_limit: Option<u32>,
+    on_limit: OnLimit,
+    queue_timeout: Option<Duration>,
+}
+
+impl ConcurrencyLimitLayer {
+    pub(crate) fn new(ip_addr: String, ip_semaphores: Option<Arc<IpSemaphores>>, config: &ConcurrencyConfig) -> Self {
+        Self {
+            ip_addr,
+            ip_semaphores,
+            connection_limit: config.connection,
+            on_limit: config.on_limit,
+            queue_timeout: config.queue_timeout_millis.map(Duration::from_millis),
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for ConcurrencyLimitLayer {
+    type Service = ConcurrencyLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        ConcurrencyLimit {
+            service,
+            ip_addr: self.ip_addr.clone(),
+            ip_semaphores: self.ip_semaphores.clone(),
+            connection_semaphore: self
+                .connection_limit
+                .map(|limit| Arc::new(Semaphore::new(limit as usize))),
+            on_limit: self.on_limit,
+            queue_timeout: self.queue_timeout,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ConcurrencyLimit<S> {
+    service: S,
+    ip_addr: String,
+    ip_semaphores: Option<Arc<IpSemaphores>>,
+    connection_semaphore: Option<Arc<Semaphore>>,
+    on_limit: OnLimit,
+    queue_timeout: Option<Duration>,
+}
+
+/// Waits for a permit according to the limit policy. Returns `None` if the request should be rejected.
+async fn acquire(
+    semaphore: Arc<Semaphore>,
+    on_limit: OnLimit,
+    queue_timeout: Option<Duration>,
+) -> Option<OwnedSemaphorePermit> {
+    match (on_limit, queue_timeout) {
+        (OnLimit::Reject, _) => semaphore.try_acquire_owned().ok(),
+        (OnLimit::Queue, None) => semaphore.acquire_owned().await.ok(),
+        (OnLimit::Queue, Some(timeout)) => tokio::time::timeout(timeout, semaphore.acquire_owned())
+            .await
+            .ok()
+            .and_then(Result::ok),
+    }
+}
+
+impl<'a, S> RpcServiceT<'a> for ConcurrencyLimit<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        let service = self.service.clone();
+        let ip_addr = self.ip_addr.clone();
+        let ip_semaphores = self.ip_semaphores.clone();
+        let connection_semaphore = self.connection_semaphore.clone();
+        let on_limit = self.on_limit;
+        let queue_timeout = self.queue_timeout;
+
+        async move {
+            // the connection slot is taken first so a connection doesn't hold
+            // ip slots while waiting for its own requests
+            let _connection_permit = match connection_semaphore {
+                Some(semaphore) => match acquire(semaphore, on_limit, queue_timeout).await {
+                    Some(permit) => Some(permit),
+                    None => return MethodResponse::error(req.id, errors::server_busy("Too many concurrent requests")),
+                },
+                None => None,
+            };
+
+            let Some(ip_semaphores) = ip_semaphores else {
+                return service.call(req).await;
+            };
+
+            let slot = IpSlot::new(ip_semaphores, ip_addr);
+            let response = match acquire(slot.semaphore(), on_limit, queue_timeout).await {
+                Some(_permit) => service.call(req).await,
+                None => MethodResponse::error(req.id, errors::server_busy("Too many concurrent requests")),
+            };
+            drop(slot);
+
+            response
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonrpsee::types::Id;
+    use jsonrpsee::ResponsePayload;
+
+    #[derive(Clone)]
+    struct MockService;
+    impl RpcServiceT<'static> for MockService {
+        type Future = BoxFuture<'static, MethodResponse>;
+
+        fn call(&self, req: Request<'static>) -> Self::Future {
+            async move {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                MethodResponse::response(req.id, ResponsePayload::success("ok"), 1024)
+            }
+            .boxed()
+        }
+    }
+
+    fn config(ip: Option<u32>, connection: Option<u32>, on_limit: OnLimit) -> ConcurrencyConfig {
+        ConcurrencyConfig {
+            ip,
+            connection,
+            on_limit,
+            queue_timeout_millis: None,
+        }
+    }
+
+    async fn run(services: &[&ConcurrencyLimit<MockService>], count: usize) -> (usize, Duration) {
+        let start = tokio::time::Instant::now();
+        let calls = services.iter().flat_map(|service| {
+            (0..count).map(|id| service.call(Request::new("test".into(), None, Id::Number(id as u64))))
+        });
+        let results = futures::future::join_all(calls).await;
+        (results.iter().filter(|r| r.is_success()).count(), start.elapsed())
+    }
+
+    #[tokio::test]
+    async fn queues_requests_per_ip() {
+        let semaphores = Arc::new(IpSemaphores::new(2));
+        let layer = ConcurrencyLimitLayer::new(
+            "1.1.1.1".to_string(),
+            Some(semaphores.clone()),
+            &config(Some(2), None, OnLimit::Queue),
+        );
+        let conn1 = tower::Layer::layer(&layer, MockService);
+        let conn2 = tower::Layer::layer(&layer, MockService);
+
+        // 4 requests from the same ip, 2 at a time
+        let (ok, elapsed) = run(&[&conn1, &conn2], 2).await;
+        assert_eq!(ok, 4);
+        assert!(elapsed >= Duration::from_millis(200));
+        assert!(elapsed < Duration::from_millis(300));
+
+        // no state is kept for idle ips
+        assert_eq!(semaphores.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_requests_per_connection() {
+        let layer = ConcurrencyLimitLayer::new("1.1.1.1".to_string(), None, &config(None, Some(2), OnLimit::Reject));
+        let conn1 = tower::Layer::layer(&layer, MockService);
+        let conn2 = tower::Layer::layer(&layer, MockService);
+
+        let (ok, elapsed) = run(&[&conn1, &conn2], 3).await;
+        // 2 per connection
+        assert_eq!(ok, 4);
+        assert!(elapsed < Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn queue_timeout_rejects() {
+        let mut config = config(None, Some(1), OnLimit::Queue);
+        config.queue_timeout_millis = Some(150);
+        let layer = ConcurrencyLimitLayer::new("1.1.1.1".to_string(), None, &config);
+        let conn = tower::Layer::layer(&layer, MockService);
+
+        // 2nd request waits 100ms, 3rd one would wait 200ms
+        let (ok, _) = run(&[&conn], 3).await;
+        assert_eq!(ok, 2);
+    }
+}
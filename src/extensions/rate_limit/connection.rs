@@ -1,6 +1,10 @@
-use crate::extensions::rate_limit::MethodWeights;
+use crate::extensions::prometheus::RpcMetrics;
+use crate::extensions::rate_limit::{
+    feedback::{self, DirectRateLimiter},
+    MethodWeights, OnLimit, RateLimitFeedback,
+};
 use futures::{future::BoxFuture, FutureExt};
-use governor::{DefaultDirectRateLimiter, Jitter, RateLimiter};
+use governor::Jitter;
 use jsonrpsee::{
     server::{middleware::rpc::RpcServiceT, types::Request},
     MethodResponse,
@@ -13,6 +17,8 @@ pub struct ConnectionRateLimitLayer {
     period: Duration,
     jitter: Jitter,
     method_weights: MethodWeights,
+    on_limit: OnLimit,
+    metrics: RpcMetrics,
 }
 
 impl ConnectionRateLimitLayer {
@@ -22,41 +28,60 @@ impl ConnectionRateLimitLayer {
             period,
             jitter,
             method_weights,
+            on_limit: OnLimit::Queue,
+            metrics: RpcMetrics::noop(),
         }
     }
+
+    pub fn with_on_limit(mut self, on_limit: OnLimit) -> Self {
+        self.on_limit = on_limit;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: RpcMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
 }
 
 impl<S> tower::Layer<S> for ConnectionRateLimitLayer {
     type Service = ConnectionRateLimit<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        ConnectionRateLimit::new(
+        let mut service = ConnectionRateLimit::new(
             service,
             self.burst,
             self.period,
             self.jitter,
             self.method_weights.clone(),
-        )
+        );
+        service.on_limit = self.on_limit;
+        service.metrics = self.metrics.clone();
+        service
     }
 }
 
 #[derive(Clone)]
 pub struct ConnectionRateLimit<S> {
     service: S,
-    limiter: Arc<DefaultDirectRateLimiter>,
+    limiter: Arc<DirectRateLimiter>,
     jitter: Jitter,
     method_weights: MethodWeights,
+    on_limit: OnLimit,
+    metrics: RpcMetrics,
 }
 
 impl<S> ConnectionRateLimit<S> {
     pub fn new(service: S, burst: NonZeroU32, period: Duration, jitter: Jitter, method_weights: MethodWeights) -> Self {
         let quota = super::build_quota(burst, period);
-        let limiter = Arc::new(RateLimiter::direct(quota));
+        let limiter = Arc::new(feedback::direct_limiter(quota));
         Self {
             service,
             limiter,
             jitter,
             method_weights,
+            on_limit: OnLimit::Queue,
+            metrics: RpcMetrics::noop(),
         }
     }
 }
@@ -72,13 +97,19 @@ where
         let service = self.service.clone();
         let limiter = self.limiter.clone();
         let weight = self.method_weights.get(req.method_name());
+        let on_limit = self.on_limit;
+        let metrics = self.metrics.clone();
 
         async move {
             if let Some(n) = NonZeroU32::new(weight) {
-                limiter
-                    .until_n_ready_with_jitter(n, jitter)
-                    .await
-                    .expect("check_n have been done during init");
+                let outcome = match on_limit {
+                    OnLimit::Queue => Ok(feedback::until_n_ready(&limiter, n, jitter).await),
+                    OnLimit::Reject => feedback::check_n(&limiter, n),
+                };
+                feedback::observe(&metrics, "connection", req.method_name(), &outcome);
+                if let Err(err) = RateLimitFeedback::record(req.extensions().get(), outcome) {
+                    return MethodResponse::error(req.id, err);
+                }
             }
             service.call(req).await
         }
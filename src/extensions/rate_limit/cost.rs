@@ -0,0 +1,451 @@
+//! Cost based rate limiting.
+//!
+//! Every ip has a budget refilled over `period_secs`. Requests are charged after
+//! the response, based on what they consumed, so the budget can go negative.
+//! Further requests wait until it is positive again, or are rejected with `on_limit: reject`.
+
+use crate::extensions::prometheus::RpcMetrics;
+use crate::extensions::rate_limit::{feedback, Exceeded, OnLimit, RateLimitFeedback};
+use futures::{future::BoxFuture, FutureExt};
+use jsonrpsee::{
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    MethodResponse,
+};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct CostConfig {
+    /// Cost units available per ip in a period.
+    pub budget: u32,
+    #[serde(default = "default_period_secs")]
+    pub period_secs: u64,
+    /// Charge 1 unit per this many milliseconds spent handling the request, not counting
+    /// the time it waited for other rate limits.
+    #[serde(default)]
+    pub latency_millis_per_unit: Option<u64>,
+    /// Charge 1 unit per this many bytes of response.
+    #[serde(default)]
+    pub response_bytes_per_unit: Option<u64>,
+    /// Charge 1 unit per this many blocks queried by `eth_getLogs`. Block tags are
+    /// resolved against the current and finalized heads of the `eth_api` extension.
+    #[serde(default)]
+    pub blocks_per_unit: Option<u64>,
+    /// Blocks charged for an `eth_getLogs` range that can't be resolved, e.g. `latest`
+    /// before the first head is known. Also caps the blocks charged for a range.
+    #[serde(default)]
+    pub max_blocks: Option<u64>,
+}
+
+fn default_period_secs() -> u64 {
+    60
+}
+
+impl CostConfig {
+    /// Every request costs at least 1 unit.
+    fn cost(&self, latency: Duration, response_bytes: usize, blocks: Option<u64>) -> f64 {
+        let mut cost = 1.0;
+        if let Some(per_unit) = self.latency_millis_per_unit {
+            cost += latency.as_millis() as f64 / per_unit.max(1) as f64;
+        }
+        if let Some(per_unit) = self.response_bytes_per_unit {
+            cost += response_bytes as f64 / per_unit.max(1) as f64;
+        }
+        if let (Some(per_unit), Some(blocks)) = (self.blocks_per_unit, blocks) {
+            cost += blocks as f64 / per_unit.max(1) as f64;
+        }
+        cost
+    }
+}
+
+struct Bucket {
+    balance: f64,
+    updated: Instant,
+}
+
+/// Budgets per ip, shared by all connections.
+pub(crate) struct CostLimiter {
+    capacity: f64,
+    // units per second
+    refill_rate: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    last_sweep: Mutex<Instant>,
+}
+
+impl CostLimiter {
+    pub fn new(budget: u32, period: Duration) -> Self {
+        Self {
+            capacity: budget as f64,
+            refill_rate: budget as f64 / period.as_secs_f64(),
+            buckets: Default::default(),
+            last_sweep: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.balance = (bucket.balance + elapsed * self.refill_rate).min(self.capacity);
+        bucket.updated = now;
+    }
+
+    pub fn budget(&self) -> u32 {
+        self.capacity as u32
+    }
+
+    /// Returns how long to wait until the budget of `key` is positive.
+    fn wait_time(&self, key: &str) -> Option<Duration> {
+        let buckets = self.buckets.lock().expect("cost limiter lock poisoned");
+        let bucket = buckets.get(key)?;
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        let balance = bucket.balance + elapsed * self.refill_rate;
+        if balance > 0.0 {
+            return None;
+        }
+        Some(Duration::from_secs_f64((-balance / self.refill_rate).max(0.001)))
+    }
+
+    pub async fn until_ready(&self, key: &str) {
+        while let Some(wait) = self.wait_time(key) {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    pub fn charge(&self, key: &str, cost: f64) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("cost limiter lock poisoned");
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            balance: self.capacity,
+            updated: now,
+        });
+        self.refill(bucket, now);
+        bucket.balance -= cost;
+
+        // forget ips whose budget is full again, at most once per period
+        let mut last_sweep = self.last_sweep.lock().expect("cost limiter lock poisoned");
+        if now.saturating_duration_since(*last_sweep).as_secs_f64() * self.refill_rate >= self.capacity {
+            *last_sweep = now;
+            buckets.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+                bucket.balance + elapsed * self.refill_rate < self.capacity
+            });
+        }
+    }
+
+    #[cfg(test)]
+    fn balance(&self, key: &str) -> f64 {
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get_mut(key).unwrap();
+        self.refill(bucket, Instant::now());
+        bucket.balance
+    }
+}
+
+/// Resolves a block tag such as `latest` or `finalized` to the number of the block.
+pub(crate) type BlockTags = Arc<dyn Fn(&str) -> Option<u64> + Send + Sync>;
+
+/// Number of blocks queried by an `eth_getLogs` request. A bound that is omitted
+/// defaults to `latest`. `max_blocks` is charged if a bound can't be resolved.
+fn get_logs_blocks(req: &Request, block_tags: &BlockTags, max_blocks: Option<u64>) -> Option<u64> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Filter {
+        from_block: Option<String>,
+        to_block: Option<String>,
+        block_hash: Option<String>,
+    }
+
+    if req.method_name() != "eth_getLogs" {
+        return None;
+    }
+
+    let params: &RawValue = req.params.as_deref()?;
+    let Ok([filter]) = serde_json::from_str::<[Filter; 1]>(params.get()) else {
+        return max_blocks;
+    };
+    if filter.block_hash.is_some() {
+        return Some(1);
+    }
+
+    let resolve = |block: Option<&str>| match block.unwrap_or("latest") {
+        "earliest" => Some(0),
+        n if n.starts_with("0x") => u64::from_str_radix(&n[2..], 16).ok(),
+        tag => block_tags(tag),
+    };
+    let (Some(from), Some(to)) = (
+        resolve(filter.from_block.as_deref()),
+        resolve(filter.to_block.as_deref()),
+    ) else {
+        return max_blocks;
+    };
+    let blocks = to.saturating_sub(from) + 1;
+    Some(max_blocks.map_or(blocks, |max| blocks.min(max)))
+}
+
+#[derive(Clone)]
+pub struct CostLimitLayer {
+    ip_addr: String,
+    limiter: Arc<CostLimiter>,
+    config: Arc<CostConfig>,
+    block_tags: BlockTags,
+    on_limit: OnLimit,
+    metrics: RpcMetrics,
+}
+
+impl CostLimitLayer {
+    pub(crate) fn new(
+        ip_addr: String,
+        limiter: Arc<CostLimiter>,
+        config: Arc<CostConfig>,
+        block_tags: BlockTags,
+        on_limit: OnLimit,
+        metrics: RpcMetrics,
+    ) -> Self {
+        Self {
+            ip_addr,
+            limiter,
+            config,
+            block_tags,
+            on_limit,
+            metrics,
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for CostLimitLayer {
+    type Service = CostLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        CostLimit {
+            service,
+            ip_addr: self.ip_addr.clone(),
+            limiter: self.limiter.clone(),
+            config: self.config.clone(),
+            block_tags: self.block_tags.clone(),
+            on_limit: self.on_limit,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CostLimit<S> {
+    service: S,
+    ip_addr: String,
+    limiter: Arc<CostLimiter>,
+    config: Arc<CostConfig>,
+    block_tags: BlockTags,
+    on_limit: OnLimit,
+    metrics: RpcMetrics,
+}
+
+impl<'a, S> RpcServiceT<'a> for CostLimit<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        let service = self.service.clone();
+        let ip_addr = self.ip_addr.clone();
+        let limiter = self.limiter.clone();
+        let config = self.config.clone();
+        let blocks = config
+            .blocks_per_unit
+            .and_then(|_| get_logs_blocks(&req, &self.block_tags, config.max_blocks));
+        let on_limit = self.on_limit;
+        let metrics = self.metrics.clone();
+
+        async move {
+            match on_limit {
+                OnLimit::Queue => limiter.until_ready(&ip_addr).await,
+                OnLimit::Reject => {
+                    if let Some(retry_after) = limiter.wait_time(&ip_addr) {
+                        let outcome = Err(Exceeded {
+                            limit: limiter.budget(),
+                            retry_after,
+                        });
+                        feedback::observe(&metrics, "cost", req.method_name(), &outcome);
+                        if let Err(err) = RateLimitFeedback::record(req.extensions().get(), outcome) {
+                            return MethodResponse::error(req.id, err);
+                        }
+                    }
+                }
+            }
+
+            let start = Instant::now();
+            let response = service.call(req).await;
+            let cost = config.cost(start.elapsed(), response.as_result().len(), blocks);
+            limiter.charge(&ip_addr, cost);
+
+            response
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonrpsee::types::Id;
+    use jsonrpsee::ResponsePayload;
+
+    #[derive(Clone)]
+    struct MockService;
+    impl RpcServiceT<'static> for MockService {
+        type Future = BoxFuture<'static, MethodResponse>;
+
+        fn call(&self, req: Request<'static>) -> Self::Future {
+            async move {
+                let size = if req.method_name() == "big" { 1000 } else { 10 };
+                MethodResponse::response(req.id, ResponsePayload::success("x".repeat(size)), 1024 * 1024)
+            }
+            .boxed()
+        }
+    }
+
+    fn request(method: &'static str, params: Option<&str>) -> Request<'static> {
+        let params = params.map(|p| &*Box::leak(RawValue::from_string(p.to_string()).unwrap()));
+        Request::new(method.into(), params, Id::Number(1))
+    }
+
+    fn block_tags() -> BlockTags {
+        Arc::new(|tag| match tag {
+            "latest" => Some(0x100),
+            "finalized" => Some(0xf0),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn get_logs_blocks_works() {
+        let tags = block_tags();
+        let blocks = |params| get_logs_blocks(&request("eth_getLogs", Some(params)), &tags, None);
+        assert_eq!(blocks(r#"[{"fromBlock":"0x1","toBlock":"0x10"}]"#), Some(16));
+        assert_eq!(blocks(r#"[{"blockHash":"0x01"}]"#), Some(1));
+        assert_eq!(blocks(r#"[{"fromBlock":"0x10","toBlock":"0x1"}]"#), Some(1));
+        assert_eq!(
+            get_logs_blocks(
+                &request("eth_call", Some(r#"[{"fromBlock":"0x1","toBlock":"0x10"}]"#)),
+                &tags,
+                None
+            ),
+            None
+        );
+
+        // tags are resolved against the heads, omitted bounds are latest
+        assert_eq!(blocks(r#"[{"fromBlock":"0x0","toBlock":"latest"}]"#), Some(0x101));
+        assert_eq!(blocks(r#"[{"fromBlock":"earliest"}]"#), Some(0x101));
+        assert_eq!(blocks(r#"[{"fromBlock":"finalized"}]"#), Some(0x11));
+        assert_eq!(blocks(r#"[{}]"#), Some(1));
+
+        // unresolved bounds are charged max_blocks, which also caps ranges
+        let capped = |params| get_logs_blocks(&request("eth_getLogs", Some(params)), &tags, Some(100));
+        assert_eq!(blocks(r#"[{"fromBlock":"safe"}]"#), None);
+        assert_eq!(capped(r#"[{"fromBlock":"safe"}]"#), Some(100));
+        assert_eq!(capped(r#"[{"fromBlock":"0x0","toBlock":"latest"}]"#), Some(100));
+        assert_eq!(capped(r#"[{"fromBlock":"0x1","toBlock":"0x10"}]"#), Some(16));
+    }
+
+    #[tokio::test]
+    async fn charges_by_cost() {
+        let config = Arc::new(CostConfig {
+            budget: 100,
+            period_secs: 1,
+            latency_millis_per_unit: None,
+            response_bytes_per_unit: Some(100),
+            blocks_per_unit: Some(10),
+            max_blocks: None,
+        });
+        let limiter = Arc::new(CostLimiter::new(config.budget, Duration::from_secs(config.period_secs)));
+        let layer = CostLimitLayer::new(
+            "1.1.1.1".to_string(),
+            limiter.clone(),
+            config,
+            block_tags(),
+            OnLimit::Queue,
+            RpcMetrics::noop(),
+        );
+        let service = tower::Layer::layer(&layer, MockService);
+
+        // 1 + 46 bytes / 100
+        service.call(request("cheap", None)).await;
+        assert!((limiter.balance("1.1.1.1") - 98.54).abs() < 0.1);
+
+        // 1 + 1036 bytes / 100
+        service.call(request("big", None)).await;
+        assert!((limiter.balance("1.1.1.1") - 87.18).abs() < 0.1);
+
+        // 1 + 46 bytes / 100 + 1000 blocks / 10
+        service
+            .call(request(
+                "eth_getLogs",
+                Some(r#"[{"fromBlock":"0x1","toBlock":"0x3e8"}]"#),
+            ))
+            .await;
+        assert!(limiter.balance("1.1.1.1") < 0.0);
+
+        // in debt, next request waits for the budget to refill
+        let start = tokio::time::Instant::now();
+        service.call(request("cheap", None)).await;
+        assert!(start.elapsed() >= Duration::from_millis(100));
+
+        // other ips are not affected
+        let other = tower::Layer::layer(
+            &CostLimitLayer {
+                ip_addr: "2.2.2.2".to_string(),
+                ..layer.clone()
+            },
+            MockService,
+        );
+        let start = tokio::time::Instant::now();
+        other.call(request("cheap", None)).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[tokio::test]
+    async fn rejects_when_in_debt() {
+        let config = Arc::new(CostConfig {
+            budget: 10,
+            period_secs: 10,
+            latency_millis_per_unit: None,
+            response_bytes_per_unit: None,
+            blocks_per_unit: Some(1),
+            max_blocks: None,
+        });
+        let limiter = Arc::new(CostLimiter::new(config.budget, Duration::from_secs(config.period_secs)));
+        let layer = CostLimitLayer::new(
+            "1.1.1.1".to_string(),
+            limiter.clone(),
+            config,
+            block_tags(),
+            OnLimit::Reject,
+            RpcMetrics::noop(),
+        );
+        let service = tower::Layer::layer(&layer, MockService);
+
+        // 1 + 0x101 blocks up to latest
+        let response = service
+            .call(request("eth_getLogs", Some(r#"[{"fromBlock":"0x0"}]"#)))
+            .await;
+        assert!(response.is_success());
+
+        let feedback = RateLimitFeedback::default();
+        let mut req = request("cheap", None);
+        req.extensions_mut().insert(feedback.clone());
+        let response = service.call(req).await;
+        assert!(response.is_error());
+        assert!(response.as_result().contains("retry_after_millis"));
+
+        let mut headers = http::HeaderMap::new();
+        feedback.write_headers(&mut headers);
+        assert_eq!(headers["x-ratelimit-limit"], "10");
+        // 10 - 258 units, refilled at 1 unit per second
+        assert_eq!(headers[http::header::RETRY_AFTER], "248");
+    }
+}
@@ -0,0 +1,276 @@
+//! Reports the state of the quotas back to clients.
+//!
+//! The rate limit layers record the outcome of every call in the
+//! [`RateLimitFeedback`] found in the request extensions. The server inserts
+//! one for every HTTP request and turns it into `X-RateLimit-*` and
+//! `Retry-After` headers. Rejected calls also carry the wait time in the
+//! JSON-RPC error data, which is what WS clients get.
+
+use governor::{
+    clock::{Clock, DefaultClock},
+    middleware::StateInformationMiddleware,
+    state::{InMemoryState, NotKeyed},
+    Jitter, Quota, RateLimiter,
+};
+use http::{HeaderMap, HeaderValue};
+use jsonrpsee::types::ErrorObjectOwned;
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::extensions::prometheus::RpcMetrics;
+use crate::utils::errors;
+
+/// Cells were taken from a quota.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permit {
+    pub limit: u32,
+    pub remaining: u32,
+    /// Time the call waited for the quota to refill, including `jitter`.
+    pub delay: Duration,
+    /// Random delay added to spread out the calls that waited.
+    pub jitter: Duration,
+}
+
+impl Permit {
+    /// Whether the call had to wait for the quota to refill.
+    pub fn waited(&self) -> bool {
+        !self.delay.is_zero()
+    }
+}
+
+/// The quota is exhausted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exceeded {
+    pub limit: u32,
+    pub retry_after: Duration,
+}
+
+/// A per connection limiter reporting the remaining quota.
+pub(crate) type DirectRateLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock, StateInformationMiddleware>;
+
+pub(crate) fn direct_limiter(quota: Quota) -> DirectRateLimiter {
+    RateLimiter::direct(quota).with_middleware::<StateInformationMiddleware>()
+}
+
+pub(crate) fn check_n(limiter: &DirectRateLimiter, n: NonZeroU32) -> Result<Permit, Exceeded> {
+    match limiter.check_n(n).expect("check_n have been done during init") {
+        Ok(snapshot) => Ok(Permit {
+            limit: snapshot.quota().burst_size().get(),
+            remaining: snapshot.remaining_burst_capacity(),
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+        }),
+        Err(not_until) => Err(Exceeded {
+            limit: not_until.quota().burst_size().get(),
+            retry_after: not_until.wait_time_from(DefaultClock::default().now()),
+        }),
+    }
+}
+
+pub(crate) async fn until_n_ready(limiter: &DirectRateLimiter, n: NonZeroU32, jitter: Jitter) -> Permit {
+    let mut wait = Wait::default();
+    loop {
+        match check_n(limiter, n) {
+            Ok(permit) => return wait.permit(permit),
+            Err(exceeded) => wait.sleep(jitter, exceeded).await,
+        }
+    }
+}
+
+/// Time spent waiting for a quota to refill.
+#[derive(Default)]
+pub(crate) struct Wait {
+    delay: Duration,
+    jitter: Duration,
+}
+
+impl Wait {
+    pub async fn sleep(&mut self, jitter: Jitter, exceeded: Exceeded) {
+        let jitter = jitter + Duration::ZERO;
+        let delay = jitter + exceeded.retry_after;
+        self.delay += delay;
+        self.jitter += jitter;
+        tokio::time::sleep(delay).await;
+    }
+
+    pub fn permit(self, permit: Permit) -> Permit {
+        Permit {
+            delay: self.delay,
+            jitter: self.jitter,
+            ..permit
+        }
+    }
+}
+
+/// Counts the calls of `method` delayed or rejected by `rule`.
+pub(crate) fn observe(metrics: &RpcMetrics, rule: &str, method: &str, outcome: &Result<Permit, Exceeded>) {
+    match outcome {
+        Ok(permit) if permit.waited() => metrics.rate_limit_delayed(rule, method, permit.delay, permit.jitter),
+        Ok(_) => {}
+        Err(_) => metrics.rate_limit_rejected(rule, method),
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    // the quota closest to be exhausted
+    limit: Option<u32>,
+    remaining: u32,
+    retry_after: Option<Duration>,
+}
+
+/// Collects the outcome of the rate limited calls of a request.
+#[derive(Debug, Clone, Default)]
+pub struct RateLimitFeedback(Arc<Mutex<State>>);
+
+impl RateLimitFeedback {
+    /// Records the outcome of a call, returning the error to respond with if it was rejected.
+    pub fn record(
+        feedback: Option<&RateLimitFeedback>,
+        outcome: Result<Permit, Exceeded>,
+    ) -> Result<(), ErrorObjectOwned> {
+        let (limit, remaining, retry_after) = match outcome {
+            Ok(permit) => (permit.limit, permit.remaining, None),
+            Err(exceeded) => (exceeded.limit, 0, Some(exceeded.retry_after)),
+        };
+
+        if let Some(feedback) = feedback {
+            let mut state = feedback.0.lock().expect("rate limit feedback lock poisoned");
+            if state.limit.is_none() || remaining < state.remaining {
+                state.limit = Some(limit);
+                state.remaining = remaining;
+            }
+            state.retry_after = state.retry_after.max(retry_after);
+        }
+
+        match retry_after {
+            Some(retry_after) => Err(errors::rate_limited(limit, retry_after)),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds the `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `Retry-After` headers.
+    pub fn write_headers(&self, headers: &mut HeaderMap) {
+        let state = self.0.lock().expect("rate limit feedback lock poisoned");
+        let Some(limit) = state.limit else {
+            return;
+        };
+        headers.insert("x-ratelimit-limit", HeaderValue::from(limit));
+        headers.insert("x-ratelimit-remaining", HeaderValue::from(state.remaining));
+        if let Some(retry_after) = state.retry_after {
+            // whole seconds, rounded up so clients don't retry too early
+            let secs = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+            headers.insert(http::header::RETRY_AFTER, HeaderValue::from(secs));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_the_tightest_quota() {
+        let feedback = RateLimitFeedback::default();
+        let permit = |limit, remaining| {
+            Ok(Permit {
+                limit,
+                remaining,
+                delay: Duration::ZERO,
+                jitter: Duration::ZERO,
+            })
+        };
+
+        let mut headers = HeaderMap::new();
+        feedback.write_headers(&mut headers);
+        assert!(headers.is_empty());
+
+        assert!(RateLimitFeedback::record(Some(&feedback), permit(100, 50)).is_ok());
+        assert!(RateLimitFeedback::record(Some(&feedback), permit(10, 3)).is_ok());
+        assert!(RateLimitFeedback::record(Some(&feedback), permit(100, 49)).is_ok());
+        feedback.write_headers(&mut headers);
+        assert_eq!(headers["x-ratelimit-limit"], "10");
+        assert_eq!(headers["x-ratelimit-remaining"], "3");
+        assert!(headers.get(http::header::RETRY_AFTER).is_none());
+
+        let err = RateLimitFeedback::record(
+            Some(&feedback),
+            Err(Exceeded {
+                limit: 20,
+                retry_after: Duration::from_millis(1500),
+            }),
+        )
+        .unwrap_err();
+        assert_eq!(err.data().unwrap().get(), r#"{"limit":20,"retry_after_millis":1500}"#);
+
+        feedback.write_headers(&mut headers);
+        assert_eq!(headers["x-ratelimit-limit"], "20");
+        assert_eq!(headers["x-ratelimit-remaining"], "0");
+        assert_eq!(headers[http::header::RETRY_AFTER], "2");
+    }
+
+    #[test]
+    fn direct_limiter_reports_remaining() {
+        let limiter = direct_limiter(crate::extensions::rate_limit::build_quota(
+            NonZeroU32::new(2).unwrap(),
+            Duration::from_secs(1),
+        ));
+        let one = NonZeroU32::new(1).unwrap();
+
+        assert_eq!(check_n(&limiter, one).unwrap().remaining, 1);
+        assert_eq!(check_n(&limiter, one).unwrap().remaining, 0);
+        let exceeded = check_n(&limiter, one).unwrap_err();
+        assert_eq!(exceeded.limit, 2);
+        assert!(exceeded.retry_after > Duration::from_millis(400));
+        assert!(exceeded.retry_after <= Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn observes_delayed_and_rejected_calls() {
+        use crate::extensions::rate_limit::{KeyedRateLimiter, MemoryRateLimiter};
+        use substrate_prometheus_endpoint::Registry;
+
+        let registry = Registry::new();
+        let metrics = RpcMetrics::new(&registry);
+        let quota = crate::extensions::rate_limit::build_quota(NonZeroU32::new(1).unwrap(), Duration::from_millis(50));
+        let limiter: MemoryRateLimiter = RateLimiter::keyed(quota).with_middleware::<StateInformationMiddleware>();
+        let key = "1.1.1.1".to_string();
+        let one = NonZeroU32::MIN;
+        let jitter = Jitter::up_to(Duration::from_millis(10));
+
+        let outcome = Ok(KeyedRateLimiter::until_key_n_ready(&limiter, &key, one, jitter).await);
+        observe(&metrics, "ip", "foo", &outcome);
+        let permit = KeyedRateLimiter::until_key_n_ready(&limiter, &key, one, jitter).await;
+        assert!(permit.waited());
+        assert!(permit.delay >= permit.jitter);
+        observe(&metrics, "ip", "foo", &Ok(permit));
+        let outcome = KeyedRateLimiter::check_key_n(&limiter, &key, one).await;
+        assert!(outcome.is_err());
+        observe(&metrics, "ip", "foo", &outcome);
+
+        let value = |name: &str| {
+            let family = registry.gather().into_iter().find(|f| f.get_name() == name).unwrap();
+            let metric = &family.get_metric()[0];
+            let labels = metric
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect::<Vec<_>>();
+            assert_eq!(labels, [("method", "foo"), ("rule", "ip")]);
+            // counters and histograms, only one of them is set
+            metric.get_counter().get_value() + metric.get_histogram().get_sample_count() as f64
+        };
+        assert_eq!(value("rate_limit_delayed"), 1.0);
+        assert_eq!(value("rate_limit_rejected"), 1.0);
+        assert_eq!(value("rate_limit_delay_seconds"), 1.0);
+        assert_eq!(value("rate_limit_jitter_seconds"), 1.0);
+
+        // the key is forgotten once its quota is full again
+        assert_eq!(limiter.sweep(), Some(1));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(limiter.sweep(), Some(0));
+    }
+}
@@ -1,6 +1,8 @@
-use crate::extensions::rate_limit::MethodWeights;
+use crate::extensions::prometheus::RpcMetrics;
+use crate::extensions::rate_limit::{feedback, BanList, KeyedRateLimiter, MethodWeights, OnLimit, RateLimitFeedback};
+use crate::utils::errors;
 use futures::{future::BoxFuture, FutureExt};
-use governor::{DefaultKeyedRateLimiter, Jitter};
+use governor::Jitter;
 use jsonrpsee::{
     server::{middleware::rpc::RpcServiceT, types::Request},
     MethodResponse,
@@ -10,15 +12,18 @@ use std::{num::NonZeroU32, sync::Arc};
 #[derive(Clone)]
 pub struct IpRateLimitLayer {
     ip_addr: String,
-    limiter: Arc<DefaultKeyedRateLimiter<String>>,
+    limiter: Arc<dyn KeyedRateLimiter>,
     jitter: Jitter,
     method_weights: MethodWeights,
+    ban_list: Option<Arc<BanList>>,
+    on_limit: OnLimit,
+    metrics: RpcMetrics,
 }
 
 impl IpRateLimitLayer {
     pub fn new(
         ip_addr: String,
-        limiter: Arc<DefaultKeyedRateLimiter<String>>,
+        limiter: Arc<dyn KeyedRateLimiter>,
         jitter: Jitter,
         method_weights: MethodWeights,
     ) -> Self {
@@ -27,21 +32,44 @@ impl IpRateLimitLayer {
             limiter,
             jitter,
             method_weights,
+            ban_list: None,
+            on_limit: OnLimit::Queue,
+            metrics: RpcMetrics::noop(),
         }
     }
+
+    /// Reports ips exceeding their quota to the ban list and rejects banned ips.
+    pub fn with_ban_list(mut self, ban_list: Option<Arc<BanList>>) -> Self {
+        self.ban_list = ban_list;
+        self
+    }
+
+    pub fn with_on_limit(mut self, on_limit: OnLimit) -> Self {
+        self.on_limit = on_limit;
+        self
+    }
+
+    pub fn with_metrics(mut self, metrics: RpcMetrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
 }
 
 impl<S> tower::Layer<S> for IpRateLimitLayer {
     type Service = IpRateLimit<S>;
 
     fn layer(&self, service: S) -> Self::Service {
-        IpRateLimit::new(
+        let mut service = IpRateLimit::new(
             service,
             self.ip_addr.clone(),
             self.limiter.clone(),
             self.jitter,
             self.method_weights.clone(),
-        )
+        );
+        service.ban_list.clone_from(&self.ban_list);
+        service.on_limit = self.on_limit;
+        service.metrics = self.metrics.clone();
+        service
     }
 }
 
@@ -49,16 +77,19 @@ impl<S> tower::Layer<S> for IpRateLimitLayer {
 pub struct IpRateLimit<S> {
     service: S,
     ip_addr: String,
-    limiter: Arc<DefaultKeyedRateLimiter<String>>,
+    limiter: Arc<dyn KeyedRateLimiter>,
     jitter: Jitter,
     method_weights: MethodWeights,
+    ban_list: Option<Arc<BanList>>,
+    on_limit: OnLimit,
+    metrics: RpcMetrics,
 }
 
 impl<S> IpRateLimit<S> {
     pub fn new(
         service: S,
         ip_addr: String,
-        limiter: Arc<DefaultKeyedRateLimiter<String>>,
+        limiter: Arc<dyn KeyedRateLimiter>,
         jitter: Jitter,
         method_weights: MethodWeights,
     ) -> Self {
@@ -68,6 +99,9 @@ impl<S> IpRateLimit<S> {
             limiter,
             jitter,
             method_weights,
+            ban_list: None,
+            on_limit: OnLimit::Queue,
+            metrics: RpcMetrics::noop(),
         }
     }
 }
@@ -84,12 +118,27 @@ where
         let service = self.service.clone();
         let limiter = self.limiter.clone();
         let weight = self.method_weights.get(req.method_name());
+        let ban_list = self.ban_list.clone();
+        let on_limit = self.on_limit;
+        let metrics = self.metrics.clone();
         async move {
+            if let Some(ban_list) = &ban_list {
+                if ban_list.is_banned(&ip_addr) {
+                    return MethodResponse::error(req.id, errors::server_busy("IP is temporarily banned"));
+                }
+            }
             if let Some(n) = NonZeroU32::new(weight) {
-                limiter
-                    .until_key_n_ready_with_jitter(&ip_addr, n, jitter)
-                    .await
-                    .expect("check_n have been done during init");
+                let outcome = match on_limit {
+                    OnLimit::Queue => Ok(limiter.until_key_n_ready(&ip_addr, n, jitter).await),
+                    OnLimit::Reject => limiter.check_key_n(&ip_addr, n).await,
+                };
+                feedback::observe(&metrics, "ip", req.method_name(), &outcome);
+                if let (true, Some(ban_list)) = (outcome.is_err(), &ban_list) {
+                    ban_list.record_offense(&ip_addr);
+                }
+                if let Err(err) = RateLimitFeedback::record(req.extensions().get(), outcome) {
+                    return MethodResponse::error(req.id, err);
+                }
             }
             service.call(req).await
         }
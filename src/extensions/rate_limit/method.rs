@@ -0,0 +1,231 @@
+use crate::extensions::prometheus::RpcMetrics;
+use crate::extensions::rate_limit::{
+    feedback::{self, DirectRateLimiter},
+    BanList, KeyedRateLimiter, MethodWeights, OnLimit, RateLimitFeedback,
+};
+use futures::{future::BoxFuture, FutureExt};
+use governor::{Jitter, Quota};
+use jsonrpsee::{
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    MethodResponse,
+};
+use std::{collections::HashMap, num::NonZeroU32, sync::Arc};
+
+#[derive(Clone)]
+pub(crate) enum MethodLimiter {
+    /// Shared by all connections, keyed by ip.
+    Ip(Arc<dyn KeyedRateLimiter>),
+    /// A new limiter is created for every connection.
+    Connection(Quota),
+}
+
+#[derive(Clone)]
+enum ConnectionLimiter {
+    Ip(Arc<dyn KeyedRateLimiter>),
+    Connection(Arc<DirectRateLimiter>),
+}
+
+impl From<&MethodLimiter> for ConnectionLimiter {
+    fn from(limiter: &MethodLimiter) -> Self {
+        match limiter {
+            MethodLimiter::Ip(limiter) => ConnectionLimiter::Ip(limiter.clone()),
+            MethodLimiter::Connection(quota) => {
+                ConnectionLimiter::Connection(Arc::new(feedback::direct_limiter(*quota)))
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MethodRateLimitLayer {
+    ip_addr: String,
+    // method name => index of the limiter
+    methods: Arc<HashMap<String, usize>>,
+    limiters: Arc<Vec<(MethodLimiter, Jitter)>>,
+    method_weights: MethodWeights,
+    ban_list: Option<Arc<BanList>>,
+    on_limit: OnLimit,
+    metrics: RpcMetrics,
+}
+
+impl MethodRateLimitLayer {
+    pub(crate) fn new(
+        ip_addr: String,
+        methods: Arc<HashMap<String, usize>>,
+        limiters: Arc<Vec<(MethodLimiter, Jitter)>>,
+        method_weights: MethodWeights,
+        ban_list: Option<Arc<BanList>>,
+        on_limit: OnLimit,
+        metrics: RpcMetrics,
+    ) -> Self {
+        Self {
+            ip_addr,
+            methods,
+            limiters,
+            method_weights,
+            ban_list,
+            on_limit,
+            metrics,
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for MethodRateLimitLayer {
+    type Service = MethodRateLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        MethodRateLimit {
+            service,
+            ip_addr: self.ip_addr.clone(),
+            methods: self.methods.clone(),
+            limiters: Arc::new(
+                self.limiters
+                    .iter()
+                    .map(|(limiter, jitter)| (limiter.into(), *jitter))
+                    .collect(),
+            ),
+            method_weights: self.method_weights.clone(),
+            ban_list: self.ban_list.clone(),
+            on_limit: self.on_limit,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MethodRateLimit<S> {
+    service: S,
+    ip_addr: String,
+    methods: Arc<HashMap<String, usize>>,
+    limiters: Arc<Vec<(ConnectionLimiter, Jitter)>>,
+    method_weights: MethodWeights,
+    ban_list: Option<Arc<BanList>>,
+    on_limit: OnLimit,
+    metrics: RpcMetrics,
+}
+
+impl<'a, S> RpcServiceT<'a> for MethodRateLimit<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        let service = self.service.clone();
+
+        let Some(index) = self.methods.get(req.method_name()) else {
+            return async move { service.call(req).await }.boxed();
+        };
+
+        let (limiter, jitter) = self.limiters[*index].clone();
+        let ip_addr = self.ip_addr.clone();
+        let weight = self.method_weights.get(req.method_name());
+        let ban_list = self.ban_list.clone();
+        let on_limit = self.on_limit;
+        let metrics = self.metrics.clone();
+
+        async move {
+            if let Some(n) = NonZeroU32::new(weight) {
+                // only per ip quotas count towards bans
+                let (outcome, per_ip) = match (limiter, on_limit) {
+                    (ConnectionLimiter::Ip(limiter), OnLimit::Queue) => {
+                        (Ok(limiter.until_key_n_ready(&ip_addr, n, jitter).await), true)
+                    }
+                    (ConnectionLimiter::Ip(limiter), OnLimit::Reject) => (limiter.check_key_n(&ip_addr, n).await, true),
+                    (ConnectionLimiter::Connection(limiter), OnLimit::Queue) => {
+                        (Ok(feedback::until_n_ready(&limiter, n, jitter).await), false)
+                    }
+                    (ConnectionLimiter::Connection(limiter), OnLimit::Reject) => {
+                        (feedback::check_n(&limiter, n), false)
+                    }
+                };
+                feedback::observe(&metrics, "method", req.method_name(), &outcome);
+                if let (true, Some(ban_list)) = (per_ip && outcome.is_err(), &ban_list) {
+                    ban_list.record_offense(&ip_addr);
+                }
+                if let Err(err) = RateLimitFeedback::record(req.extensions().get(), outcome) {
+                    return MethodResponse::error(req.id, err);
+                }
+            }
+            service.call(req).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::rate_limit::{RateLimitBuilder, RateLimitConfig};
+    use jsonrpsee::types::Id;
+    use jsonrpsee::ResponsePayload;
+
+    #[derive(Clone)]
+    struct MockService;
+    impl RpcServiceT<'static> for MockService {
+        type Future = BoxFuture<'static, MethodResponse>;
+
+        fn call(&self, req: Request<'static>) -> Self::Future {
+            async move { MethodResponse::response(req.id, ResponsePayload::success("ok"), 1024) }.boxed()
+        }
+    }
+
+    async fn calls(service: &MethodRateLimit<MockService>, method: &'static str, count: u64) {
+        let calls = (1..=count)
+            .map(|id| service.call(Request::new(method.into(), None, Id::Number(id))))
+            .collect::<Vec<_>>();
+        let results = futures::future::join_all(calls).await;
+        assert!(results.iter().all(|r| r.is_success()));
+    }
+
+    #[tokio::test]
+    async fn method_rate_limit_works() {
+        let config: RateLimitConfig = serde_yaml::from_str(
+            r#"
+            methods:
+              - methods: [heavy, heavy2]
+                burst: 10
+                period_secs: 1
+                jitter_up_to_millis: 0
+              - methods: [per_conn]
+                per: connection
+                burst: 10
+                period_secs: 1
+                jitter_up_to_millis: 0
+            "#,
+        )
+        .unwrap();
+        let builder = RateLimitBuilder::new(config);
+
+        let weights = MethodWeights::default();
+        let shared = builder.shared_method_weights(&weights);
+        assert_eq!(shared.get("heavy"), 0);
+        assert_eq!(shared.get("cheap"), 1);
+
+        let layer = |ip: &str| builder.method_limit(ip.to_string(), weights.clone()).unwrap();
+        let conn1 = tower::Layer::layer(&layer("1.1.1.1"), MockService);
+        let conn2 = tower::Layer::layer(&layer("1.1.1.1"), MockService);
+        let other = tower::Layer::layer(&layer("2.2.2.2"), MockService);
+
+        let elapsed = |start: tokio::time::Instant| start.elapsed().as_millis();
+
+        // burst is available
+        let start = tokio::time::Instant::now();
+        calls(&conn1, "heavy", 6).await;
+        calls(&conn2, "heavy2", 4).await;
+        calls(&conn1, "per_conn", 10).await;
+        calls(&conn2, "per_conn", 10).await;
+        calls(&other, "heavy", 10).await;
+        assert!(elapsed(start) < 50);
+
+        // other methods are not limited
+        let start = tokio::time::Instant::now();
+        calls(&conn1, "cheap", 100).await;
+        assert!(elapsed(start) < 50);
+
+        // the group quota is shared by all connections from the same ip
+        let start = tokio::time::Instant::now();
+        calls(&conn2, "heavy", 2).await;
+        assert!(elapsed(start) >= 150);
+    }
+}
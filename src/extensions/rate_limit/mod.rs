@@ -1,17 +1,48 @@
-use governor::{DefaultKeyedRateLimiter, Jitter, Quota, RateLimiter};
+use governor::{Jitter, Quota};
+use ipnet::IpNet;
 use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::num::NonZeroU32;
 use std::{sync::Arc, time::Duration};
 
-use super::{Extension, ExtensionRegistry};
+use super::{
+    api::EthApi,
+    prometheus::{Prometheus, RpcMetrics},
+    Extension, ExtensionRegistry,
+};
+use crate::utils::parse_ip_nets;
+use concurrency::IpSemaphores;
+use cost::{BlockTags, CostLimiter};
+use method::MethodLimiter;
+use store::Store;
 
+mod ban;
+mod concurrency;
 mod connection;
+mod cost;
+mod feedback;
 mod ip;
+mod method;
+mod quota;
+mod store;
+mod subscription;
 mod weight;
 mod xff;
 
+pub use ban::{BanConfig, BanList};
+pub use concurrency::{ConcurrencyConfig, ConcurrencyLimit, ConcurrencyLimitLayer, OnLimit};
 pub use connection::{ConnectionRateLimit, ConnectionRateLimitLayer};
+pub use cost::{CostConfig, CostLimit, CostLimitLayer};
+pub use feedback::{Exceeded, Permit, RateLimitFeedback};
 pub use ip::{IpRateLimit, IpRateLimitLayer};
+pub use method::{MethodRateLimit, MethodRateLimitLayer};
+pub use quota::{quota_methods, QuotaConfig, QuotaKey, QuotaLayer, QuotaLimit, QuotaLimits, Quotas, QUOTA_METHOD};
+pub use store::{KeyedRateLimiter, MemoryRateLimiter, StoreConfig};
+pub use subscription::{
+    BufferConfig, NotificationSink, OnFull, SubscriptionClosed, SubscriptionLimitConfig, SubscriptionRateLimit,
+    SubscriptionRateLimitLayer,
+};
 pub use weight::MethodWeights;
 pub use xff::XFF;
 
@@ -21,6 +52,54 @@ pub struct RateLimitConfig {
     pub connection: Option<Rule>,
     #[serde(default)]
     pub use_xff: bool,
+    /// What to do with calls exceeding the `ip`, `connection` or `methods` quotas.
+    /// Rejected calls get an error with the time to wait before retrying.
+    #[serde(default)]
+    pub on_limit: OnLimit,
+    /// Proxies allowed to set forwarding headers. If empty, the left-most
+    /// `X-Forwarded-For` entry is used as is.
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    /// Dedicated quotas for methods or groups of methods. Calls to these methods
+    /// don't draw from the `ip` and `connection` quotas.
+    #[serde(default)]
+    pub methods: Vec<MethodRule>,
+    /// Shares the `ip` and per ip method quotas between replicas.
+    #[serde(default)]
+    pub store: StoreConfig,
+    /// Limits on the number of requests in flight.
+    #[serde(default)]
+    pub concurrency: Option<ConcurrencyConfig>,
+    /// Per ip budget charged with the measured cost of each request.
+    #[serde(default)]
+    pub cost: Option<CostConfig>,
+    /// Temporarily bans ips whose calls are repeatedly rejected by the `ip`, per ip method
+    /// or subscription quotas. With `use_xff`, requires `trusted_proxies`.
+    #[serde(default)]
+    pub ban: Option<BanConfig>,
+    /// Daily and monthly budgets per API key or ip.
+    #[serde(default)]
+    pub quota: Option<QuotaConfig>,
+    /// Limits on opening subscriptions and on notifications pending delivery.
+    #[serde(default)]
+    pub subscriptions: Option<SubscriptionLimitConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct MethodRule {
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub per: LimitScope,
+    #[serde(flatten)]
+    pub rule: Rule,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitScope {
+    #[default]
+    Ip,
+    Connection,
 }
 
 #[derive(Deserialize, Debug, Clone, Default)]
@@ -45,18 +124,42 @@ fn default_jitter_up_to_millis() -> u64 {
     100
 }
 
+// how often idle keys are dropped from the in-memory limiters and the tracked keys reported
+const KEYS_SWEEP_INTERVAL: Duration = Duration::from_secs(15);
+
 pub struct RateLimitBuilder {
     config: RateLimitConfig,
     ip_jitter: Option<Jitter>,
-    ip_limiter: Option<Arc<DefaultKeyedRateLimiter<String>>>,
+    ip_limiter: Option<Arc<dyn KeyedRateLimiter>>,
+    trusted_proxies: Vec<IpNet>,
+    // method name => index into method_limiters
+    methods: Arc<HashMap<String, usize>>,
+    method_limiters: Arc<Vec<(MethodLimiter, Jitter)>>,
+    ip_semaphores: Option<Arc<IpSemaphores>>,
+    cost: Option<(Arc<CostLimiter>, Arc<CostConfig>)>,
+    block_tags: BlockTags,
+    ban_list: Option<Arc<BanList>>,
+    quotas: Option<Arc<Quotas>>,
+    subscription_limiter: Option<(Arc<dyn KeyedRateLimiter>, Jitter)>,
+    // name => limiter, for the number of keys tracked
+    keyed_limiters: Vec<(String, Arc<dyn KeyedRateLimiter>)>,
+    metrics: RpcMetrics,
 }
 
 #[async_trait::async_trait]
 impl Extension for RateLimitBuilder {
     type Config = RateLimitConfig;
 
-    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
-        Ok(Self::new(config.clone()))
+    async fn from_config(config: &Self::Config, registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        let metrics = match registry.get::<Prometheus>().await {
+            Some(prometheus) => prometheus.rpc_metrics(),
+            None => RpcMetrics::noop(),
+        };
+        let eth_api = match config.cost.as_ref().and_then(|c| c.blocks_per_unit) {
+            Some(_) => registry.get::<EthApi>().await,
+            None => None,
+        };
+        Ok(Self::new(config.clone()).with_metrics(metrics).with_eth_api(eth_api))
     }
 }
 
@@ -72,31 +175,203 @@ impl RateLimitBuilder {
             assert!(rule.period_secs > 0, "period_secs must be greater than 0");
         }
 
+        let store = Store::new(&config.store).expect("Invalid rate limit store");
+
+        let mut keyed_limiters = Vec::new();
+        let mut methods = HashMap::new();
+        let mut method_limiters = Vec::new();
+        for (index, method_rule) in config.methods.iter().enumerate() {
+            let rule = &method_rule.rule;
+            assert!(rule.burst > 0, "burst must be greater than 0");
+            assert!(rule.period_secs > 0, "period_secs must be greater than 0");
+            for method in &method_rule.methods {
+                let existing = methods.insert(method.clone(), index);
+                assert!(
+                    existing.is_none(),
+                    "method `{method}` has more than one rate limit rule"
+                );
+            }
+
+            let burst = NonZeroU32::new(rule.burst).unwrap();
+            let period = Duration::from_secs(rule.period_secs);
+            let limiter = match method_rule.per {
+                LimitScope::Ip => {
+                    let name = format!("method:{}", method_rule.methods.join(","));
+                    let limiter = store.keyed_limiter(&name, burst, period);
+                    keyed_limiters.push((name, limiter.clone()));
+                    MethodLimiter::Ip(limiter)
+                }
+                LimitScope::Connection => MethodLimiter::Connection(build_quota(burst, period)),
+            };
+            method_limiters.push((limiter, Jitter::up_to(Duration::from_millis(rule.jitter_up_to_millis))));
+        }
+        let methods = Arc::new(methods);
+        let method_limiters = Arc::new(method_limiters);
+
+        if let Some(ref concurrency) = config.concurrency {
+            assert!(concurrency.ip != Some(0), "concurrency ip must be greater than 0");
+            assert!(
+                concurrency.connection != Some(0),
+                "concurrency connection must be greater than 0"
+            );
+        }
+        let ip_semaphores = config
+            .concurrency
+            .as_ref()
+            .and_then(|c| c.ip)
+            .map(|limit| Arc::new(IpSemaphores::new(limit)));
+
+        if let Some(ref cost) = config.cost {
+            assert!(cost.budget > 0, "cost budget must be greater than 0");
+            assert!(cost.period_secs > 0, "cost period_secs must be greater than 0");
+        }
+        let cost = config.cost.as_ref().map(|cost| {
+            (
+                Arc::new(CostLimiter::new(cost.budget, Duration::from_secs(cost.period_secs))),
+                Arc::new(cost.clone()),
+            )
+        });
+
+        if let Some(ref ban) = config.ban {
+            assert!(ban.threshold > 0, "ban threshold must be greater than 0");
+            assert!(ban.window_secs > 0, "ban window_secs must be greater than 0");
+            // anyone could get any ip banned by forging X-Forwarded-For
+            assert!(
+                !config.use_xff || !config.trusted_proxies.is_empty(),
+                "ban with use_xff requires trusted_proxies"
+            );
+            if config.on_limit == OnLimit::Queue {
+                tracing::warn!("ban has no effect with on_limit queue, only rejected calls are offenses");
+            }
+        }
+        let ban_list = config.ban.as_ref().map(|ban| Arc::new(BanList::new(ban)));
+
+        if let Some(ref quota) = config.quota {
+            for limits in std::iter::once(&quota.limits).chain(quota.keys.values()) {
+                assert!(limits.daily != Some(0), "quota daily must be greater than 0");
+                assert!(limits.monthly != Some(0), "quota monthly must be greater than 0");
+            }
+        }
+        let quotas = config.quota.clone().map(|quota| Arc::new(Quotas::new(quota)));
+
+        let subscriptions = config.subscriptions.as_ref();
+        if let Some(rule) = subscriptions.and_then(|s| s.ip.as_ref()) {
+            assert!(rule.burst > 0, "burst must be greater than 0");
+            assert!(rule.period_secs > 0, "period_secs must be greater than 0");
+        }
+        if let Some(buffer) = subscriptions.and_then(|s| s.buffer.as_ref()) {
+            assert!(buffer.size > 0, "subscription buffer size must be greater than 0");
+        }
+        let subscription_limiter = subscriptions.and_then(|s| s.ip.as_ref()).map(|rule| {
+            let burst = NonZeroU32::new(rule.burst).unwrap();
+            let limiter = store.keyed_limiter("subscriptions", burst, Duration::from_secs(rule.period_secs));
+            keyed_limiters.push(("subscriptions".to_string(), limiter.clone()));
+            (limiter, Jitter::up_to(Duration::from_millis(rule.jitter_up_to_millis)))
+        });
+
+        let trusted_proxies =
+            parse_ip_nets(config.trusted_proxies.iter().map(String::as_str)).expect("Invalid trusted_proxies");
+        if config.use_xff && trusted_proxies.is_empty() {
+            tracing::warn!("use_xff is enabled without trusted_proxies, X-Forwarded-For can be forged by clients");
+        }
+
         if let Some(ref rule) = config.ip {
             let burst = NonZeroU32::new(rule.burst).unwrap();
-            let quota = build_quota(burst, Duration::from_secs(rule.period_secs));
-            let ip_limiter = Some(Arc::new(RateLimiter::keyed(quota)));
+            let ip_limiter = store.keyed_limiter("ip", burst, Duration::from_secs(rule.period_secs));
+            keyed_limiters.push(("ip".to_string(), ip_limiter.clone()));
+            let ip_limiter = Some(ip_limiter);
             let ip_jitter = Some(Jitter::up_to(Duration::from_millis(rule.jitter_up_to_millis)));
             Self {
                 config,
                 ip_jitter,
                 ip_limiter,
+                trusted_proxies,
+                methods,
+                method_limiters,
+                ip_semaphores,
+                cost,
+                block_tags: Arc::new(|_| None),
+                ban_list,
+                quotas,
+                subscription_limiter,
+                keyed_limiters,
+                metrics: RpcMetrics::noop(),
             }
         } else {
             Self {
                 config,
                 ip_jitter: None,
                 ip_limiter: None,
+                trusted_proxies,
+                methods,
+                method_limiters,
+                ip_semaphores,
+                cost,
+                block_tags: Arc::new(|_| None),
+                ban_list,
+                quotas,
+                subscription_limiter,
+                keyed_limiters,
+                metrics: RpcMetrics::noop(),
             }
         }
     }
 
+    /// Resolves the block tags of `eth_getLogs` ranges charged by the cost limit.
+    pub fn with_eth_api(mut self, eth_api: Option<Arc<EthApi>>) -> Self {
+        if let Some(api) = eth_api {
+            self.block_tags = Arc::new(move |tag| match tag {
+                "latest" | "pending" => api.current_head().map(|(_, number)| number),
+                "safe" | "finalized" => api.current_finalized_head().map(|(_, number)| number),
+                _ => None,
+            });
+        }
+        self
+    }
+
+    /// Reports calls delayed or rejected, and the number of keys tracked by the limiters.
+    pub fn with_metrics(mut self, metrics: RpcMetrics) -> Self {
+        if !metrics.is_noop() && !self.keyed_limiters.is_empty() {
+            let limiters = self
+                .keyed_limiters
+                .iter()
+                .map(|(name, limiter)| (name.clone(), Arc::downgrade(limiter)))
+                .collect::<Vec<_>>();
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(KEYS_SWEEP_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    let mut alive = false;
+                    for (name, limiter) in &limiters {
+                        let Some(limiter) = limiter.upgrade() else {
+                            continue;
+                        };
+                        alive = true;
+                        if let Some(keys) = limiter.sweep() {
+                            metrics.rate_limit_keys(name, keys);
+                        }
+                    }
+                    if !alive {
+                        break;
+                    }
+                }
+            });
+        }
+        self.metrics = metrics;
+        self
+    }
+
     pub fn connection_limit(&self, method_weights: MethodWeights) -> Option<ConnectionRateLimitLayer> {
         if let Some(ref rule) = self.config.connection {
             let burst = NonZeroU32::new(rule.burst).unwrap();
             let period = Duration::from_secs(rule.period_secs);
             let jitter = Jitter::up_to(Duration::from_millis(rule.jitter_up_to_millis));
-            Some(ConnectionRateLimitLayer::new(burst, period, jitter, method_weights))
+            Some(
+                ConnectionRateLimitLayer::new(burst, period, jitter, method_weights)
+                    .with_on_limit(self.config.on_limit)
+                    .with_metrics(self.metrics.clone()),
+            )
         } else {
             None
         }
@@ -109,13 +384,120 @@ impl RateLimitBuilder {
                 self.ip_jitter.unwrap_or_default(),
                 method_weights,
             )
+            .with_ban_list(self.ban_list.clone())
+            .with_on_limit(self.config.on_limit)
+            .with_metrics(self.metrics.clone())
+        })
+    }
+
+    pub fn concurrency_limit(&self, remote_ip: String) -> Option<ConcurrencyLimitLayer> {
+        self.config
+            .concurrency
+            .as_ref()
+            .map(|config| ConcurrencyLimitLayer::new(remote_ip, self.ip_semaphores.clone(), config))
+    }
+
+    pub fn cost_limit(&self, remote_ip: String) -> Option<CostLimitLayer> {
+        self.cost.as_ref().map(|(limiter, config)| {
+            CostLimitLayer::new(
+                remote_ip,
+                limiter.clone(),
+                config.clone(),
+                self.block_tags.clone(),
+                self.config.on_limit,
+                self.metrics.clone(),
+            )
+        })
+    }
+
+    pub fn method_limit(&self, remote_ip: String, method_weights: MethodWeights) -> Option<MethodRateLimitLayer> {
+        if self.methods.is_empty() {
+            return None;
+        }
+        Some(MethodRateLimitLayer::new(
+            remote_ip,
+            self.methods.clone(),
+            self.method_limiters.clone(),
+            method_weights,
+            self.ban_list.clone(),
+            self.config.on_limit,
+            self.metrics.clone(),
+        ))
+    }
+
+    // weights for the ip and connection limits, excluding methods with a dedicated rule
+    pub fn shared_method_weights(&self, method_weights: &MethodWeights) -> MethodWeights {
+        if self.methods.is_empty() {
+            return method_weights.clone();
+        }
+        method_weights.without(self.methods.keys().map(String::as_str))
+    }
+
+    // the caller the daily and monthly budgets are accounted to
+    pub fn quota_key<T>(&self, req: &http::Request<T>, remote_ip: &str) -> Option<QuotaKey> {
+        self.quotas.as_ref().map(|quotas| quotas.key(req, remote_ip))
+    }
+
+    pub fn quota_limit(&self, key: Option<QuotaKey>, method_weights: MethodWeights) -> Option<QuotaLayer> {
+        let quotas = self.quotas.as_ref()?;
+        Some(QuotaLayer::new(key?, quotas.clone(), method_weights))
+    }
+
+    // limits how often the ip opens subscriptions through `subscribe_methods`
+    pub fn subscription_limit(
+        &self,
+        remote_ip: String,
+        subscribe_methods: Arc<HashSet<String>>,
+    ) -> Option<SubscriptionRateLimitLayer> {
+        self.subscription_limiter.as_ref().map(|(limiter, jitter)| {
+            SubscriptionRateLimitLayer::new(
+                remote_ip,
+                limiter.clone(),
+                *jitter,
+                subscribe_methods,
+                self.config.on_limit,
+                self.ban_list.clone(),
+                self.metrics.clone(),
+            )
         })
     }
 
+    // what to do with notifications the subscriber doesn't consume fast enough
+    pub fn subscription_buffer(&self) -> Option<BufferConfig> {
+        self.config.subscriptions.as_ref()?.buffer.clone()
+    }
+
+    pub fn quotas(&self) -> Option<Arc<Quotas>> {
+        self.quotas.clone()
+    }
+
+    pub fn ban_list(&self) -> Option<Arc<BanList>> {
+        self.ban_list.clone()
+    }
+
     // whether to use the X-Forwarded-For header to get the remote ip
     pub fn use_xff(&self) -> bool {
         self.config.use_xff
     }
+
+    // the ip of the client sending the request, taking forwarding headers into account if use_xff is enabled
+    pub fn client_ip<T>(&self, req: &http::Request<T>, remote_ip: IpAddr) -> String {
+        if !self.config.use_xff {
+            return remote_ip.to_string();
+        }
+        if self.trusted_proxies.is_empty() {
+            return req.xxf_ip().unwrap_or(remote_ip.to_string());
+        }
+        req.client_ip(remote_ip, &self.trusted_proxies).to_string()
+    }
+
+    // like `client_ip`, but forwarding headers are only used when sent by trusted_proxies
+    pub fn trusted_client_ip<T>(&self, req: &http::Request<T>, remote_ip: IpAddr) -> IpAddr {
+        if !self.config.use_xff {
+            return remote_ip;
+        }
+        req.client_ip(remote_ip, &self.trusted_proxies)
+    }
 }
 
 pub fn build_quota(burst: NonZeroU32, period: Duration) -> Quota {
@@ -0,0 +1,498 @@
+//! Daily and monthly request budgets.
+//!
+//! Usage is accounted per API key, taken from `key_header`, or per ip for
+//! callers without a known key. Windows follow the UTC calendar and usage is
+//! saved to `state_file` so it survives restarts.
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, NaiveDate, Utc};
+use futures::{future::BoxFuture, FutureExt};
+use jsonrpsee::{
+    core::JsonValue,
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    types::ErrorObjectOwned,
+    MethodResponse, RpcModule,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::task::JoinHandle;
+
+use super::{Exceeded, MethodWeights, Permit, RateLimitFeedback};
+use crate::utils::errors;
+
+/// Checking the remaining budget doesn't use it.
+pub const QUOTA_METHOD: &str = "subway_quota";
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QuotaLimits {
+    #[serde(default)]
+    pub daily: Option<u64>,
+    #[serde(default)]
+    pub monthly: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct QuotaConfig {
+    /// Limits for callers without a key listed in `keys`.
+    #[serde(flatten)]
+    pub limits: QuotaLimits,
+    #[serde(default = "default_key_header")]
+    pub key_header: String,
+    /// Limits per API key.
+    #[serde(default)]
+    pub keys: HashMap<String, QuotaLimits>,
+    #[serde(default)]
+    pub state_file: Option<PathBuf>,
+    #[serde(default = "default_save_interval_secs")]
+    pub save_interval_secs: u64,
+}
+
+fn default_key_header() -> String {
+    "x-api-key".to_string()
+}
+
+fn default_save_interval_secs() -> u64 {
+    10
+}
+
+/// Who a request is accounted to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum QuotaKey {
+    ApiKey(String),
+    Ip(String),
+}
+
+impl QuotaKey {
+    fn id(&self) -> String {
+        match self {
+            QuotaKey::ApiKey(key) => format!("key:{key}"),
+            QuotaKey::Ip(ip) => format!("ip:{ip}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Window {
+    Day,
+    Month,
+}
+
+impl Window {
+    fn name(&self) -> &'static str {
+        match self {
+            Window::Day => "daily",
+            Window::Month => "monthly",
+        }
+    }
+
+    fn start(&self, now: DateTime<Utc>) -> NaiveDate {
+        match self {
+            Window::Day => now.date_naive(),
+            Window::Month => now.date_naive().with_day(1).expect("every month has a first day"),
+        }
+    }
+
+    fn reset_in(&self, now: DateTime<Utc>) -> Duration {
+        let start = self.start(now);
+        let next = match self {
+            Window::Day => start + ChronoDuration::days(1),
+            Window::Month => start
+                .checked_add_months(chrono::Months::new(1))
+                .expect("date out of range"),
+        };
+        let next = next.and_hms_opt(0, 0, 0).expect("midnight is valid").and_utc();
+        (next - now).to_std().unwrap_or_default()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct Counter {
+    // first day of the window, in days from the common era
+    start: Option<i32>,
+    used: u64,
+}
+
+impl Counter {
+    fn used(&self, window: Window, now: DateTime<Utc>) -> u64 {
+        if self.start == Some(window.start(now).num_days_from_ce()) {
+            self.used
+        } else {
+            0
+        }
+    }
+
+    fn add(&mut self, window: Window, now: DateTime<Utc>, n: u64) {
+        let start = window.start(now).num_days_from_ce();
+        if self.start != Some(start) {
+            self.start = Some(start);
+            self.used = 0;
+        }
+        self.used += n;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct Usage {
+    daily: Counter,
+    monthly: Counter,
+}
+
+impl Usage {
+    fn counter(&self, window: Window) -> &Counter {
+        match window {
+            Window::Day => &self.daily,
+            Window::Month => &self.monthly,
+        }
+    }
+
+    fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        self.daily.used(Window::Day, now) == 0 && self.monthly.used(Window::Month, now) == 0
+    }
+}
+
+#[derive(Default)]
+struct State {
+    usage: HashMap<String, Usage>,
+    dirty: bool,
+}
+
+pub struct Quotas {
+    config: QuotaConfig,
+    state: Arc<Mutex<State>>,
+    save_task: Option<JoinHandle<()>>,
+}
+
+impl Quotas {
+    pub fn new(config: QuotaConfig) -> Self {
+        let mut usage = HashMap::new();
+        if let Some(path) = &config.state_file {
+            match load(path) {
+                Ok(loaded) => usage = loaded,
+                Err(e) => tracing::warn!("Unable to load quota usage from {}, starting over: {e}", path.display()),
+            }
+        }
+        let state = Arc::new(Mutex::new(State { usage, dirty: false }));
+
+        let save_task = config.state_file.clone().map(|path| {
+            let state = state.clone();
+            let interval = Duration::from_secs(config.save_interval_secs.max(1));
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(interval).await;
+                    save(&state, &path);
+                }
+            })
+        });
+
+        Self {
+            config,
+            state,
+            save_task,
+        }
+    }
+
+    /// The key of the caller, an API key if one listed in `keys` is given.
+    pub fn key<T>(&self, req: &http::Request<T>, ip: &str) -> QuotaKey {
+        req.headers()
+            .get(&self.config.key_header)
+            .and_then(|value| value.to_str().ok())
+            .filter(|key| self.config.keys.contains_key(*key))
+            .map(|key| QuotaKey::ApiKey(key.to_string()))
+            .unwrap_or_else(|| QuotaKey::Ip(ip.to_string()))
+    }
+
+    fn limits(&self, key: &QuotaKey) -> QuotaLimits {
+        match key {
+            QuotaKey::ApiKey(api_key) => self.config.keys.get(api_key).copied().unwrap_or(self.config.limits),
+            QuotaKey::Ip(_) => self.config.limits,
+        }
+    }
+
+    fn windows(limits: QuotaLimits) -> impl Iterator<Item = (Window, u64)> {
+        [(Window::Day, limits.daily), (Window::Month, limits.monthly)]
+            .into_iter()
+            .filter_map(|(window, limit)| Some((window, limit?)))
+    }
+
+    /// Uses `n` requests of the budget, unless it would exceed any window.
+    pub fn take(&self, key: &QuotaKey, n: u64) -> Result<Permit, Exceeded> {
+        self.take_at(key, n, Utc::now())
+    }
+
+    fn take_at(&self, key: &QuotaKey, n: u64, now: DateTime<Utc>) -> Result<Permit, Exceeded> {
+        let limits = self.limits(key);
+        let mut state = self.state.lock().expect("quota lock poisoned");
+        let usage = state.usage.get(&key.id()).cloned().unwrap_or_default();
+
+        // the window closest to be exhausted
+        let mut tightest: Option<Permit> = None;
+        for (window, limit) in Self::windows(limits) {
+            let used = usage.counter(window).used(window, now);
+            if used + n > limit {
+                return Err(Exceeded {
+                    limit: clamp(limit),
+                    retry_after: window.reset_in(now),
+                });
+            }
+            let remaining = clamp(limit - used - n);
+            if tightest.map_or(true, |t| remaining < t.remaining) {
+                tightest = Some(Permit {
+                    limit: clamp(limit),
+                    remaining,
+                    delay: Duration::ZERO,
+                    jitter: Duration::ZERO,
+                });
+            }
+        }
+
+        let Some(permit) = tightest else {
+            return Ok(Permit {
+                limit: u32::MAX,
+                remaining: u32::MAX,
+                delay: Duration::ZERO,
+                jitter: Duration::ZERO,
+            });
+        };
+
+        let usage = state.usage.entry(key.id()).or_default();
+        usage.daily.add(Window::Day, now, n);
+        usage.monthly.add(Window::Month, now, n);
+        state.dirty = true;
+
+        Ok(permit)
+    }
+
+    /// The budget of `key`, as returned by `subway_quota`.
+    pub fn status(&self, key: &QuotaKey) -> JsonValue {
+        self.status_at(key, Utc::now())
+    }
+
+    fn status_at(&self, key: &QuotaKey, now: DateTime<Utc>) -> JsonValue {
+        let limits = self.limits(key);
+        let state = self.state.lock().expect("quota lock poisoned");
+        let usage = state.usage.get(&key.id()).cloned().unwrap_or_default();
+
+        let mut status = serde_json::Map::new();
+        for window in [Window::Day, Window::Month] {
+            let limit = match window {
+                Window::Day => limits.daily,
+                Window::Month => limits.monthly,
+            };
+            let value = limit.map(|limit| {
+                let used = usage.counter(window).used(window, now);
+                json!({
+                    "limit": limit,
+                    "used": used,
+                    "remaining": limit.saturating_sub(used),
+                    "resets_in_secs": window.reset_in(now).as_secs(),
+                })
+            });
+            status.insert(window.name().to_string(), value.unwrap_or(JsonValue::Null));
+        }
+        JsonValue::Object(status)
+    }
+}
+
+impl Drop for Quotas {
+    fn drop(&mut self) {
+        if let Some(task) = self.save_task.take() {
+            task.abort();
+        }
+        if let Some(path) = &self.config.state_file {
+            save(&self.state, path);
+        }
+    }
+}
+
+fn clamp(n: u64) -> u32 {
+    u32::try_from(n).unwrap_or(u32::MAX)
+}
+
+fn load(path: &Path) -> anyhow::Result<HashMap<String, Usage>> {
+    match std::fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes the usage if it changed, dropping counters of past windows.
+fn save(state: &Mutex<State>, path: &Path) {
+    let now = Utc::now();
+    let json = {
+        let mut state = state.lock().expect("quota lock poisoned");
+        if !state.dirty {
+            return;
+        }
+        state.dirty = false;
+        state.usage.retain(|_, usage| !usage.is_stale(now));
+        serde_json::to_vec(&state.usage).expect("usage is serializable")
+    };
+
+    // write then rename so a crash can't leave a truncated file
+    let tmp = path.with_extension("tmp");
+    if let Err(e) = std::fs::write(&tmp, json).and_then(|_| std::fs::rename(&tmp, path)) {
+        tracing::warn!("Unable to save quota usage to {}: {e}", path.display());
+        state.lock().expect("quota lock poisoned").dirty = true;
+    }
+}
+
+/// `subway_quota`, returning the budget of the caller.
+pub fn quota_methods(quotas: Arc<Quotas>) -> anyhow::Result<RpcModule<()>> {
+    let mut module = RpcModule::new(());
+    module.register_method(QUOTA_METHOD, move |_, _, extensions| {
+        match extensions.get::<QuotaKey>() {
+            Some(key) => Ok::<JsonValue, ErrorObjectOwned>(quotas.status(key)),
+            None => Err(errors::failed("No quota applies to this caller")),
+        }
+    })?;
+    Ok(module)
+}
+
+#[derive(Clone)]
+pub struct QuotaLayer {
+    key: QuotaKey,
+    quotas: Arc<Quotas>,
+    method_weights: MethodWeights,
+}
+
+impl QuotaLayer {
+    pub(crate) fn new(key: QuotaKey, quotas: Arc<Quotas>, method_weights: MethodWeights) -> Self {
+        Self {
+            key,
+            quotas,
+            method_weights,
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for QuotaLayer {
+    type Service = QuotaLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        QuotaLimit {
+            service,
+            key: self.key.clone(),
+            quotas: self.quotas.clone(),
+            method_weights: self.method_weights.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct QuotaLimit<S> {
+    service: S,
+    key: QuotaKey,
+    quotas: Arc<Quotas>,
+    method_weights: MethodWeights,
+}
+
+impl<'a, S> RpcServiceT<'a> for QuotaLimit<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        let service = self.service.clone();
+        let weight = self.method_weights.get(req.method_name());
+
+        if weight > 0 && req.method_name() != QUOTA_METHOD {
+            let outcome = self.quotas.take(&self.key, weight as u64);
+            if let Err(err) = RateLimitFeedback::record(req.extensions().get(), outcome) {
+                return async move { MethodResponse::error(req.id, err) }.boxed();
+            }
+        }
+
+        async move { service.call(req).await }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn config(state_file: Option<PathBuf>) -> QuotaConfig {
+        serde_yaml::from_str::<QuotaConfig>(
+            r#"
+            daily: 3
+            monthly: 5
+            keys:
+              gold:
+                daily: 100
+            "#,
+        )
+        .map(|config| QuotaConfig { state_file, ..config })
+        .unwrap()
+    }
+
+    fn at(day: u32, hour: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 1, day, hour, 0, 0).unwrap()
+    }
+
+    #[test]
+    fn daily_and_monthly_windows() {
+        let quotas = Quotas::new(config(None));
+        let ip = QuotaKey::Ip("1.1.1.1".to_string());
+        let gold = QuotaKey::ApiKey("gold".to_string());
+
+        assert_eq!(quotas.take_at(&ip, 2, at(1, 10)).unwrap().remaining, 1);
+        assert_eq!(quotas.take_at(&ip, 1, at(1, 11)).unwrap().remaining, 0);
+        let exceeded = quotas.take_at(&ip, 1, at(1, 12)).unwrap_err();
+        assert_eq!(exceeded.limit, 3);
+        assert_eq!(exceeded.retry_after, Duration::from_secs(12 * 3600));
+
+        // a new day, but only 2 left in the month
+        assert_eq!(quotas.take_at(&ip, 2, at(2, 0)).unwrap().remaining, 0);
+        let exceeded = quotas.take_at(&ip, 1, at(3, 0)).unwrap_err();
+        assert_eq!(exceeded.limit, 5);
+        assert_eq!(exceeded.retry_after, Duration::from_secs(29 * 24 * 3600));
+
+        // api keys have their own limits, unlimited monthly
+        assert_eq!(quotas.take_at(&gold, 10, at(3, 0)).unwrap().remaining, 90);
+
+        let status = quotas.status_at(&ip, at(3, 0));
+        assert_eq!(status["daily"]["used"], 0);
+        assert_eq!(status["monthly"]["used"], 5);
+        assert_eq!(status["monthly"]["remaining"], 0);
+        assert_eq!(quotas.status_at(&gold, at(3, 0))["monthly"], JsonValue::Null);
+    }
+
+    #[tokio::test]
+    async fn usage_survives_restarts() {
+        let path = std::env::temp_dir().join(format!("subway-quota-{}.json", std::process::id()));
+        let ip = QuotaKey::Ip("1.1.1.1".to_string());
+
+        let quotas = Quotas::new(config(Some(path.clone())));
+        quotas.take(&ip, 2).unwrap();
+        drop(quotas);
+
+        let quotas = Quotas::new(config(Some(path.clone())));
+        assert_eq!(quotas.take(&ip, 1).unwrap().remaining, 0);
+        assert!(quotas.take(&ip, 1).is_err());
+        drop(quotas);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn uses_known_api_keys() {
+        let quotas = Quotas::new(config(None));
+        let request = |key: &str| http::Request::builder().header("x-api-key", key).body(()).unwrap();
+
+        assert_eq!(
+            quotas.key(&request("gold"), "1.1.1.1"),
+            QuotaKey::ApiKey("gold".to_string())
+        );
+        assert_eq!(
+            quotas.key(&request("made-up"), "1.1.1.1"),
+            QuotaKey::Ip("1.1.1.1".to_string())
+        );
+    }
+}
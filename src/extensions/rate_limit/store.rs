@@ -0,0 +1,261 @@
+use async_trait::async_trait;
+use governor::{
+    clock::{Clock, DefaultClock},
+    middleware::StateInformationMiddleware,
+    state::keyed::DefaultKeyedStateStore,
+    Jitter, RateLimiter,
+};
+use serde::Deserialize;
+use std::{
+    num::NonZeroU32,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{feedback::Wait, Exceeded, Permit};
+use crate::utils::redis::RedisClient;
+
+/// Where the state of the keyed (per ip) limiters is kept.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum StoreConfig {
+    /// Each replica limits on its own.
+    #[default]
+    Memory,
+    /// Quotas are shared by all replicas using the same Redis-protocol server.
+    Redis {
+        url: String,
+        #[serde(default = "default_key_prefix")]
+        key_prefix: String,
+        #[serde(default = "default_timeout_millis")]
+        timeout_millis: u64,
+    },
+}
+
+fn default_key_prefix() -> String {
+    "subway:rate_limit".to_string()
+}
+
+fn default_timeout_millis() -> u64 {
+    500
+}
+
+/// A rate limiter with a quota per key.
+#[async_trait]
+pub trait KeyedRateLimiter: Send + Sync {
+    /// Takes `n` cells for `key` if they are available.
+    // `&String` to avoid an allocation for the governor keyed limiter on every call
+    #[allow(clippy::ptr_arg)]
+    async fn check_key_n(&self, key: &String, n: NonZeroU32) -> Result<Permit, Exceeded>;
+
+    /// Waits until `n` cells are available for `key`.
+    #[allow(clippy::ptr_arg)]
+    async fn until_key_n_ready(&self, key: &String, n: NonZeroU32, jitter: Jitter) -> Permit {
+        let mut wait = Wait::default();
+        loop {
+            match self.check_key_n(key, n).await {
+                Ok(permit) => return wait.permit(permit),
+                Err(exceeded) => wait.sleep(jitter, exceeded).await,
+            }
+        }
+    }
+
+    /// Forgets the keys whose quota is full again, returning the number of keys left.
+    /// `None` if the keys are not kept by this replica.
+    fn sweep(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// The in-memory keyed limiter, reporting the remaining quota.
+pub type MemoryRateLimiter =
+    RateLimiter<String, DefaultKeyedStateStore<String>, DefaultClock, StateInformationMiddleware>;
+
+#[async_trait]
+impl KeyedRateLimiter for MemoryRateLimiter {
+    async fn check_key_n(&self, key: &String, n: NonZeroU32) -> Result<Permit, Exceeded> {
+        match RateLimiter::check_key_n(self, key, n).expect("check_n have been done during init") {
+            Ok(snapshot) => Ok(Permit {
+                limit: snapshot.quota().burst_size().get(),
+                remaining: snapshot.remaining_burst_capacity(),
+                delay: Duration::ZERO,
+                jitter: Duration::ZERO,
+            }),
+            Err(not_until) => Err(Exceeded {
+                limit: not_until.quota().burst_size().get(),
+                retry_after: not_until.wait_time_from(DefaultClock::default().now()),
+            }),
+        }
+    }
+
+    fn sweep(&self) -> Option<usize> {
+        self.retain_recent();
+        Some(self.len())
+    }
+}
+
+pub(crate) enum Store {
+    Memory,
+    Redis {
+        client: Arc<RedisClient>,
+        key_prefix: String,
+    },
+}
+
+impl Store {
+    pub fn new(config: &StoreConfig) -> anyhow::Result<Self> {
+        match config {
+            StoreConfig::Memory => Ok(Store::Memory),
+            StoreConfig::Redis {
+                url,
+                key_prefix,
+                timeout_millis,
+            } => Ok(Store::Redis {
+                client: Arc::new(RedisClient::new(url, Duration::from_millis(*timeout_millis))?),
+                key_prefix: key_prefix.clone(),
+            }),
+        }
+    }
+
+    /// Creates a keyed limiter. `name` must be unique for every rule sharing the store.
+    pub fn keyed_limiter(&self, name: &str, burst: NonZeroU32, period: Duration) -> Arc<dyn KeyedRateLimiter> {
+        match self {
+            Store::Memory => Arc::new(
+                RateLimiter::keyed(super::build_quota(burst, period)).with_middleware::<StateInformationMiddleware>(),
+            ),
+            Store::Redis { client, key_prefix } => Arc::new(RedisRateLimiter {
+                client: client.clone(),
+                key_prefix: format!("{key_prefix}:{name}"),
+                burst: burst.get(),
+                period,
+            }),
+        }
+    }
+}
+
+/// Fixed window counters shared through a Redis-protocol server.
+///
+/// Unlike the in-memory limiter, up to twice the burst can pass around a window
+/// boundary. If the server can't be reached, requests are let through.
+struct RedisRateLimiter {
+    client: Arc<RedisClient>,
+    key_prefix: String,
+    burst: u32,
+    period: Duration,
+}
+
+#[async_trait]
+impl KeyedRateLimiter for RedisRateLimiter {
+    async fn check_key_n(&self, key: &String, n: NonZeroU32) -> Result<Permit, Exceeded> {
+        let period = self.period.as_millis().max(1) as u64;
+        let ttl = (period * 2).to_string();
+        let n = n.get().to_string();
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let window = now / period;
+        let redis_key = format!("{}:{key}:{window}", self.key_prefix);
+
+        let replies = self
+            .client
+            .pipeline(&[
+                &[b"INCRBY", redis_key.as_bytes(), n.as_bytes()],
+                &[b"PEXPIRE", redis_key.as_bytes(), ttl.as_bytes()],
+            ])
+            .await;
+
+        match replies.and_then(|r| r[0].as_int()) {
+            Ok(count) if count <= self.burst as i64 => Ok(Permit {
+                limit: self.burst,
+                remaining: (self.burst as i64 - count) as u32,
+                delay: Duration::ZERO,
+                jitter: Duration::ZERO,
+            }),
+            Ok(_) => Err(Exceeded {
+                limit: self.burst,
+                retry_after: Duration::from_millis((window + 1) * period - now),
+            }),
+            Err(e) => {
+                tracing::warn!("Rate limit store unavailable, allowing request: {e}");
+                Ok(Permit {
+                    limit: self.burst,
+                    remaining: self.burst,
+                    delay: Duration::ZERO,
+                    jitter: Duration::ZERO,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::redis::mock::redis_server;
+
+    #[tokio::test]
+    async fn redis_limiter_is_shared() {
+        let (addr, _handle) = redis_server().await;
+        let config = StoreConfig::Redis {
+            url: format!("redis://{addr}"),
+            key_prefix: default_key_prefix(),
+            timeout_millis: 500,
+        };
+
+        // two replicas using the same server
+        let replica1 = Store::new(&config).unwrap();
+        let replica2 = Store::new(&config).unwrap();
+        let burst = NonZeroU32::new(10).unwrap();
+        let period = Duration::from_millis(500);
+        let limiter1 = replica1.keyed_limiter("ip", burst, period);
+        let limiter2 = replica2.keyed_limiter("ip", burst, period);
+        let other_rule = replica2.keyed_limiter("method:eth_getLogs", burst, period);
+
+        let key = "1.1.1.1".to_string();
+        let one = NonZeroU32::new(1).unwrap();
+        let jitter = Jitter::up_to(Duration::ZERO);
+
+        // wait for the start of a window so the burst isn't split
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        tokio::time::sleep(Duration::from_millis(500 - now % 500 + 20)).await;
+
+        let start = tokio::time::Instant::now();
+        for _ in 0..5 {
+            limiter1.until_key_n_ready(&key, one, jitter).await;
+            limiter2.until_key_n_ready(&key, one, jitter).await;
+        }
+        // other keys and rules have their own quota
+        other_rule.until_key_n_ready(&key, burst, jitter).await;
+        limiter1.until_key_n_ready(&"2.2.2.2".to_string(), burst, jitter).await;
+        assert!(start.elapsed() < Duration::from_millis(200));
+
+        // quota used up by both replicas
+        limiter2.until_key_n_ready(&key, one, jitter).await;
+        assert!(start.elapsed() >= Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn redis_limiter_fails_open() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let store = Store::new(&StoreConfig::Redis {
+            url: format!("redis://{addr}"),
+            key_prefix: default_key_prefix(),
+            timeout_millis: 100,
+        })
+        .unwrap();
+        let limiter = store.keyed_limiter("ip", NonZeroU32::new(1).unwrap(), Duration::from_secs(60));
+
+        let key = "1.1.1.1".to_string();
+        let one = NonZeroU32::new(1).unwrap();
+        for _ in 0..3 {
+            limiter
+                .until_key_n_ready(&key, one, Jitter::up_to(Duration::ZERO))
+                .await;
+        }
+    }
+}
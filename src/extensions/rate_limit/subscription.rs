@@ -0,0 +1,454 @@
+//! Limits on subscriptions: how often an ip may subscribe, and what happens to
+//! notifications a subscriber doesn't consume fast enough.
+
+use futures::{future::BoxFuture, FutureExt};
+use governor::Jitter;
+use jsonrpsee::{
+    core::StringError,
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    MethodResponse, SubscriptionMessage, SubscriptionSink,
+};
+use serde::Deserialize;
+use std::{
+    collections::{HashSet, VecDeque},
+    num::NonZeroU32,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::{oneshot, watch, Notify};
+
+use super::{feedback, BanList, KeyedRateLimiter, OnLimit, RateLimitFeedback, Rule};
+use crate::extensions::prometheus::RpcMetrics;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SubscriptionLimitConfig {
+    /// Subscriptions an ip may open per period. Subscribe calls still count towards the `ip` and `connection` quotas.
+    #[serde(default)]
+    pub ip: Option<Rule>,
+    /// Notifications queued per subscriber. If not set, notifications wait for the subscriber.
+    #[serde(default)]
+    pub buffer: Option<BufferConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct BufferConfig {
+    #[serde(default = "default_buffer_size")]
+    pub size: usize,
+    #[serde(default)]
+    pub on_full: OnFull,
+}
+
+fn default_buffer_size() -> usize {
+    128
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OnFull {
+    /// Drop the oldest queued notification to make room.
+    #[default]
+    DropOldest,
+    /// Drop the new notification.
+    DropNewest,
+    /// Close the subscription with an error.
+    Close,
+}
+
+/// The subscription has been closed.
+#[derive(Debug)]
+pub struct SubscriptionClosed;
+
+#[derive(Default)]
+struct Queue {
+    messages: VecDeque<SubscriptionMessage>,
+    overflowed: bool,
+    // no more messages will be queued
+    done: bool,
+}
+
+enum Inner {
+    Direct {
+        sink: SubscriptionSink,
+        // held until the subscription ends
+        _close: oneshot::Sender<StringError>,
+    },
+    Buffered {
+        config: BufferConfig,
+        queue: Arc<Mutex<Queue>>,
+        notify: Arc<Notify>,
+        closed: watch::Receiver<bool>,
+    },
+}
+
+/// Sends notifications to a subscriber according to the buffer policy.
+///
+/// When buffered, the sink is owned by a task writing the queued notifications,
+/// since dropping any copy of a `SubscriptionSink` ends the subscription.
+pub struct NotificationSink(Inner);
+
+impl NotificationSink {
+    /// `close` ends the subscription with an error notification.
+    pub fn new(sink: SubscriptionSink, buffer: Option<BufferConfig>, close: oneshot::Sender<StringError>) -> Self {
+        let Some(config) = buffer else {
+            return Self(Inner::Direct { sink, _close: close });
+        };
+
+        let queue = Arc::new(Mutex::new(Queue::default()));
+        let notify = Arc::new(Notify::new());
+        let (closed_tx, closed) = watch::channel(false);
+        tokio::spawn(write(sink, queue.clone(), notify.clone(), close, closed_tx));
+
+        Self(Inner::Buffered {
+            config,
+            queue,
+            notify,
+            closed,
+        })
+    }
+
+    pub async fn send(&self, message: SubscriptionMessage) -> Result<(), SubscriptionClosed> {
+        let (config, queue, notify) = match &self.0 {
+            Inner::Direct { sink, .. } => return sink.send(message).await.map_err(|_| SubscriptionClosed),
+            Inner::Buffered {
+                config,
+                queue,
+                notify,
+                closed,
+            } => {
+                if *closed.borrow() {
+                    return Err(SubscriptionClosed);
+                }
+                (config, queue, notify)
+            }
+        };
+
+        let mut queue = queue.lock().expect("notification queue lock poisoned");
+        if queue.overflowed {
+            return Err(SubscriptionClosed);
+        }
+        if queue.messages.len() >= config.size {
+            match config.on_full {
+                OnFull::DropOldest => {
+                    queue.messages.pop_front();
+                }
+                OnFull::DropNewest => return Ok(()),
+                OnFull::Close => {
+                    queue.overflowed = true;
+                    notify.notify_one();
+                    return Err(SubscriptionClosed);
+                }
+            }
+        }
+        queue.messages.push_back(message);
+        notify.notify_one();
+        Ok(())
+    }
+
+    /// Completes when the subscription has been closed.
+    pub async fn closed(&self) {
+        match &self.0 {
+            Inner::Direct { sink, .. } => sink.closed().await,
+            Inner::Buffered { closed, .. } => {
+                let _ = closed.clone().wait_for(|closed| *closed).await;
+            }
+        }
+    }
+}
+
+impl Drop for NotificationSink {
+    fn drop(&mut self) {
+        if let Inner::Buffered { queue, notify, .. } = &self.0 {
+            queue.lock().expect("notification queue lock poisoned").done = true;
+            notify.notify_one();
+        }
+    }
+}
+
+/// Sends the queued notifications until the subscription is closed or nothing is left to send.
+async fn write(
+    sink: SubscriptionSink,
+    queue: Arc<Mutex<Queue>>,
+    notify: Arc<Notify>,
+    close: oneshot::Sender<StringError>,
+    closed: watch::Sender<bool>,
+) {
+    loop {
+        let next = {
+            let mut queue = queue.lock().expect("notification queue lock poisoned");
+            if queue.overflowed {
+                tracing::debug!("Closing subscription {:?}: subscriber too slow", sink.subscription_id());
+                let _ = close.send("Subscription closed, too many pending notifications".into());
+                break;
+            }
+            match queue.messages.pop_front() {
+                Some(message) => Some(message),
+                None if queue.done => break,
+                None => None,
+            }
+        };
+
+        match next {
+            Some(message) => {
+                if sink.send(message).await.is_err() {
+                    break;
+                }
+            }
+            None => {
+                tokio::select! {
+                    _ = notify.notified() => {}
+                    _ = sink.closed() => break,
+                }
+            }
+        }
+    }
+    let _ = closed.send(true);
+}
+
+#[derive(Clone)]
+pub struct SubscriptionRateLimitLayer {
+    ip_addr: String,
+    limiter: Arc<dyn KeyedRateLimiter>,
+    jitter: Jitter,
+    subscribe_methods: Arc<HashSet<String>>,
+    on_limit: OnLimit,
+    ban_list: Option<Arc<BanList>>,
+    metrics: RpcMetrics,
+}
+
+impl SubscriptionRateLimitLayer {
+    pub(crate) fn new(
+        ip_addr: String,
+        limiter: Arc<dyn KeyedRateLimiter>,
+        jitter: Jitter,
+        subscribe_methods: Arc<HashSet<String>>,
+        on_limit: OnLimit,
+        ban_list: Option<Arc<BanList>>,
+        metrics: RpcMetrics,
+    ) -> Self {
+        Self {
+            ip_addr,
+            limiter,
+            jitter,
+            subscribe_methods,
+            on_limit,
+            ban_list,
+            metrics,
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for SubscriptionRateLimitLayer {
+    type Service = SubscriptionRateLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        SubscriptionRateLimit {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct SubscriptionRateLimit<S> {
+    service: S,
+    layer: SubscriptionRateLimitLayer,
+}
+
+impl<'a, S> RpcServiceT<'a> for SubscriptionRateLimit<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        let service = self.service.clone();
+        if !self.layer.subscribe_methods.contains(req.method_name()) {
+            return async move { service.call(req).await }.boxed();
+        }
+
+        let SubscriptionRateLimitLayer {
+            ip_addr,
+            limiter,
+            jitter,
+            on_limit,
+            ban_list,
+            metrics,
+            ..
+        } = self.layer.clone();
+
+        async move {
+            let one = NonZeroU32::MIN;
+            let outcome = match on_limit {
+                OnLimit::Queue => Ok(limiter.until_key_n_ready(&ip_addr, one, jitter).await),
+                OnLimit::Reject => limiter.check_key_n(&ip_addr, one).await,
+            };
+            feedback::observe(&metrics, "subscription", req.method_name(), &outcome);
+            if let (true, Some(ban_list)) = (outcome.is_err(), &ban_list) {
+                ban_list.record_offense(&ip_addr);
+            }
+            if let Err(err) = RateLimitFeedback::record(req.extensions().get(), outcome) {
+                return MethodResponse::error(req.id, err);
+            }
+            service.call(req).await
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::rate_limit::{build_quota, MemoryRateLimiter};
+    use governor::{middleware::StateInformationMiddleware, RateLimiter};
+    use jsonrpsee::types::Id;
+    use jsonrpsee::ResponsePayload;
+    use jsonrpsee::{
+        core::client::SubscriptionClientT, rpc_params, server::ServerBuilder, ws_client::WsClientBuilder, RpcModule,
+    };
+    use std::time::Duration;
+
+    #[derive(Clone)]
+    struct MockService;
+    impl RpcServiceT<'static> for MockService {
+        type Future = BoxFuture<'static, MethodResponse>;
+
+        fn call(&self, req: Request<'static>) -> Self::Future {
+            async move { MethodResponse::response(req.id, ResponsePayload::success("ok"), 1024) }.boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn limits_subscribe_calls() {
+        let limiter: MemoryRateLimiter =
+            RateLimiter::keyed(build_quota(NonZeroU32::new(2).unwrap(), Duration::from_secs(60)))
+                .with_middleware::<StateInformationMiddleware>();
+        let layer = SubscriptionRateLimitLayer::new(
+            "1.1.1.1".to_string(),
+            Arc::new(limiter),
+            Jitter::up_to(Duration::ZERO),
+            Arc::new(["subscribe".to_string()].into()),
+            OnLimit::Reject,
+            None,
+            RpcMetrics::noop(),
+        );
+        let service = tower::Layer::layer(&layer, MockService);
+        let call = |method: &'static str| service.call(Request::new(method.into(), None, Id::Number(1)));
+
+        assert!(call("subscribe").await.is_success());
+        assert!(call("subscribe").await.is_success());
+        assert!(!call("subscribe").await.is_success());
+        // other calls are not limited
+        assert!(call("other").await.is_success());
+    }
+
+    /// A server pushing `count` notifications right away through the buffer policy.
+    async fn server(buffer: BufferConfig, count: usize) -> String {
+        let server = ServerBuilder::default().build("127.0.0.1:0").await.unwrap();
+        let mut module = RpcModule::new(());
+        module
+            .register_subscription("sub", "notif", "unsub", move |_, pending, _, _| {
+                let buffer = buffer.clone();
+                async move {
+                    let (close_tx, close_rx) = oneshot::channel();
+                    let sink = NotificationSink::new(pending.accept().await.unwrap(), Some(buffer), close_tx);
+                    for i in 0..count {
+                        if sink.send(SubscriptionMessage::from_json(&i).unwrap()).await.is_err() {
+                            break;
+                        }
+                    }
+                    drop(sink);
+                    match close_rx.await {
+                        Ok(err) => Err(err),
+                        Err(_) => Ok(()),
+                    }
+                }
+            })
+            .unwrap();
+        let url = format!("ws://{}", server.local_addr().unwrap());
+        let handle = server.start(module);
+        std::mem::forget(handle);
+        url
+    }
+
+    /// The notifications received and whether the server closed the subscription.
+    async fn received(url: &str) -> (Vec<usize>, bool) {
+        let client = WsClientBuilder::default()
+            .max_buffer_capacity_per_subscription(10_000)
+            .build(url)
+            .await
+            .unwrap();
+        let mut sub = client
+            .subscribe::<usize, _>("sub", rpc_params!(), "unsub")
+            .await
+            .unwrap();
+        let mut values = vec![];
+        // the client ends the subscription stream when the server closes it
+        while let Ok(next) = tokio::time::timeout(Duration::from_millis(500), sub.next()).await {
+            match next {
+                Some(value) => values.push(value.unwrap()),
+                None => return (values, true),
+            }
+        }
+        (values, false)
+    }
+
+    #[tokio::test]
+    async fn buffer_policies() {
+        let (values, closed) = received(
+            &server(
+                BufferConfig {
+                    size: 10_000,
+                    on_full: OnFull::DropNewest,
+                },
+                5_000,
+            )
+            .await,
+        )
+        .await;
+        assert_eq!(values.len(), 5_000);
+        assert!(!closed);
+
+        let (values, closed) = received(
+            &server(
+                BufferConfig {
+                    size: 1,
+                    on_full: OnFull::DropNewest,
+                },
+                5_000,
+            )
+            .await,
+        )
+        .await;
+        assert!(values.len() < 5_000);
+        assert_eq!(values[0], 0);
+        assert!(!closed);
+
+        let (values, closed) = received(
+            &server(
+                BufferConfig {
+                    size: 1,
+                    on_full: OnFull::DropOldest,
+                },
+                5_000,
+            )
+            .await,
+        )
+        .await;
+        assert!(values.len() < 5_000);
+        assert_eq!(values.last(), Some(&4_999));
+        assert!(!closed);
+
+        let (values, closed) = received(
+            &server(
+                BufferConfig {
+                    size: 1,
+                    on_full: OnFull::Close,
+                },
+                5_000,
+            )
+            .await,
+        )
+        .await;
+        assert!(values.len() < 5_000);
+        assert!(closed);
+    }
+}
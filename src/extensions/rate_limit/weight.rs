@@ -12,6 +12,16 @@ impl MethodWeights {
 }
 
 impl MethodWeights {
+    /// Returns a copy with the weight of the given methods set to 0.
+    pub fn without<'a>(&self, methods: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut weights = (*self.0).clone();
+        for method in methods {
+            weights.insert(method.to_owned(), 0);
+        }
+
+        Self(Arc::new(weights))
+    }
+
     pub fn from_config(methods: &[RpcMethod]) -> Self {
         let mut weights = BTreeMap::default();
         for method in methods {
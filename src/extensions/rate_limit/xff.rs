@@ -1,19 +1,115 @@
+use ipnet::IpNet;
 use std::net::{IpAddr, SocketAddr};
 use std::str::FromStr;
 
 pub trait XFF {
+    /// Returns the first (left-most) entry of `X-Forwarded-For`.
+    /// Any client can set it, so only use it when every hop is trusted.
     fn xxf_ip(&self) -> Option<String>;
+
+    /// Returns the IP of the client, walking the proxy chain right-to-left past `trusted_proxies`.
+    ///
+    /// Forwarding headers are only considered if `remote` is a trusted proxy. The chain is
+    /// taken from the RFC 7239 `Forwarded` header, or `X-Forwarded-For` if it is not present.
+    /// Without either, `X-Real-IP` is used.
+    fn client_ip(&self, remote: IpAddr, trusted_proxies: &[IpNet]) -> IpAddr;
 }
+
 impl<T> XFF for http::Request<T> {
     fn xxf_ip(&self) -> Option<String> {
         let xff = self.headers().get("x-forwarded-for")?;
         let xff = xff.to_str().ok()?;
         let xff = xff.split(',').next()?;
-        let addr = IpAddr::from_str(xff)
-            .ok()
-            .or(SocketAddr::from_str(xff).map(|x| x.ip()).ok())?;
+        let addr = parse_ip(xff)?;
         Some(addr.to_string())
     }
+
+    fn client_ip(&self, remote: IpAddr, trusted_proxies: &[IpNet]) -> IpAddr {
+        let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+
+        let remote = remote.to_canonical();
+        if !is_trusted(&remote) {
+            return remote;
+        }
+
+        let chain = forwarded_chain(self.headers()).or_else(|| xff_chain(self.headers()));
+
+        if let Some(chain) = chain {
+            let mut client = remote;
+            for hop in chain.into_iter().rev() {
+                match hop {
+                    Some(ip) => {
+                        client = ip.to_canonical();
+                        if !is_trusted(&client) {
+                            return client;
+                        }
+                    }
+                    // a trusted proxy forwarded an unknown or obfuscated address,
+                    // nothing further left can be trusted
+                    None => return client,
+                }
+            }
+            return client;
+        }
+
+        self.headers()
+            .get("x-real-ip")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_ip)
+            .map(|ip| ip.to_canonical())
+            .unwrap_or(remote)
+    }
+}
+
+/// Parses an IP with an optional port. IPv6 may be enclosed in brackets.
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    let s = s.trim();
+    IpAddr::from_str(s)
+        .ok()
+        .or(SocketAddr::from_str(s).map(|x| x.ip()).ok())
+        .or_else(|| {
+            s.strip_prefix('[')
+                .and_then(|s| s.strip_suffix(']'))
+                .and_then(|s| IpAddr::from_str(s).ok())
+        })
+}
+
+/// Hops listed in all `X-Forwarded-For` headers, from client to the last proxy.
+fn xff_chain(headers: &http::HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let mut values = headers.get_all("x-forwarded-for").iter().peekable();
+    values.peek()?;
+
+    Some(
+        values
+            .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+            .map(parse_ip)
+            .collect(),
+    )
+}
+
+/// `for=` parameters listed in all RFC 7239 `Forwarded` headers, from client to the last proxy.
+fn forwarded_chain(headers: &http::HeaderMap) -> Option<Vec<Option<IpAddr>>> {
+    let mut values = headers.get_all(http::header::FORWARDED).iter().peekable();
+    values.peek()?;
+
+    // elements without `for=` are kept as unknown hops so positions stay aligned
+    let chain: Vec<Option<Option<IpAddr>>> = values
+        .flat_map(|v| v.to_str().unwrap_or_default().split(','))
+        .map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                if !key.trim().eq_ignore_ascii_case("for") {
+                    return None;
+                }
+                Some(parse_ip(value.trim().trim_matches('"')))
+            })
+        })
+        .collect();
+
+    chain
+        .iter()
+        .any(Option::is_some)
+        .then(|| chain.into_iter().map(Option::flatten).collect())
 }
 
 #[test]
@@ -43,3 +139,96 @@ fn test_xff() {
         assert_eq!(req.xxf_ip().as_deref(), ip);
     }
 }
+
+#[test]
+fn test_client_ip() {
+    let trusted = crate::utils::parse_ip_nets(["10.0.0.0/8", "2001:db8::/32"]).unwrap();
+    let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+    let untrusted: IpAddr = "1.1.1.1".parse().unwrap();
+
+    type Headers = Vec<(&'static str, &'static str)>;
+    let cases: Vec<(IpAddr, Headers, &str)> = vec![
+        // headers from untrusted peers are ignored
+        (untrusted, vec![("X-Forwarded-For", "2.2.2.2")], "1.1.1.1"),
+        // no headers
+        (proxy, vec![], "10.0.0.1"),
+        // forged left-most entries are skipped
+        (
+            proxy,
+            vec![("X-Forwarded-For", "6.6.6.6, 3.3.3.3, 10.0.0.2")],
+            "3.3.3.3",
+        ),
+        // multiple headers are combined in order
+        (
+            proxy,
+            vec![("X-Forwarded-For", "6.6.6.6, 3.3.3.3"), ("X-Forwarded-For", "10.0.0.2")],
+            "3.3.3.3",
+        ),
+        // all hops trusted
+        (proxy, vec![("X-Forwarded-For", "10.0.0.3, 10.0.0.2")], "10.0.0.3"),
+        // invalid hop stops the walk at the last trusted proxy
+        (proxy, vec![("X-Forwarded-For", "3.3.3.3, foo, 10.0.0.2")], "10.0.0.2"),
+        // Forwarded takes precedence over X-Forwarded-For
+        (
+            proxy,
+            vec![
+                ("X-Forwarded-For", "6.6.6.6"),
+                (
+                    "Forwarded",
+                    r#"for=6.6.6.6, For="[2001:db8:cafe::17]:4711";proto=https, for=3.3.3.3;by=10.0.0.1"#,
+                ),
+            ],
+            "3.3.3.3",
+        ),
+        (
+            proxy,
+            vec![("Forwarded", r#"for=6.6.6.6, for="[2001:db8:cafe::17]:4711""#)],
+            "6.6.6.6",
+        ),
+        (proxy, vec![("Forwarded", "for=unknown")], "10.0.0.1"),
+        // an element without `for=` is an unknown hop, not skipped
+        (
+            proxy,
+            vec![("Forwarded", "for=3.3.3.3, proto=https;by=10.0.0.1")],
+            "10.0.0.1",
+        ),
+        (
+            proxy,
+            vec![("Forwarded", "for=3.3.3.3, for=10.0.0.2, proto=https")],
+            "10.0.0.1",
+        ),
+        // without any `for=`, X-Forwarded-For is used
+        (
+            proxy,
+            vec![("Forwarded", "proto=https"), ("X-Forwarded-For", "3.3.3.3")],
+            "3.3.3.3",
+        ),
+        // X-Real-IP
+        (proxy, vec![("X-Real-IP", "3.3.3.3")], "3.3.3.3"),
+        (
+            proxy,
+            vec![("X-Real-IP", "3.3.3.3"), ("X-Forwarded-For", "4.4.4.4")],
+            "4.4.4.4",
+        ),
+        (untrusted, vec![("X-Real-IP", "3.3.3.3")], "1.1.1.1"),
+        // ipv4 mapped ipv6 peer
+        (
+            "::ffff:10.0.0.1".parse().unwrap(),
+            vec![("X-Forwarded-For", "3.3.3.3")],
+            "3.3.3.3",
+        ),
+    ];
+
+    for (remote, headers, expected) in cases {
+        let mut req = http::Request::builder();
+        for (name, value) in &headers {
+            req = req.header(*name, *value);
+        }
+        let req = req.body(()).unwrap();
+        assert_eq!(
+            req.client_ip(remote, &trusted).to_string(),
+            expected,
+            "remote: {remote}, headers: {headers:?}"
+        );
+    }
+}
@@ -0,0 +1,298 @@
+//! Middleware that serves the gateway's own liveness and readiness state.
+
+use hyper::body::Bytes;
+use hyper::{Method, StatusCode};
+use jsonrpsee::core::{
+    http_helpers::{Body as HttpBody, Request as HttpRequest, Response as HttpResponse},
+    BoxError, JsonValue,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::{Layer, Service};
+
+use crate::extensions::{
+    api::{EthApi, SubstrateApi},
+    client::Client,
+};
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HealthConfig {
+    #[serde(default = "default_liveness_path")]
+    pub liveness_path: String,
+    #[serde(default = "default_readiness_path")]
+    pub readiness_path: String,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            liveness_path: default_liveness_path(),
+            readiness_path: default_readiness_path(),
+        }
+    }
+}
+
+fn default_liveness_path() -> String {
+    "/live".to_string()
+}
+
+fn default_readiness_path() -> String {
+    "/ready".to_string()
+}
+
+/// Collects the state of the extensions the gateway depends on to serve requests.
+#[derive(Clone, Default)]
+pub struct HealthCheck {
+    client: Option<Arc<Client>>,
+    substrate_api: Option<Arc<SubstrateApi>>,
+    eth_api: Option<Arc<EthApi>>,
+}
+
+impl HealthCheck {
+    pub fn new(
+        client: Option<Arc<Client>>,
+        substrate_api: Option<Arc<SubstrateApi>>,
+        eth_api: Option<Arc<EthApi>>,
+    ) -> Self {
+        Self {
+            client,
+            substrate_api,
+            eth_api,
+        }
+    }
+
+    /// Returns whether the gateway is ready to serve requests,
+    /// along with the status of each component.
+    pub fn readiness(&self) -> (bool, JsonValue) {
+        let mut ready = true;
+        let mut components = serde_json::Map::new();
+
+        if let Some(client) = &self.client {
+            let connected = client.is_connected();
+            ready &= connected;
+            components.insert(
+                "upstream".to_string(),
+                json!({
+                    "status": status(connected),
+                    "connected": connected,
+                    "endpoints": client.endpoints().len(),
+                }),
+            );
+        }
+
+        if let Some(api) = &self.substrate_api {
+            let (ok, value) = head_status(
+                api.current_head().map(|(_, number)| number),
+                api.current_finalized_head().map(|(_, number)| number),
+                api.head_age(),
+                api.stale_timeout(),
+                true,
+            );
+            ready &= ok;
+            components.insert("substrate_api".to_string(), value);
+        }
+
+        if let Some(api) = &self.eth_api {
+            let (ok, value) = head_status(
+                api.current_head().map(|(_, number)| number),
+                api.current_finalized_head().map(|(_, number)| number),
+                api.head_age(),
+                api.stale_timeout(),
+                api.finalized_head_supported(),
+            );
+            ready &= ok;
+            components.insert("eth_api".to_string(), value);
+        }
+
+        let body = json!({
+            "status": if ready { "ready" } else { "not_ready" },
+            "components": components,
+        });
+
+        (ready, body)
+    }
+}
+
+fn status(ok: bool) -> &'static str {
+    if ok {
+        "ok"
+    } else {
+        "unhealthy"
+    }
+}
+
+fn head_status(
+    head: Option<u64>,
+    finalized_head: Option<u64>,
+    head_age: Option<Duration>,
+    stale_timeout: Duration,
+    require_finalized: bool,
+) -> (bool, JsonValue) {
+    let head_ok = head.is_some() && head_age.map(|age| age <= stale_timeout).unwrap_or(false);
+    let finalized_ok = !require_finalized || finalized_head.is_some();
+    let ok = head_ok && finalized_ok;
+
+    let value = json!({
+        "status": status(ok),
+        "head": head,
+        "head_age_seconds": head_age.map(|age| age.as_secs()),
+        "stale": !head_ok,
+        "finalized_head": finalized_head,
+    });
+
+    (ok, value)
+}
+
+#[derive(Clone)]
+pub struct HealthLayer {
+    config: HealthConfig,
+    check: HealthCheck,
+}
+
+impl HealthLayer {
+    pub fn new(config: HealthConfig, check: HealthCheck) -> anyhow::Result<Self> {
+        for path in [&config.liveness_path, &config.readiness_path] {
+            if !path.starts_with('/') {
+                anyhow::bail!("health path must start with `/`: {path}");
+            }
+        }
+
+        Ok(Self { config, check })
+    }
+}
+
+impl<S> Layer<S> for HealthLayer {
+    type Service = Health<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Health {
+            inner,
+            config: self.config.clone(),
+            check: self.check.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Health<S> {
+    inner: S,
+    config: HealthConfig,
+    check: HealthCheck,
+}
+
+impl<S, B> Service<HttpRequest<B>> for Health<S>
+where
+    S: Service<HttpRequest<B>, Response = HttpResponse>,
+    S::Error: Into<BoxError> + 'static,
+    S::Future: Send + 'static,
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: HttpRequest<B>) -> Self::Future {
+        if req.method() == Method::GET {
+            let path = req.uri().path();
+
+            if path == self.config.liveness_path {
+                let body = json!({ "status": "alive" });
+                return Box::pin(futures::future::ready(Ok(json_response(StatusCode::OK, &body))));
+            }
+
+            if path == self.config.readiness_path {
+                let (ready, body) = self.check.readiness();
+                let status = if ready {
+                    StatusCode::OK
+                } else {
+                    StatusCode::SERVICE_UNAVAILABLE
+                };
+                return Box::pin(futures::future::ready(Ok(json_response(status, &body))));
+            }
+        }
+
+        let fut = self.inner.call(req);
+        Box::pin(async move { fut.await.map_err(Into::into) })
+    }
+}
+
+fn json_response(status: StatusCode, body: &JsonValue) -> HttpResponse {
+    HttpResponse::builder()
+        .status(status)
+        .header(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("application/json; charset=utf-8"),
+        )
+        .body(HttpBody::from(body.to_string()))
+        .expect("Unable to build health response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::client::mock::dummy_server;
+
+    #[test]
+    fn head_status_works() {
+        let timeout = Duration::from_secs(10);
+
+        let (ok, _) = head_status(Some(10), Some(8), Some(Duration::from_secs(1)), timeout, true);
+        assert!(ok);
+
+        // stale head
+        let (ok, value) = head_status(Some(10), Some(8), Some(Duration::from_secs(11)), timeout, true);
+        assert!(!ok);
+        assert_eq!(value["stale"], json!(true));
+
+        // no head yet
+        let (ok, _) = head_status(None, None, None, timeout, false);
+        assert!(!ok);
+
+        // finalized head unknown
+        let (ok, _) = head_status(Some(10), None, Some(Duration::from_secs(1)), timeout, true);
+        assert!(!ok);
+        let (ok, _) = head_status(Some(10), None, Some(Duration::from_secs(1)), timeout, false);
+        assert!(ok);
+    }
+
+    #[tokio::test]
+    async fn readiness_reflects_upstream_connection() {
+        let (ok, value) = HealthCheck::default().readiness();
+        assert!(ok);
+        assert_eq!(value, json!({ "status": "ready", "components": {} }));
+
+        let (addr, handle, _, _) = dummy_server().await;
+        let client = Arc::new(Client::with_endpoints([format!("ws://{addr}")]).unwrap());
+        let check = HealthCheck::new(Some(client.clone()), None, None);
+
+        while !client.is_connected() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (ok, value) = check.readiness();
+        assert!(ok);
+        assert_eq!(value["components"]["upstream"]["status"], json!("ok"));
+
+        handle.stop().unwrap();
+        handle.stopped().await;
+
+        while client.is_connected() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let (ok, value) = check.readiness();
+        assert!(!ok);
+        assert_eq!(value["status"], json!("not_ready"));
+        assert_eq!(value["components"]["upstream"]["status"], json!("unhealthy"));
+    }
+}
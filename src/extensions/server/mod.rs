@@ -1,8 +1,10 @@
 use async_trait::async_trait;
 use futures::FutureExt;
 use http::header::HeaderValue;
+use jsonrpsee::core::http_helpers::{Body as HttpBody, Response as HttpResponse};
 use jsonrpsee::server::{
-    middleware::rpc::RpcServiceBuilder, serve_with_graceful_shutdown, stop_channel, ws, BatchRequestConfig,
+    middleware::rpc::{layer::either::Either, RpcServiceBuilder},
+    serve_with_graceful_shutdown, stop_channel, ws, BatchRequestConfig, MethodCallback, PingConfig,
     RandomStringIdProvider, RpcModule, ServerHandle, StopHandle, TowerServiceBuilder,
 };
 use jsonrpsee::Methods;
@@ -12,24 +14,42 @@ use serde::Deserialize;
 
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use std::{future::Future, net::SocketAddr};
-use tower::layer::layer_fn;
+use tower::layer::util::Identity;
 use tower::Service;
 use tower_http::cors::{AllowOrigin, CorsLayer};
 
 use super::{Extension, ExtensionRegistry};
-use crate::extensions::rate_limit::{MethodWeights, RateLimitBuilder, XFF};
+use crate::extensions::{
+    admin::{Admin, AdminAuthorized},
+    api::{EthApi, SubstrateApi},
+    client::Client,
+    ip_filter::IpFilter,
+    rate_limit::{MethodWeights, RateLimitBuilder, RateLimitFeedback},
+};
+pub use health::{HealthCheck, HealthConfig};
 pub use prometheus::Protocol;
+pub use sse::SseConfig;
 
+mod health;
 mod prometheus;
 mod proxy_get_request;
+mod sse;
+mod subscription_limit;
 
 use crate::extensions::prometheus::RpcMetrics;
-use crate::extensions::server::prometheus::PrometheusService;
+use crate::extensions::server::prometheus::PrometheusLayer;
+use health::HealthLayer;
 use proxy_get_request::{ProxyGetRequestLayer, ProxyGetRequestMethod};
+use sse::{SseLayer, SseRpc};
+use subscription_limit::{IpSubscriptionCounter, IpSubscriptionLimitLayer, SubscriptionMethods};
 
 pub struct SubwayServerBuilder {
     pub config: ServerConfig,
+    health_check: HealthCheck,
+    ip_filter: Option<Arc<IpFilter>>,
+    admin: Option<Arc<Admin>>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -66,6 +86,49 @@ pub struct ServerConfig {
     pub request_timeout_seconds: u64,
     #[serde(default)]
     pub cors: Option<ItemOrList<String>>,
+    #[serde(default)]
+    pub health: HealthConfig,
+    #[serde(default)]
+    pub sse: Option<SseConfig>,
+    /// Defaults to 1024 if not set.
+    #[serde(default)]
+    pub max_subscriptions_per_connection: Option<u32>,
+    #[serde(default)]
+    pub max_subscriptions_per_ip: Option<u32>,
+    #[serde(default)]
+    pub ws_ping: Option<WsPingConfig>,
+}
+
+/// Ping idle WS clients and close the connection if they stop responding.
+#[derive(Deserialize, Debug, Clone)]
+pub struct WsPingConfig {
+    #[serde(default = "default_ping_interval_seconds")]
+    pub interval_seconds: u64,
+    #[serde(default = "default_inactive_limit_seconds")]
+    pub inactive_limit_seconds: u64,
+    #[serde(default = "default_max_failures")]
+    pub max_failures: usize,
+}
+
+fn default_ping_interval_seconds() -> u64 {
+    30
+}
+
+fn default_inactive_limit_seconds() -> u64 {
+    40
+}
+
+fn default_max_failures() -> usize {
+    1
+}
+
+impl From<&WsPingConfig> for PingConfig {
+    fn from(config: &WsPingConfig) -> Self {
+        PingConfig::new()
+            .ping_interval(Duration::from_secs(config.interval_seconds))
+            .inactive_limit(Duration::from_secs(config.inactive_limit_seconds))
+            .max_failures(config.max_failures.max(1))
+    }
 }
 
 fn default_request_timeout_seconds() -> u64 {
@@ -76,8 +139,40 @@ fn default_request_timeout_seconds() -> u64 {
 impl Extension for SubwayServerBuilder {
     type Config = ServerConfig;
 
-    async fn from_config(config: &Self::Config, _registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
-        Ok(Self::new(config.clone()))
+    async fn from_config(config: &Self::Config, registry: &ExtensionRegistry) -> Result<Self, anyhow::Error> {
+        let health_check = HealthCheck::new(
+            registry.get::<Client>().await,
+            registry.get::<SubstrateApi>().await,
+            registry.get::<EthApi>().await,
+        );
+
+        Ok(Self::new(config.clone())
+            .with_health_check(health_check)
+            .with_ip_filter(registry.get::<IpFilter>().await)
+            .with_admin(registry.get::<Admin>().await))
+    }
+}
+
+fn forbidden() -> HttpResponse {
+    HttpResponse::builder()
+        .status(http::StatusCode::FORBIDDEN)
+        .body(HttpBody::from("Forbidden"))
+        .expect("Unable to build forbidden response")
+}
+
+fn too_many_requests() -> HttpResponse {
+    HttpResponse::builder()
+        .status(http::StatusCode::TOO_MANY_REQUESTS)
+        .body(HttpBody::from("Too Many Requests"))
+        .expect("Unable to build too many requests response")
+}
+
+/// Like `RpcServiceBuilder::option_layer`, for layers stacked with `tower::ServiceBuilder`
+/// so the stack can wrap other services than jsonrpsee's.
+fn optional<L>(layer: Option<L>) -> Either<L, Identity> {
+    match layer {
+        Some(layer) => Either::Left(layer),
+        None => Either::Right(Identity::new()),
     }
 }
 
@@ -99,7 +194,27 @@ fn cors_layer(cors: Option<ItemOrList<String>>) -> anyhow::Result<CorsLayer> {
 
 impl SubwayServerBuilder {
     pub fn new(config: ServerConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            health_check: HealthCheck::default(),
+            ip_filter: None,
+            admin: None,
+        }
+    }
+
+    pub fn with_health_check(mut self, health_check: HealthCheck) -> Self {
+        self.health_check = health_check;
+        self
+    }
+
+    pub fn with_ip_filter(mut self, ip_filter: Option<Arc<IpFilter>>) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    pub fn with_admin(mut self, admin: Option<Arc<Admin>>) -> Self {
+        self.admin = admin;
+        self
     }
 
     pub async fn build<Fut: Future<Output = anyhow::Result<RpcModule<()>>>>(
@@ -111,10 +226,31 @@ impl SubwayServerBuilder {
     ) -> anyhow::Result<(SocketAddr, ServerHandle)> {
         let config = self.config.clone();
 
-        let rpc_module = rpc_module_builder().await?;
+        let methods: Methods = rpc_module_builder().await?.into();
+
+        let subscription_methods = SubscriptionMethods::new(
+            methods
+                .method_names()
+                .filter(|name| matches!(methods.method(name), Some(MethodCallback::Subscription(_))))
+                .map(|name| name.to_string())
+                .collect(),
+        );
+        let shared_method_weights = match &rate_limit_builder {
+            Some(r) => r.shared_method_weights(&rpc_method_weights),
+            None => rpc_method_weights.clone(),
+        };
+
+        let ip_subscription_counter = config.max_subscriptions_per_ip.map(IpSubscriptionCounter::new);
 
         let http_middleware = tower::ServiceBuilder::new()
             .layer(cors_layer(config.cors.clone()).expect("Invalid CORS config"))
+            .layer(HealthLayer::new(config.health.clone(), self.health_check.clone()).expect("Invalid health config"))
+            .option_layer(
+                config
+                    .sse
+                    .clone()
+                    .map(|sse| SseLayer::new(sse, methods.clone()).expect("Invalid SSE config")),
+            )
             .layer(
                 ProxyGetRequestLayer::new(
                     config
@@ -154,7 +290,13 @@ impl SubwayServerBuilder {
             rpc_metrics: RpcMetrics,
             svc_builder: TowerServiceBuilder<RpcMiddleware, HttpMiddleware>,
             rate_limit_builder: Option<Arc<RateLimitBuilder>>,
+            ip_filter: Option<Arc<IpFilter>>,
+            admin: Option<Arc<Admin>>,
             rpc_method_weights: MethodWeights,
+            shared_method_weights: MethodWeights,
+            ip_subscription_counter: Option<IpSubscriptionCounter>,
+            subscription_methods: SubscriptionMethods,
+            sse: Option<SseConfig>,
         }
 
         // Each RPC call/connection get its own `stop_handle`
@@ -164,20 +306,41 @@ impl SubwayServerBuilder {
         // must be kept and it can also be used to stop the server.
         let (stop_handle, server_handle) = stop_channel();
 
+        let mut server_builder = jsonrpsee::server::Server::builder()
+            .set_http_middleware(http_middleware)
+            .set_batch_request_config(batch_request_config)
+            .max_connections(config.max_connections)
+            .set_id_provider(RandomStringIdProvider::new(16));
+        if let Some(max) = config.max_subscriptions_per_connection {
+            server_builder = server_builder.max_subscriptions_per_connection(max);
+        }
+        if let Some(ws_ping) = &config.ws_ping {
+            server_builder = server_builder.enable_ws_ping(ws_ping.into());
+        }
+
         let per_conn = PerConnection {
-            methods: rpc_module.into(),
+            methods,
             stop_handle: stop_handle.clone(),
             rpc_metrics,
-            svc_builder: jsonrpsee::server::Server::builder()
-                .set_http_middleware(http_middleware)
-                .set_batch_request_config(batch_request_config)
-                .max_connections(config.max_connections)
-                .set_id_provider(RandomStringIdProvider::new(16))
-                .to_service_builder(),
+            svc_builder: server_builder.to_service_builder(),
             rate_limit_builder,
+            ip_filter: self.ip_filter.clone(),
+            admin: self.admin.clone(),
             rpc_method_weights,
+            shared_method_weights,
+            ip_subscription_counter,
+            subscription_methods,
+            sse: config.sse.clone(),
         };
 
+        // behind proxies, bans are on the forwarded client ips, which are only
+        // known per request, so connections are only checked without use_xff
+        let ban_list = per_conn
+            .rate_limit_builder
+            .as_ref()
+            .filter(|r| !r.use_xff())
+            .and_then(|r| r.ban_list());
+
         tokio::spawn(async move {
             loop {
                 // The `tokio::select!` macro is used to wait for either of the
@@ -196,45 +359,133 @@ impl SubwayServerBuilder {
                     _ = per_conn.stop_handle.clone().shutdown() => break,
                 };
 
+                if let Some(ban_list) = &ban_list {
+                    if ban_list.is_banned(&remote_addr.ip().to_string()) {
+                        tracing::debug!("Dropped connection from {}: temporarily banned", remote_addr.ip());
+                        continue;
+                    }
+                }
+
                 let per_conn2 = per_conn.clone();
 
                 // service_fn handle each connection
-                let svc = tower::service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
+                let svc = tower::service_fn(move |mut req: hyper::Request<hyper::body::Incoming>| {
                     let PerConnection {
                         methods,
                         stop_handle,
                         rpc_metrics,
                         svc_builder,
                         rate_limit_builder,
+                        ip_filter,
+                        admin,
                         rpc_method_weights,
+                        shared_method_weights,
+                        ip_subscription_counter,
+                        subscription_methods,
+                        sse,
                     } = per_conn2.clone();
 
                     let is_websocket = ws::is_upgrade_request(&req);
                     let protocol = if is_websocket { Protocol::Ws } else { Protocol::Http };
 
-                    let mut socket_ip = remote_addr.ip().to_string();
-                    if let Some(true) = rate_limit_builder.as_ref().map(|r| r.use_xff()) {
-                        socket_ip = req.xxf_ip().unwrap_or(socket_ip);
+                    let socket_ip = match rate_limit_builder.as_ref() {
+                        Some(r) => r.client_ip(&req, remote_addr.ip()),
+                        None => remote_addr.ip().to_string(),
+                    };
+
+                    // any client can forge X-Forwarded-For, so the filter only trusts ips
+                    // resolved through trusted_proxies
+                    let filter_ip = match rate_limit_builder.as_ref() {
+                        Some(r) => r.trusted_client_ip(&req, remote_addr.ip()),
+                        None => remote_addr.ip(),
+                    };
+                    let access = ip_filter.as_ref().map(|f| f.check(filter_ip));
+                    if let Some(true) = access.map(|a| a.is_denied()) {
+                        tracing::debug!("Rejected request from {filter_ip}: denied by ip filter");
+                        return futures::future::ready(Ok(forbidden())).boxed();
+                    }
+
+                    // admin requests are exempt from rate limits and bans
+                    let is_admin = admin.as_ref().map(|a| a.is_authorized(&req)).unwrap_or(false);
+                    if is_admin {
+                        req.extensions_mut().insert(AdminAuthorized);
+                    }
+
+                    let rate_limit_builder = rate_limit_builder
+                        .filter(|_| !is_admin && !access.map(|a| a.bypass_rate_limit()).unwrap_or(false));
+                    if let Some(true) = rate_limit_builder
+                        .as_ref()
+                        .and_then(|r| r.ban_list())
+                        .map(|b| b.is_banned(&socket_ip))
+                    {
+                        tracing::debug!("Rejected request from {socket_ip}: temporarily banned");
+                        return futures::future::ready(Ok(too_many_requests())).boxed();
+                    }
+
+                    let quota_key = rate_limit_builder.as_ref().and_then(|r| r.quota_key(&req, &socket_ip));
+                    if let Some(key) = &quota_key {
+                        req.extensions_mut().insert(key.clone());
+                    }
+
+                    // the rate limit layers report to it for the response headers
+                    let feedback = rate_limit_builder
+                        .as_ref()
+                        .filter(|_| !is_websocket)
+                        .map(|_| RateLimitFeedback::default());
+                    if let Some(feedback) = &feedback {
+                        req.extensions_mut().insert(feedback.clone());
                     }
 
                     let call_metrics = rpc_metrics.call_metrics();
 
                     async move {
-                        let rpc_middleware =
-                            RpcServiceBuilder::new()
-                                .option_layer(
-                                    rate_limit_builder
-                                        .as_ref()
-                                        .and_then(|r| r.ip_limit(socket_ip, rpc_method_weights.clone())),
-                                )
-                                .option_layer(
-                                    rate_limit_builder
-                                        .as_ref()
-                                        .and_then(|r| r.connection_limit(rpc_method_weights.clone())),
-                                )
-                                .option_layer(call_metrics.as_ref().map(move |(a, b, c)| {
-                                    layer_fn(move |s| PrometheusService::new(s, protocol, a, b, c))
-                                }));
+                        let subscription_limit = rate_limit_builder
+                            .as_ref()
+                            .and_then(|r| r.subscription_limit(socket_ip.clone(), subscription_methods.subscribe()));
+                        let rpc_layers = tower::ServiceBuilder::new()
+                            .layer(optional(ip_subscription_counter.map(|counter| {
+                                IpSubscriptionLimitLayer::new(socket_ip.clone(), counter, subscription_methods)
+                            })))
+                            .layer(optional(
+                                rate_limit_builder
+                                    .as_ref()
+                                    .and_then(|r| r.concurrency_limit(socket_ip.clone())),
+                            ))
+                            .layer(optional(rate_limit_builder.as_ref().and_then(|r| {
+                                r.method_limit(socket_ip.clone(), rpc_method_weights.clone())
+                            })))
+                            .layer(optional(subscription_limit))
+                            .layer(optional(rate_limit_builder.as_ref().and_then(|r| {
+                                r.ip_limit(socket_ip.clone(), shared_method_weights.clone())
+                            })))
+                            .layer(optional(
+                                rate_limit_builder
+                                    .as_ref()
+                                    .and_then(|r| r.connection_limit(shared_method_weights.clone())),
+                            ))
+                            .layer(optional(
+                                rate_limit_builder
+                                    .as_ref()
+                                    .and_then(|r| r.quota_limit(quota_key, rpc_method_weights.clone())),
+                            ))
+                            // innermost, so the latency it charges excludes time queued in the limiters
+                            .layer(optional(
+                                rate_limit_builder.as_ref().and_then(|r| r.cost_limit(socket_ip)),
+                            ))
+                            .layer(optional(call_metrics.map(
+                                |(call_times, calls_started, calls_finished)| {
+                                    PrometheusLayer::new(protocol, call_times, calls_started, calls_finished)
+                                },
+                            )))
+                            .into_inner();
+
+                        // SSE subscriptions go through the same middlewares as WS ones
+                        if sse.is_some_and(|sse| sse.method_name(req.uri().path()).is_some()) {
+                            req.extensions_mut()
+                                .insert(SseRpc::new(rpc_layers.clone(), methods.clone()));
+                        }
+
+                        let rpc_middleware = RpcServiceBuilder::new().layer(rpc_layers);
 
                         let mut service = svc_builder
                             .set_rpc_middleware(rpc_middleware)
@@ -249,7 +500,11 @@ impl SubwayServerBuilder {
                             });
                         }
 
-                        service.call(req).await.map_err(|e| anyhow::anyhow!("{:?}", e))
+                        let mut response = service.call(req).await.map_err(|e| anyhow::anyhow!("{:?}", e))?;
+                        if let Some(feedback) = feedback {
+                            feedback.write_headers(response.headers_mut());
+                        }
+                        Ok::<_, anyhow::Error>(response)
                     }
                     .boxed()
                 });
@@ -22,6 +22,44 @@ impl Display for Protocol {
     }
 }
 
+#[derive(Clone)]
+pub struct PrometheusLayer {
+    protocol: Protocol,
+    call_times: HistogramVec,
+    calls_started: CounterVec<U64>,
+    calls_finished: CounterVec<U64>,
+}
+
+impl PrometheusLayer {
+    pub fn new(
+        protocol: Protocol,
+        call_times: HistogramVec,
+        calls_started: CounterVec<U64>,
+        calls_finished: CounterVec<U64>,
+    ) -> Self {
+        Self {
+            protocol,
+            call_times,
+            calls_started,
+            calls_finished,
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for PrometheusLayer {
+    type Service = PrometheusService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        PrometheusService::new(
+            inner,
+            self.protocol,
+            &self.call_times,
+            &self.calls_started,
+            &self.calls_finished,
+        )
+    }
+}
+
 #[derive(Clone)]
 pub struct PrometheusService<S> {
     inner: S,
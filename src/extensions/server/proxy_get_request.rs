@@ -26,6 +26,12 @@
 
 //! Middleware that proxies requests at a specified URI to internal
 //! RPC method calls.
+//!
+//! Paths may contain `{name}` segments and a query template such as
+//! `/balance/{address}?block=latest`. Captured values are passed to the
+//! RPC method as positional params: path segments first, then query
+//! params, in template order. A type can be given with `{name:type}`
+//! where type is one of `auto` (default), `string`, `u64`, `bool` or `json`.
 
 use hyper::body::Bytes;
 use hyper::header::{ACCEPT, CONTENT_TYPE};
@@ -35,13 +41,13 @@ use jsonrpsee::{
     core::{
         client::Error as RpcError,
         http_helpers::{Body as HttpBody, Request as HttpRequest, Response as HttpResponse},
-        BoxError,
+        BoxError, JsonValue,
     },
     types::{Id, RequestSer},
 };
-use std::collections::HashMap;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
 use tower::{Layer, Service};
 
@@ -51,53 +57,228 @@ pub struct ProxyGetRequestMethod {
     pub method: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParamType {
+    Auto,
+    String,
+    U64,
+    Bool,
+    Json,
+}
+
+impl ParamType {
+    fn parse(ty: &str) -> Result<Self, RpcError> {
+        match ty {
+            "auto" => Ok(Self::Auto),
+            "string" | "str" => Ok(Self::String),
+            "u64" | "number" => Ok(Self::U64),
+            "bool" => Ok(Self::Bool),
+            "json" => Ok(Self::Json),
+            _ => Err(RpcError::Custom(format!(
+                "ProxyGetRequestLayer unknown param type `{ty}`"
+            ))),
+        }
+    }
+
+    fn coerce(&self, value: &str) -> Result<JsonValue, String> {
+        match self {
+            Self::Auto => Ok(if let Ok(number) = value.parse::<u64>() {
+                number.into()
+            } else if let Ok(b) = value.parse::<bool>() {
+                b.into()
+            } else {
+                value.into()
+            }),
+            Self::String => Ok(value.into()),
+            Self::U64 => {
+                let number = match value.strip_prefix("0x") {
+                    Some(hex) => u64::from_str_radix(hex, 16),
+                    None => value.parse::<u64>(),
+                };
+                number.map(Into::into).map_err(|_| format!("`{value}` is not a number"))
+            }
+            Self::Bool => value
+                .parse::<bool>()
+                .map(Into::into)
+                .map_err(|_| format!("`{value}` is not a bool")),
+            Self::Json => serde_json::from_str(value).map_err(|_| format!("`{value}` is not valid json")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Param {
+    name: String,
+    ty: ParamType,
+}
+
+impl Param {
+    /// Parses `name` or `name:type`.
+    fn parse(spec: &str) -> Result<Self, RpcError> {
+        let (name, ty) = match spec.split_once(':') {
+            Some((name, ty)) => (name, ParamType::parse(ty)?),
+            None => (spec, ParamType::Auto),
+        };
+        if name.is_empty() {
+            return Err(RpcError::Custom("ProxyGetRequestLayer param name is empty".to_string()));
+        }
+        Ok(Self {
+            name: name.to_string(),
+            ty,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(Param),
+}
+
+#[derive(Debug, Clone)]
+struct Route {
+    method: String,
+    segments: Vec<Segment>,
+    query: Vec<(Param, Option<String>)>,
+}
+
+impl Route {
+    fn parse(template: &ProxyGetRequestMethod) -> Result<Self, RpcError> {
+        if !template.path.starts_with('/') {
+            return Err(RpcError::Custom(
+                "ProxyGetRequestLayer path must start with `/`".to_string(),
+            ));
+        }
+
+        let (path, query) = match template.path.split_once('?') {
+            Some((path, query)) => (path, query),
+            None => (template.path.as_str(), ""),
+        };
+
+        let segments = path
+            .split('/')
+            .skip(1)
+            .map(
+                |segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    Some(spec) => Param::parse(spec).map(Segment::Param),
+                    None => Ok(Segment::Literal(segment.to_string())),
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let query = query
+            .split('&')
+            .filter(|q| !q.is_empty())
+            .map(|q| match q.split_once('=') {
+                Some((spec, default)) => Ok((Param::parse(spec)?, Some(default.to_string()))),
+                None => Ok((Param::parse(q)?, None)),
+            })
+            .collect::<Result<Vec<_>, RpcError>>()?;
+
+        Ok(Self {
+            method: template.method.clone(),
+            segments,
+            query,
+        })
+    }
+
+    /// Returns `None` if the path does not match this route, otherwise the params
+    /// for the RPC call or an error if a value could not be coerced.
+    fn params(&self, path: &str, query: Option<&str>) -> Option<Result<Vec<JsonValue>, String>> {
+        let parts = path.split('/').skip(1).collect::<Vec<_>>();
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut captured = Vec::new();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(param) => captured.push((param, part.to_string())),
+            }
+        }
+
+        Some(self.coerce(captured, query))
+    }
+
+    fn coerce(&self, captured: Vec<(&Param, String)>, query: Option<&str>) -> Result<Vec<JsonValue>, String> {
+        let mut params = Vec::with_capacity(captured.len() + self.query.len());
+
+        for (param, value) in captured {
+            let value = percent_decode(&value)?;
+            params.push(param.ty.coerce(&value).map_err(|e| format!("{}: {e}", param.name))?);
+        }
+
+        let pairs = form_urlencoded::parse(query.unwrap_or_default().as_bytes()).collect::<Vec<_>>();
+        for (param, default) in &self.query {
+            let value = pairs
+                .iter()
+                .find(|(key, _)| *key == param.name)
+                .map(|(_, value)| value.to_string())
+                .or_else(|| default.clone());
+
+            match value {
+                Some(value) => params.push(param.ty.coerce(&value).map_err(|e| format!("{}: {e}", param.name))?),
+                None => params.push(JsonValue::Null),
+            }
+        }
+
+        // missing optional query params at the end are omitted
+        while params.last() == Some(&JsonValue::Null) {
+            params.pop();
+        }
+
+        Ok(params)
+    }
+}
+
+/// Decodes a path segment, unlike query strings `+` is not a space.
+fn percent_decode(value: &str) -> Result<String, String> {
+    percent_encoding::percent_decode_str(value)
+        .decode_utf8()
+        .map(|v| v.into_owned())
+        .map_err(|_| format!("invalid path segment `{value}`"))
+}
+
 #[derive(Debug, Clone)]
 pub struct ProxyGetRequestLayer {
-    methods: Vec<ProxyGetRequestMethod>,
+    routes: Arc<Vec<Route>>,
 }
 
 impl ProxyGetRequestLayer {
     pub fn new(methods: Vec<ProxyGetRequestMethod>) -> Result<Self, RpcError> {
-        for method in &methods {
-            if !method.path.starts_with('/') {
-                return Err(RpcError::Custom(
-                    "ProxyGetRequestLayer path must start with `/`".to_string(),
-                ));
-            }
-        }
+        let routes = methods.iter().map(Route::parse).collect::<Result<Vec<_>, _>>()?;
 
-        Ok(Self { methods })
+        Ok(Self {
+            routes: Arc::new(routes),
+        })
     }
 }
 impl<S> Layer<S> for ProxyGetRequestLayer {
     type Service = ProxyGetRequest<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        ProxyGetRequest::new(inner, self.methods.clone()).expect("Path already validated in ProxyGetRequestLayer; qed")
+        ProxyGetRequest {
+            inner,
+            routes: self.routes.clone(),
+        }
     }
 }
 
 #[derive(Debug, Clone)]
 pub struct ProxyGetRequest<S> {
     inner: S,
-    methods: HashMap<String, String>,
+    routes: Arc<Vec<Route>>,
 }
 
 impl<S> ProxyGetRequest<S> {
-    pub fn new(inner: S, methods: Vec<ProxyGetRequestMethod>) -> Result<Self, RpcError> {
-        let mut map = HashMap::with_capacity(methods.len());
-
-        for method in methods {
-            if !method.path.starts_with('/') {
-                return Err(RpcError::Custom(
-                    "ProxyGetRequestLayer path must start with `/`".to_string(),
-                ));
-            }
-
-            map.insert(method.path, method.method);
-        }
-
-        Ok(Self { inner, methods: map })
+    fn find_route(&self, uri: &Uri) -> Option<(&str, Result<Vec<JsonValue>, String>)> {
+        self.routes.iter().find_map(|route| {
+            route
+                .params(uri.path(), uri.query())
+                .map(|params| (route.method.as_str(), params))
+        })
     }
 }
 
@@ -121,34 +302,43 @@ where
     }
 
     fn call(&mut self, mut req: HttpRequest<B>) -> Self::Future {
-        let method = self.methods.get(req.uri().path());
-        let modify = method.is_some() && req.method() == Method::GET;
+        let route = if req.method() == Method::GET {
+            self.find_route(req.uri())
+        } else {
+            None
+        };
+        let modify = route.is_some();
 
         // Proxy the request to the appropriate method call.
-        let req = if modify {
-            // RPC methods are accessed with `POST`.
-            *req.method_mut() = Method::POST;
-            // Precautionary remove the URI.
-            *req.uri_mut() = Uri::from_static("/");
-
-            // Requests must have the following headers:
-            req.headers_mut()
-                .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-            req.headers_mut()
-                .insert(ACCEPT, HeaderValue::from_static("application/json"));
-            // Adjust the body to reflect the method call.
-            // let bytes = serde_json::to_vec(&RequestSer::borrowed(&Id::Number(0), method.unwrap(), None))
-            // .expect("Valid request; qed");
-
-            // let body = HttpBody::from(bytes);
-            // Adjust the body to reflect the method call.
-            let bytes = serde_json::to_vec(&RequestSer::borrowed(&Id::Number(0), method.unwrap(), None))
-                .expect("Valid request; qed");
-            let body = HttpBody::from(bytes);
-
-            req.map(|_| body)
-        } else {
-            req.map(HttpBody::new)
+        let req = match route {
+            Some((_, Err(err))) => {
+                return Box::pin(futures::future::ready(Ok(response::invalid_params(err))));
+            }
+            Some((method, Ok(params))) => {
+                // RPC methods are accessed with `POST`.
+                *req.method_mut() = Method::POST;
+                // Precautionary remove the URI.
+                *req.uri_mut() = Uri::from_static("/");
+
+                // Requests must have the following headers:
+                req.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                req.headers_mut()
+                    .insert(ACCEPT, HeaderValue::from_static("application/json"));
+
+                // Adjust the body to reflect the method call.
+                let params = if params.is_empty() {
+                    None
+                } else {
+                    Some(serde_json::value::to_raw_value(&params).expect("Valid params; qed"))
+                };
+                let bytes = serde_json::to_vec(&RequestSer::borrowed(&Id::Number(0), &method, params.as_deref()))
+                    .expect("Valid request; qed");
+                let body = HttpBody::from(bytes);
+
+                req.map(|_| body)
+            }
+            None => req.map(HttpBody::new),
         };
 
         // Call the inner service and get a future that resolves to the response.
@@ -216,4 +406,90 @@ mod response {
 
         from_template(hyper::StatusCode::INTERNAL_SERVER_ERROR, error, JSON)
     }
+    /// Create a response for invalid path or query params.
+    pub(crate) fn invalid_params(msg: String) -> HttpResponse {
+        let err = ResponsePayload::<()>::error(crate::utils::errors::invalid_params(msg));
+        let rp = Response::new(err, Id::Null);
+        let error = serde_json::to_string(&rp).expect("built from known-good data; qed");
+
+        from_template(hyper::StatusCode::BAD_REQUEST, error, JSON)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn parse_route(path: &str) -> Route {
+        Route::parse(&ProxyGetRequestMethod {
+            path: path.to_string(),
+            method: "test".to_string(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn fixed_path_works() {
+        let route = parse_route("/health");
+        assert_eq!(route.params("/health", None), Some(Ok(vec![])));
+        assert_eq!(route.params("/health/1", None), None);
+        assert_eq!(route.params("/other", None), None);
+    }
+
+    #[test]
+    fn path_params_are_coerced() {
+        let route = parse_route("/block/{number}");
+        assert_eq!(route.params("/block/100", None), Some(Ok(vec![json!(100)])));
+        assert_eq!(route.params("/block/0xabc", None), Some(Ok(vec![json!("0xabc")])));
+        assert_eq!(route.params("/block", None), None);
+
+        let route = parse_route("/block/{number:u64}");
+        assert_eq!(route.params("/block/0x10", None), Some(Ok(vec![json!(16)])));
+        assert!(matches!(route.params("/block/abc", None), Some(Err(_))));
+
+        let route = parse_route("/storage/{key:string}/{at:json}");
+        assert_eq!(
+            route.params("/storage/123/%5B1%2C2%5D", None),
+            Some(Ok(vec![json!("123"), json!([1, 2])]))
+        );
+
+        // path segments are not form encoded
+        let route = parse_route("/storage/{key:string}");
+        assert_eq!(route.params("/storage/a+b&c", None), Some(Ok(vec![json!("a+b&c")])));
+        assert_eq!(route.params("/storage/a%20b%26c", None), Some(Ok(vec![json!("a b&c")])));
+    }
+
+    #[test]
+    fn query_params_work() {
+        let route = parse_route("/balance/{address}?block=latest");
+        assert_eq!(
+            route.params("/balance/0x01", None),
+            Some(Ok(vec![json!("0x01"), json!("latest")]))
+        );
+        assert_eq!(
+            route.params("/balance/0x01", Some("block=100&foo=bar")),
+            Some(Ok(vec![json!("0x01"), json!(100)]))
+        );
+
+        // optional params without default are omitted when missing
+        let route = parse_route("/block/{number}?full:bool");
+        assert_eq!(route.params("/block/1", None), Some(Ok(vec![json!(1)])));
+        assert_eq!(
+            route.params("/block/1", Some("full=true")),
+            Some(Ok(vec![json!(1), json!(true)]))
+        );
+        assert!(matches!(route.params("/block/1", Some("full=yes")), Some(Err(_))));
+    }
+
+    #[test]
+    fn invalid_templates_are_rejected() {
+        for path in ["health", "/block/{number:foo}", "/block/{}"] {
+            let res = ProxyGetRequestLayer::new(vec![ProxyGetRequestMethod {
+                path: path.to_string(),
+                method: "test".to_string(),
+            }]);
+            assert!(res.is_err(), "{path} should be rejected");
+        }
+    }
 }
@@ -0,0 +1,331 @@
+//! Middleware that streams subscriptions as Server-Sent Events.
+//!
+//! `GET {path}/<subscribe method>?params=[...]` subscribes through the registered
+//! RPC module, so SSE clients go through the same subscription middlewares
+//! (e.g. `merge_subscription`) and share upstream subscriptions with WS clients.
+//! The subscribe call also goes through the RPC middlewares of the connection
+//! (subscription and rate limits, metrics), see [`SseRpc`].
+
+use futures::{future::BoxFuture, stream, FutureExt, StreamExt};
+use http_body_util::StreamBody;
+use hyper::body::{Bytes, Frame};
+use hyper::{Method, StatusCode};
+use jsonrpsee::{
+    core::{
+        http_helpers::{Body as HttpBody, Request as HttpRequest, Response as HttpResponse},
+        server::MethodsError,
+        BoxError, JsonValue,
+    },
+    server::{middleware::rpc::RpcServiceT, MethodCallback},
+    types::{
+        error::{SERVER_IS_BUSY_CODE, TOO_MANY_SUBSCRIPTIONS_CODE},
+        Id, Request, Response, ResponsePayload,
+    },
+    Extensions, MethodResponse, Methods, Subscription,
+};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tower::{Layer, Service};
+
+use crate::utils::errors;
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct SseConfig {
+    #[serde(default = "default_path")]
+    pub path: String,
+    /// Interval of the comment lines sent to keep idle connections open.
+    #[serde(default = "default_keep_alive_seconds")]
+    pub keep_alive_seconds: u64,
+}
+
+impl SseConfig {
+    /// Returns the subscribe method name targeted by a request path.
+    pub fn method_name<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(self.path.as_str())?.strip_prefix('/')
+    }
+}
+
+fn default_path() -> String {
+    "/subscribe".to_string()
+}
+
+fn default_keep_alive_seconds() -> u64 {
+    15
+}
+
+/// Notifications queued for an SSE client before the subscription waits for it,
+/// or applies the subscriptions `buffer` policy when one is configured.
+const SUBSCRIPTION_CHANNEL_SIZE: usize = 16;
+
+/// A subscription made through the RPC middlewares, with the extensions of its
+/// subscribe request, e.g. its `SubscriptionSlot`, held until the subscription ends.
+type Subscribed = Arc<Mutex<Option<(Subscription, Extensions)>>>;
+
+/// Subscribes through the RPC middlewares of the connection. The server adds it to
+/// the extensions of SSE requests.
+#[derive(Clone)]
+pub struct SseRpc {
+    service: Arc<dyn Fn(Request<'static>) -> BoxFuture<'static, MethodResponse> + Send + Sync>,
+    subscribed: Subscribed,
+}
+
+impl SseRpc {
+    pub fn new<L>(rpc_middleware: L, methods: Methods) -> Self
+    where
+        L: Layer<SseSubscribe>,
+        L::Service: RpcServiceT<'static> + Send + Sync + 'static,
+        <L::Service as RpcServiceT<'static>>::Future: 'static,
+    {
+        let subscribed = Subscribed::default();
+        let service = rpc_middleware.layer(SseSubscribe {
+            methods,
+            subscribed: subscribed.clone(),
+        });
+        Self {
+            service: Arc::new(move |req| service.call(req).boxed()),
+            subscribed,
+        }
+    }
+
+    async fn subscribe(&self, req: Request<'static>) -> (MethodResponse, Option<(Subscription, Extensions)>) {
+        let response = (self.service)(req).await;
+        let subscription = self.subscribed.lock().expect("SSE subscription lock poisoned").take();
+        (response, subscription)
+    }
+}
+
+/// Innermost RPC service of SSE requests, subscribes with a bounded channel.
+#[derive(Clone)]
+pub struct SseSubscribe {
+    methods: Methods,
+    subscribed: Subscribed,
+}
+
+impl<'a> RpcServiceT<'a> for SseSubscribe {
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, req: Request<'a>) -> Self::Future {
+        let methods = self.methods.clone();
+        let subscribed = self.subscribed.clone();
+
+        async move {
+            let params = req.params().parse::<Vec<JsonValue>>().unwrap_or_default();
+            match methods.subscribe(&req.method, params, SUBSCRIPTION_CHANNEL_SIZE).await {
+                Ok(subscription) => {
+                    let id = subscription.subscription_id().clone().into_owned();
+                    *subscribed.lock().expect("SSE subscription lock poisoned") =
+                        Some((subscription, req.extensions.clone()));
+                    MethodResponse::subscription_response(req.id, jsonrpsee::ResponsePayload::success(id), usize::MAX)
+                }
+                Err(MethodsError::JsonRpc(err)) => MethodResponse::subscription_error(req.id, err),
+                Err(err) => MethodResponse::subscription_error(req.id, errors::failed(err)),
+            }
+        }
+        .boxed()
+    }
+}
+
+#[derive(Clone)]
+pub struct SseLayer {
+    config: SseConfig,
+    methods: Methods,
+}
+
+impl SseLayer {
+    pub fn new(config: SseConfig, methods: Methods) -> anyhow::Result<Self> {
+        if !config.path.starts_with('/') || config.path.ends_with('/') {
+            anyhow::bail!(
+                "sse path must start with `/` and must not end with `/`: {}",
+                config.path
+            );
+        }
+        if config.keep_alive_seconds == 0 {
+            anyhow::bail!("sse keep_alive_seconds must be greater than 0");
+        }
+
+        Ok(Self { config, methods })
+    }
+}
+
+impl<S> Layer<S> for SseLayer {
+    type Service = Sse<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Sse {
+            inner,
+            config: self.config.clone(),
+            methods: self.methods.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Sse<S> {
+    inner: S,
+    config: SseConfig,
+    methods: Methods,
+}
+
+impl<S> Sse<S> {
+    /// Returns the subscribe method name if the request targets a registered subscription.
+    fn subscription_name<B>(&self, req: &HttpRequest<B>) -> Option<String> {
+        if req.method() != Method::GET {
+            return None;
+        }
+
+        let name = self.config.method_name(req.uri().path())?;
+
+        match self.methods.method(name) {
+            Some(MethodCallback::Subscription(_)) => Some(name.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl<S, B> Service<HttpRequest<B>> for Sse<S>
+where
+    S: Service<HttpRequest<B>, Response = HttpResponse>,
+    S::Error: Into<BoxError> + 'static,
+    S::Future: Send + 'static,
+    B: http_body::Body<Data = Bytes> + Send + 'static,
+{
+    type Response = S::Response;
+    type Error = BoxError;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send + 'static>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, req: HttpRequest<B>) -> Self::Future {
+        let Some(name) = self.subscription_name(&req) else {
+            let fut = self.inner.call(req);
+            return Box::pin(async move { fut.await.map_err(Into::into) });
+        };
+
+        let params = match parse_params(req.uri().query()) {
+            Ok(params) => params,
+            Err(err) => return Box::pin(futures::future::ready(Ok(error_response(StatusCode::BAD_REQUEST, err)))),
+        };
+
+        let Some(rpc) = req.extensions().get::<SseRpc>().cloned() else {
+            tracing::error!("SSE request {name} without RPC middlewares");
+            return Box::pin(futures::future::ready(Ok(error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                errors::internal_error("SSE is unavailable"),
+            ))));
+        };
+
+        // the request extensions reach the RPC middlewares, like for JSON-RPC over HTTP
+        let mut extensions = req.extensions().clone();
+        extensions.remove::<SseRpc>();
+        let mut request = Request::new(name.clone().into(), None, Id::Number(0));
+        request.params = Some(Cow::Owned(
+            serde_json::value::to_raw_value(&params).expect("JSON values serialize; qed"),
+        ));
+        request.extensions = extensions;
+
+        let keep_alive = Duration::from_secs(self.config.keep_alive_seconds);
+
+        Box::pin(async move {
+            let (response, subscription) = rpc.subscribe(request).await;
+            let Some((subscription, extensions)) = subscription else {
+                tracing::debug!("SSE subscribe {name} failed: {}", response.as_result());
+                let status = match response.as_error_code() {
+                    Some(SERVER_IS_BUSY_CODE | TOO_MANY_SUBSCRIPTIONS_CODE) => StatusCode::TOO_MANY_REQUESTS,
+                    _ => StatusCode::BAD_GATEWAY,
+                };
+                return Ok(json_response(status, response.into_result()));
+            };
+
+            let mut interval = tokio::time::interval(keep_alive);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            interval.reset();
+
+            let events = stream::unfold(
+                (subscription, interval, extensions),
+                |(mut subscription, mut interval, extensions)| async move {
+                    let event = tokio::select! {
+                        msg = subscription.next::<JsonValue>() => match msg {
+                            Some(Ok((value, _))) => format!("data: {value}\n\n"),
+                            Some(Err(err)) => {
+                                tracing::debug!("SSE subscription error: {err}");
+                                return None;
+                            }
+                            // subscription closed by the server
+                            None => return None,
+                        },
+                        _ = interval.tick() => ": keep-alive\n\n".to_string(),
+                    };
+                    Some((
+                        Ok::<_, BoxError>(Frame::data(Bytes::from(event))),
+                        (subscription, interval, extensions),
+                    ))
+                },
+            );
+
+            let response = HttpResponse::builder()
+                .status(StatusCode::OK)
+                .header(hyper::header::CONTENT_TYPE, "text/event-stream")
+                .header(hyper::header::CACHE_CONTROL, "no-cache")
+                .body(HttpBody::new(StreamBody::new(events.boxed())))
+                .expect("Unable to build SSE response");
+
+            Ok(response)
+        })
+    }
+}
+
+/// Parses the `params` query value as a JSON array.
+fn parse_params(query: Option<&str>) -> Result<Vec<JsonValue>, jsonrpsee::types::ErrorObjectOwned> {
+    let Some((_, params)) =
+        form_urlencoded::parse(query.unwrap_or_default().as_bytes()).find(|(key, _)| key == "params")
+    else {
+        return Ok(vec![]);
+    };
+
+    match serde_json::from_str::<JsonValue>(&params) {
+        Ok(JsonValue::Array(params)) => Ok(params),
+        Ok(JsonValue::Null) => Ok(vec![]),
+        Ok(param) => Ok(vec![param]),
+        Err(err) => Err(errors::invalid_params(err)),
+    }
+}
+
+fn error_response(status: StatusCode, err: jsonrpsee::types::ErrorObjectOwned) -> HttpResponse {
+    let rp = Response::new(ResponsePayload::<()>::error(err), Id::Null);
+    let body = serde_json::to_string(&rp).expect("built from known-good data; qed");
+    json_response(status, body)
+}
+
+fn json_response(status: StatusCode, body: String) -> HttpResponse {
+    HttpResponse::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json; charset=utf-8")
+        .body(HttpBody::from(body))
+        .expect("Unable to build SSE error response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parse_params_works() {
+        assert_eq!(parse_params(None).unwrap(), Vec::<JsonValue>::new());
+        assert_eq!(parse_params(Some("foo=bar")).unwrap(), Vec::<JsonValue>::new());
+        assert_eq!(
+            parse_params(Some("params=%5B%220x01%22%2C1%5D")).unwrap(),
+            vec![json!("0x01"), json!(1)]
+        );
+        assert_eq!(parse_params(Some("params=1")).unwrap(), vec![json!(1)]);
+        assert!(parse_params(Some("params=%5B")).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+use futures::{future::BoxFuture, FutureExt};
+use jsonrpsee::{
+    server::{middleware::rpc::RpcServiceT, types::Request},
+    types::error::reject_too_many_subscriptions,
+    MethodResponse,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+/// Names of the subscribe methods registered on the server.
+#[derive(Clone, Default)]
+pub struct SubscriptionMethods {
+    subscribe: Arc<HashSet<String>>,
+}
+
+impl SubscriptionMethods {
+    pub fn new(subscribe: HashSet<String>) -> Self {
+        Self {
+            subscribe: Arc::new(subscribe),
+        }
+    }
+
+    pub fn subscribe(&self) -> Arc<HashSet<String>> {
+        self.subscribe.clone()
+    }
+}
+
+/// Number of active subscriptions per IP, shared by all connections.
+#[derive(Clone)]
+pub struct IpSubscriptionCounter {
+    limit: u32,
+    counts: Arc<Mutex<HashMap<String, u32>>>,
+}
+
+impl IpSubscriptionCounter {
+    pub fn new(limit: u32) -> Self {
+        Self {
+            limit,
+            counts: Default::default(),
+        }
+    }
+
+    fn try_acquire(&self, ip: &str) -> Option<SubscriptionSlot> {
+        let mut counts = self.counts.lock().expect("subscription counter lock poisoned");
+        let count = counts.entry(ip.to_string()).or_default();
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(SubscriptionSlot(Arc::new(Slot {
+            ip: ip.to_string(),
+            counter: self.clone(),
+        })))
+    }
+
+    fn release(&self, ip: &str) {
+        let mut counts = self.counts.lock().expect("subscription counter lock poisoned");
+        if let Some(count) = counts.get_mut(ip) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                counts.remove(ip);
+            }
+        }
+    }
+
+    #[cfg(test)]
+    fn count(&self, ip: &str) -> u32 {
+        self.counts.lock().unwrap().get(ip).cloned().unwrap_or_default()
+    }
+}
+
+struct Slot {
+    ip: String,
+    counter: IpSubscriptionCounter,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.counter.release(&self.ip);
+    }
+}
+
+/// A subscription counted towards the limit of its ip, added to the extensions of
+/// the subscribe request. The subscription holds it until it ends, however it ends:
+/// unsubscribed, closed by the server or the upstream, or on disconnect.
+#[derive(Clone)]
+pub struct SubscriptionSlot(#[allow(dead_code)] Arc<Slot>);
+
+#[derive(Clone)]
+pub struct IpSubscriptionLimitLayer {
+    ip_addr: String,
+    counter: IpSubscriptionCounter,
+    methods: SubscriptionMethods,
+}
+
+impl IpSubscriptionLimitLayer {
+    pub fn new(ip_addr: String, counter: IpSubscriptionCounter, methods: SubscriptionMethods) -> Self {
+        Self {
+            ip_addr,
+            counter,
+            methods,
+        }
+    }
+}
+
+impl<S> tower::Layer<S> for IpSubscriptionLimitLayer {
+    type Service = IpSubscriptionLimit<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        IpSubscriptionLimit {
+            service,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct IpSubscriptionLimit<S> {
+    service: S,
+    layer: IpSubscriptionLimitLayer,
+}
+
+impl<'a, S> RpcServiceT<'a> for IpSubscriptionLimit<S>
+where
+    S: RpcServiceT<'a> + Send + Sync + Clone + 'static,
+{
+    type Future = BoxFuture<'a, MethodResponse>;
+
+    fn call(&self, mut req: Request<'a>) -> Self::Future {
+        let service = self.service.clone();
+
+        if self.layer.methods.subscribe.contains(req.method_name()) {
+            let Some(slot) = self.layer.counter.try_acquire(&self.layer.ip_addr) else {
+                let limit = self.layer.counter.limit;
+                return async move { MethodResponse::error(req.id, reject_too_many_subscriptions(limit)) }.boxed();
+            };
+            // released once every copy of the extensions is dropped, i.e. when the
+            // subscription ends, or right away if it is rejected
+            req.extensions_mut().insert(slot);
+        }
+
+        async move { service.call(req).await }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonrpsee::types::Id;
+    use jsonrpsee::ResponsePayload;
+
+    /// Keeps the extensions of accepted subscriptions, like the subscription tasks do.
+    #[derive(Clone, Default)]
+    struct MockService {
+        active: Arc<Mutex<Vec<jsonrpsee::Extensions>>>,
+    }
+
+    impl RpcServiceT<'static> for MockService {
+        type Future = BoxFuture<'static, MethodResponse>;
+
+        fn call(&self, req: Request<'static>) -> Self::Future {
+            let active = self.active.clone();
+            async move {
+                match req.method_name() {
+                    "sub" => {
+                        active.lock().unwrap().push(req.extensions().clone());
+                        MethodResponse::response(req.id, ResponsePayload::success("0x01"), 1024)
+                    }
+                    "rejected" => MethodResponse::error(req.id, reject_too_many_subscriptions(0)),
+                    _ => MethodResponse::response(req.id, ResponsePayload::success("ok"), 1024),
+                }
+            }
+            .boxed()
+        }
+    }
+
+    fn call(service: &IpSubscriptionLimit<MockService>, method: &'static str) -> BoxFuture<'static, MethodResponse> {
+        service.call(Request::new(method.into(), None, Id::Number(1)))
+    }
+
+    #[tokio::test]
+    async fn limits_subscriptions_per_ip() {
+        let counter = IpSubscriptionCounter::new(2);
+        let methods = SubscriptionMethods::new(["sub".to_string(), "rejected".to_string()].into());
+        let layer = |ip: &str| IpSubscriptionLimitLayer::new(ip.to_string(), counter.clone(), methods.clone());
+
+        let (service1, service2, other_service) =
+            (MockService::default(), MockService::default(), MockService::default());
+        let conn1 = tower::Layer::layer(&layer("1.1.1.1"), service1.clone());
+        let conn2 = tower::Layer::layer(&layer("1.1.1.1"), service2.clone());
+        let other = tower::Layer::layer(&layer("2.2.2.2"), other_service);
+
+        // rejected subscriptions are not counted
+        assert!(call(&conn1, "rejected").await.is_error());
+        assert_eq!(counter.count("1.1.1.1"), 0);
+
+        assert!(call(&conn1, "sub").await.is_success());
+        assert!(call(&conn2, "sub").await.is_success());
+        // limit reached for this ip
+        assert!(call(&conn1, "sub").await.is_error());
+        assert!(call(&conn2, "sub").await.is_error());
+        // other methods and other ips are not affected
+        assert!(call(&conn1, "foo").await.is_success());
+        assert!(call(&other, "sub").await.is_success());
+
+        // a subscription ending, however it ends, frees a slot
+        service1.active.lock().unwrap().clear();
+        assert_eq!(counter.count("1.1.1.1"), 1);
+        assert!(call(&conn1, "sub").await.is_success());
+        assert_eq!(counter.count("1.1.1.1"), 2);
+
+        service1.active.lock().unwrap().clear();
+        service2.active.lock().unwrap().clear();
+        assert_eq!(counter.count("1.1.1.1"), 0);
+        assert_eq!(counter.count("2.2.2.2"), 1);
+    }
+}
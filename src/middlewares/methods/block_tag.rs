@@ -9,7 +9,7 @@ use crate::{
     utils::{TypeRegistry, TypeRegistryRef},
 };
 
-use super::cache::BypassCache;
+use super::cache::{BypassCache, CacheTag};
 
 pub struct BlockTagMiddleware {
     api: Arc<EthApi>,
@@ -58,9 +58,9 @@ impl BlockTagMiddleware {
                         }
                     }
                     "latest" => {
-                        // bypass cache for latest block to avoid caching forks
-                        context.insert(BypassCache(true));
-                        let (_, number) = self.api.get_head().read().await;
+                        // tag the head so the cached response is evicted if it is orphaned
+                        let (hash, number) = self.api.get_head().read().await;
+                        context.insert(CacheTag { hash, number });
                         Some(format!("0x{:x}", number).into())
                     }
                     "earliest" => None, // no need to replace earliest because it's always going to be genesis
@@ -69,19 +69,19 @@ impl BlockTagMiddleware {
                         None
                     }
                     number => {
-                        // bypass cache for block number to avoid caching forks unless it's a finalized block
-                        let mut bypass_cache = true;
-                        if let Some((_, finalized_number)) = self.api.current_finalized_head() {
-                            if let Some(hex_number) = number.strip_prefix("0x") {
-                                if let Ok(number) = u64::from_str_radix(hex_number, 16) {
-                                    if number <= finalized_number {
-                                        bypass_cache = false;
-                                    }
-                                }
-                            }
-                        }
-                        if bypass_cache {
-                            context.insert(BypassCache(true));
+                        let number = number
+                            .strip_prefix("0x")
+                            .and_then(|hex_number| u64::from_str_radix(hex_number, 16).ok());
+                        let finalized = self.api.current_finalized_head().map(|(_, number)| number);
+                        match (number, finalized) {
+                            (Some(number), Some(finalized)) if number <= finalized => {}
+                            // tag unfinalized blocks so cached responses are evicted if they are orphaned,
+                            // bypass cache if the block is unknown to avoid caching forks
+                            (Some(number), _) => match self.api.head_hash(number) {
+                                Some(hash) => context.insert(CacheTag { hash, number }),
+                                None => context.insert(BypassCache(true)),
+                            },
+                            (None, _) => context.insert(BypassCache(true)),
                         }
                         None
                     }
@@ -156,6 +156,10 @@ mod tests {
         context.get::<BypassCache>().map_or(false, |x| x.0)
     }
 
+    fn cache_tag(context: &TypeRegistry) -> Option<(JsonValue, u64)> {
+        context.get::<CacheTag>().map(|tag| (tag.hash.clone(), tag.number))
+    }
+
     async fn create_client() -> (ExecutionContext, EthApi) {
         let mut builder = TestServerBuilder::new();
 
@@ -209,6 +213,8 @@ mod tests {
         context
             .send_current_block(json!({ "number": "0x4321", "hash": "0x00" }))
             .await;
+        // wait for the head to be recorded
+        tokio::time::sleep(Duration::from_millis(10)).await;
 
         assert_eq!(
             middleware
@@ -217,8 +223,9 @@ mod tests {
                     Default::default(),
                     Box::new(move |req: CallRequest, context| {
                         async move {
-                            // cache bypassed, cannot determine finalized block
-                            assert!(bypass_cache(&context));
+                            // cannot determine finalized block, tagged with the head at that number
+                            assert!(!bypass_cache(&context));
+                            assert_eq!(cache_tag(&context), Some((json!("0x00"), 0x4321)));
                             // no replacement
                             assert_eq!(req.params, params);
                             Ok(json!("0x1111"))
@@ -277,8 +284,9 @@ mod tests {
                     Default::default(),
                     Box::new(move |req: CallRequest, context| {
                         async move {
-                            // cache bypassed for latest
-                            assert!(bypass_cache(&context));
+                            // latest is tagged with the head
+                            assert!(!bypass_cache(&context));
+                            assert_eq!(cache_tag(&context), Some((json!("0x01"), 0x4321)));
                             // latest block replaced with block number
                             assert_eq!(req.params, vec![json!("0x1234"), json!("0x4321")]);
                             Ok(json!("0x1111"))
@@ -324,6 +332,7 @@ mod tests {
                         async move {
                             // cache not bypassed, finalized replaced with block number
                             assert!(!bypass_cache(&context));
+                            assert_eq!(cache_tag(&context), None);
                             // block tag replaced with block number
                             assert_eq!(req.params, vec![json!("0x1234"), json!("0x4321")]);
                             Ok(json!("0x1111"))
@@ -343,8 +352,9 @@ mod tests {
                     Default::default(),
                     Box::new(move |req: CallRequest, context| {
                         async move {
-                            // cache bypassed for latest
-                            assert!(bypass_cache(&context));
+                            // latest is tagged with the head
+                            assert!(!bypass_cache(&context));
+                            assert_eq!(cache_tag(&context), Some((json!("0x02"), 0x5432)));
                             // latest block replaced with block number
                             assert_eq!(req.params, vec![json!("0x1234"), json!("0x5432")]);
                             Ok(json!("0x1111"))
@@ -1,29 +1,238 @@
-use std::num::NonZeroUsize;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use async_trait::async_trait;
 use blake2::Blake2b512;
-use futures::FutureExt as _;
+use futures::{future::BoxFuture, FutureExt as _};
+use jsonrpsee::{
+    core::JsonValue,
+    types::error::{INTERNAL_ERROR_CODE, SERVER_IS_BUSY_CODE},
+};
 use opentelemetry::trace::FutureExt;
+use tokio::sync::broadcast;
 
 use crate::extensions::prometheus::{get_rpc_metrics, RpcMetrics};
 use crate::{
-    config::CacheParams,
-    extensions::cache::Cache as CacheExtension,
+    config::{CacheParams, MethodParam},
+    extensions::{
+        api::{EthApi, SubstrateApi},
+        cache::Cache as CacheExtension,
+    },
     middlewares::{CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER},
-    utils::{Cache, CacheKey, TypeRegistry, TypeRegistryRef},
+    utils::{Cache, CacheKey, Capacity, Persist, SharedLimits, TypeRegistry, TypeRegistryRef},
 };
 
 pub struct BypassCache(pub bool);
 
+/// The unfinalized block a request depends on. The cached response is evicted
+/// once the block is orphaned by a reorg.
+pub struct CacheTag {
+    pub hash: JsonValue,
+    pub number: u64,
+}
+
+/// Number of block heights tagged entries are tracked for, deeper reorgs are
+/// left to the ttl.
+const TAGGED_BLOCKS: usize = 256;
+
+#[derive(Default)]
+struct Tagged {
+    keys: HashSet<CacheKey<Blake2b512>>,
+    orphaned: bool,
+}
+
+/// Tagged entries by block number and hash.
+type Tags = Arc<Mutex<BTreeMap<u64, HashMap<String, Tagged>>>>;
+
+/// Errors that may not happen when retried, such as transport errors, are
+/// never cached.
+const TRANSIENT_ERROR_CODES: [i32; 2] = [INTERNAL_ERROR_CODE, SERVER_IS_BUSY_CODE];
+
+/// Most entries kept by caches tracking the entries of a cache of `capacity`.
+fn max_entries(capacity: Capacity) -> u64 {
+    match capacity {
+        Capacity::Entries(size) => size.get() as u64,
+        Capacity::Bytes(bytes) => bytes.get(),
+    }
+}
+
+/// Tracks which cached values are still fresh, stale ones are served while
+/// being refreshed in the background.
+struct Revalidate {
+    fresh: moka::future::Cache<CacheKey<Blake2b512>, ()>,
+    refreshing: Arc<Mutex<HashSet<CacheKey<Blake2b512>>>>,
+}
+
+impl Revalidate {
+    fn new(capacity: Capacity, ttl: Duration) -> Self {
+        // at most one marker per cached entry
+        Self {
+            fresh: moka::future::Cache::builder()
+                .max_capacity(max_entries(capacity))
+                .time_to_live(ttl)
+                .build(),
+            refreshing: Default::default(),
+        }
+    }
+
+    /// Refreshes the value in the background, unless a refresh is already running.
+    fn refresh(&self, cache: Cache<Blake2b512>, key: CacheKey<Blake2b512>, fetch: BoxFuture<'static, CallResult>) {
+        if !self
+            .refreshing
+            .lock()
+            .expect("cache refreshing lock poisoned")
+            .insert(key.clone())
+        {
+            return;
+        }
+        let fresh = self.fresh.clone();
+        let refreshing = self.refreshing.clone();
+        tokio::spawn(async move {
+            match fetch.await {
+                // keep the stale value until a new one is available
+                Ok(value) if !value.is_null() => {
+                    cache.insert(key.clone(), value).await;
+                    fresh.insert(key.clone(), ()).await;
+                }
+                Ok(_) => {}
+                Err(err) => tracing::debug!("Failed to refresh cached value: {err}"),
+            }
+            refreshing.lock().expect("cache refreshing lock poisoned").remove(&key);
+        });
+    }
+}
+
+/// Errors and null results, which are not kept in the cache, kept for a short ttl.
+struct NegativeCache {
+    results: moka::future::Cache<CacheKey<Blake2b512>, CallResult>,
+    error_codes: HashSet<i32>,
+    null: bool,
+}
+
+impl NegativeCache {
+    fn accepts(&self, result: &CallResult, tagged: bool) -> bool {
+        match result {
+            // null may be returned for unfinalized blocks that are not known yet
+            Ok(value) => self.null && value.is_null() && !tagged,
+            Err(err) => self.error_codes.contains(&err.code()) && !TRANSIENT_ERROR_CODES.contains(&err.code()),
+        }
+    }
+}
+
 pub struct CacheMiddleware {
     cache: Cache<Blake2b512>,
     metrics: RpcMetrics,
+    tags: Option<Tags>,
+    revalidate: Option<Revalidate>,
+    negative: Option<NegativeCache>,
+    params: Vec<MethodParam>,
 }
 
 impl CacheMiddleware {
     pub fn new(cache: Cache<Blake2b512>, metrics: RpcMetrics) -> Self {
-        Self { cache, metrics }
+        Self {
+            cache,
+            metrics,
+            tags: None,
+            revalidate: None,
+            negative: None,
+            params: Vec::new(),
+        }
+    }
+
+    /// Canonicalises request params by their type before computing cache keys.
+    pub fn with_params(mut self, params: Vec<MethodParam>) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Values older than `ttl` are still served, and refreshed in the background.
+    /// They are dropped once they reach the cache's own ttl.
+    pub fn with_revalidate(mut self, capacity: Capacity, ttl: Duration) -> Self {
+        self.revalidate = Some(Revalidate::new(capacity, ttl));
+        self
+    }
+
+    /// Caches errors with one of `error_codes`, and null results if `null` is set,
+    /// for `ttl`.
+    pub fn with_negative(
+        mut self,
+        capacity: Capacity,
+        ttl: Duration,
+        error_codes: impl IntoIterator<Item = i32>,
+        null: bool,
+    ) -> Self {
+        self.negative = Some(NegativeCache {
+            results: moka::future::Cache::builder()
+                .max_capacity(max_entries(capacity))
+                .time_to_live(ttl)
+                .build(),
+            error_codes: error_codes.into_iter().collect(),
+            null,
+        });
+        self
+    }
+
+    /// Caches responses of tagged requests, evicting them when their block is orphaned.
+    /// Without it tagged requests bypass the cache.
+    pub fn with_orphaned_heads(mut self, mut orphaned: broadcast::Receiver<(JsonValue, u64)>) -> Self {
+        let tags = Tags::default();
+        let cache = self.cache.clone();
+        let weak_tags = Arc::downgrade(&tags);
+        tokio::spawn(async move {
+            loop {
+                let head = orphaned.recv().await;
+                let Some(tags) = weak_tags.upgrade() else {
+                    break;
+                };
+                let keys = match head {
+                    Ok((hash, number)) => {
+                        let mut tags = tags.lock().expect("cache tags lock poisoned");
+                        let tagged = tags.entry(number).or_default().entry(hash.to_string()).or_default();
+                        tagged.orphaned = true;
+                        std::mem::take(&mut tagged.keys)
+                    }
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!("Missed {skipped} orphaned heads, evicting all unfinalized entries");
+                        let mut tags = tags.lock().expect("cache tags lock poisoned");
+                        std::mem::take(&mut *tags)
+                            .into_values()
+                            .flat_map(|hashes| hashes.into_values())
+                            .flat_map(|tagged| tagged.keys)
+                            .collect()
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                for key in keys {
+                    cache.remove(&key).await;
+                }
+            }
+        });
+        self.tags = Some(tags);
+        self
+    }
+}
+
+/// Remembers the block a cached entry depends on, returns false if it is already orphaned.
+fn tag(tags: &Tags, key: &CacheKey<Blake2b512>, tag: &CacheTag) -> bool {
+    let mut tags = tags.lock().expect("cache tags lock poisoned");
+    let tagged = tags
+        .entry(tag.number)
+        .or_default()
+        .entry(tag.hash.to_string())
+        .or_default();
+    if tagged.orphaned {
+        return false;
+    }
+    tagged.keys.insert(key.clone());
+    while tags.len() > TAGGED_BLOCKS {
+        tags.pop_first();
     }
+    true
 }
 
 #[async_trait]
@@ -40,11 +249,17 @@ impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for CacheMiddleware {
 
         let metrics = get_rpc_metrics(extensions).await;
 
-        // do not cache if size is 0, otherwise use default size
-        let size = match method.cache {
-            Some(CacheParams { size: Some(0), .. }) => return None,
-            Some(CacheParams { size, .. }) => size.unwrap_or(cache_ext.config.default_size),
-            None => cache_ext.config.default_size,
+        // do not cache if size is 0, otherwise use the method's bound, then the default ones
+        let capacity = match method.cache {
+            Some(CacheParams { size: Some(0), .. } | CacheParams { max_bytes: Some(0), .. }) => return None,
+            Some(CacheParams {
+                max_bytes: Some(bytes), ..
+            }) => Capacity::Bytes(NonZeroU64::new(bytes)?),
+            Some(CacheParams { size: Some(size), .. }) => Capacity::Entries(NonZeroUsize::new(size)?),
+            _ => match cache_ext.config.default_max_bytes {
+                Some(bytes) => Capacity::Bytes(NonZeroU64::new(bytes)?),
+                None => Capacity::Entries(NonZeroUsize::new(cache_ext.config.default_size)?),
+            },
         };
 
         let ttl_seconds = match method.cache {
@@ -56,15 +271,175 @@ impl MiddlewareBuilder<RpcMethod, CallRequest, CallResult> for CacheMiddleware {
             None => cache_ext.config.default_ttl_seconds,
         };
 
-        let cache = Cache::new(
-            NonZeroUsize::new(size)?,
-            ttl_seconds.map(std::time::Duration::from_secs),
-        );
+        // checked against ttl_seconds by config::validate
+        let max_age_seconds = method.cache.as_ref().and_then(|cache| cache.max_age_seconds);
+
+        let persist = match method.cache {
+            Some(CacheParams { persist: true, .. }) => finalized_persist(extensions).await,
+            _ => None,
+        };
+
+        let limits = match &method.cache {
+            Some(params) => SharedLimits {
+                weight: NonZeroU32::new(params.weight.unwrap_or(1))
+                    .unwrap_or_else(|| panic!("Cache weight of {} must not be 0", method.method)),
+                min_bytes: params.min_bytes,
+                max_bytes: params.max_bytes,
+            },
+            None => SharedLimits::default(),
+        };
+
+        let cache = Cache::with_backend(cache_ext.backend(
+            &method.method,
+            capacity,
+            limits,
+            max_age_seconds.or(ttl_seconds).map(Duration::from_secs),
+            persist,
+        ));
+
+        cache_ext.register(&method.method, cache.clone(), method.params.clone());
+
+        let middleware = Self::new(cache, metrics).with_params(method.params.clone());
+        let middleware = match (max_age_seconds, ttl_seconds) {
+            (Some(_), Some(ttl_seconds)) => middleware.with_revalidate(capacity, Duration::from_secs(ttl_seconds)),
+            _ => middleware,
+        };
+        let middleware = match method.cache.as_ref().and_then(|cache| cache.negative.as_ref()) {
+            Some(negative) => {
+                assert!(
+                    negative.ttl_seconds > 0,
+                    "Cache negative ttl_seconds of {} must not be 0",
+                    method.method
+                );
+                if let Some(code) = negative
+                    .error_codes
+                    .iter()
+                    .find(|code| TRANSIENT_ERROR_CODES.contains(code))
+                {
+                    panic!(
+                        "Cache negative error_codes of {} must not include {code}, it may be a transient error",
+                        method.method
+                    );
+                }
+                middleware.with_negative(
+                    capacity,
+                    Duration::from_secs(negative.ttl_seconds),
+                    negative.error_codes.iter().copied(),
+                    negative.null,
+                )
+            }
+            None => middleware,
+        };
+        let middleware = match orphaned_heads(extensions).await {
+            Some(orphaned) => middleware.with_orphaned_heads(orphaned),
+            None => middleware,
+        };
+
+        Some(Box::new(middleware))
+    }
+}
+
+async fn orphaned_heads(extensions: &TypeRegistryRef) -> Option<broadcast::Receiver<(JsonValue, u64)>> {
+    let extensions = extensions.read().await;
+    if let Some(api) = extensions.get::<EthApi>() {
+        Some(api.subscribe_orphaned_heads())
+    } else {
+        extensions
+            .get::<SubstrateApi>()
+            .map(|api| api.subscribe_orphaned_heads())
+    }
+}
+
+/// Accepts responses of blocks that are finalized, and thus will never change.
+async fn finalized_persist(extensions: &TypeRegistryRef) -> Option<Persist> {
+    let extensions = extensions.read().await;
+    let finalized: Arc<dyn Fn() -> Option<u64> + Send + Sync> = if let Some(api) = extensions.get::<EthApi>() {
+        Arc::new(move || api.current_finalized_head().map(|(_, number)| number))
+    } else if let Some(api) = extensions.get::<SubstrateApi>() {
+        Arc::new(move || api.current_finalized_head().map(|(_, number)| number))
+    } else {
+        tracing::warn!("Cache persist needs the eth or substrate api extension to know the finalized head");
+        return None;
+    };
+
+    Some(Arc::new(move |value| match (block_number(value), finalized()) {
+        (Some(number), Some(finalized)) => number <= finalized,
+        _ => false,
+    }))
+}
+
+/// The number of the block a block, header, receipt or transaction belongs to.
+fn block_number(value: &JsonValue) -> Option<u64> {
+    let number = value
+        .get("blockNumber")
+        .or_else(|| value.get("number"))
+        .or_else(|| value.pointer("/block/header/number"))?;
+    u64::from_str_radix(number.as_str()?.trim_start_matches("0x"), 16).ok()
+}
+
+/// Params formatted consistently so equivalent requests share cache entries.
+/// Hex values are lowercased, block numbers normalised, object keys sorted and
+/// trailing nulls dropped.
+pub fn canonical_params(params: &[MethodParam], values: &[JsonValue]) -> Vec<JsonValue> {
+    let mut values: Vec<JsonValue> = values
+        .iter()
+        .enumerate()
+        .map(|(idx, value)| match params.get(idx).map(|p| p.ty.as_str()) {
+            Some("BlockNumber") => match hex_number(value) {
+                Some(number) => number.into(),
+                None => canonical_value(value),
+            },
+            Some("BlockTag" | "HexNumber") => match hex_number(value) {
+                Some(number) => format!("0x{number:x}").into(),
+                None => canonical_value(value),
+            },
+            Some("Boolean" | "String" | "u32") => value.clone(),
+            _ => canonical_value(value),
+        })
+        .collect();
+    while values.last().map_or(false, JsonValue::is_null) {
+        values.pop();
+    }
+    values
+}
 
-        Some(Box::new(Self::new(cache, metrics)))
+/// A block number given as a number, or as a hex or decimal string.
+fn hex_number(value: &JsonValue) -> Option<u64> {
+    match value {
+        JsonValue::Number(number) => number.as_u64(),
+        JsonValue::String(s) => match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        },
+        _ => None,
     }
 }
 
+/// Lowercases hex strings and sorts object keys, recursively.
+fn canonical_value(value: &JsonValue) -> JsonValue {
+    match value {
+        JsonValue::String(s) if is_hex(s) => s.to_lowercase().into(),
+        JsonValue::Array(values) => values.iter().map(canonical_value).collect(),
+        JsonValue::Object(map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            JsonValue::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), canonical_value(v)))
+                    .collect(),
+            )
+        }
+        _ => value.clone(),
+    }
+}
+
+fn is_hex(s: &str) -> bool {
+    s.strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .map_or(false, |hex| hex.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
 #[async_trait]
 impl Middleware<CallRequest, CallResult> for CacheMiddleware {
     async fn call(
@@ -75,32 +450,75 @@ impl Middleware<CallRequest, CallResult> for CacheMiddleware {
     ) -> CallResult {
         async move {
             let bypass_cache = context.get::<BypassCache>().map(|v| v.0).unwrap_or(false);
-            if bypass_cache {
+            // without reorg notifications unfinalized data cannot be cached safely
+            let untracked = context.get::<CacheTag>().is_some() && self.tags.is_none();
+            if bypass_cache || untracked {
                 return next(request, context).await;
             }
 
             let metrics = self.metrics.clone();
-            let key = CacheKey::<Blake2b512>::new(&request.method, &request.params);
+            let key = CacheKey::<Blake2b512>::new(&request.method, &canonical_params(&self.params, &request.params));
+            let tag = context.get::<CacheTag>();
 
             let method = request.method.to_string();
             metrics.cache_query(&method);
+            self.cache.counters().query();
+
+            if let Some(negative) = &self.negative {
+                if let Some(result) = negative.results.get(&key).await {
+                    return result;
+                }
+            }
+
+            // unfinalized data is evicted on reorgs instead
+            if let (Some(revalidate), None) = (&self.revalidate, &tag) {
+                if let Some(value) = self.cache.get(&key).await {
+                    if !revalidate.fresh.contains_key(&key) {
+                        let cache = self.cache.clone();
+                        // only polled if the refresh is started
+                        let fetch = async move {
+                            metrics.cache_miss(&method);
+                            cache.counters().miss();
+                            next(request, context).await
+                        }
+                        .boxed();
+                        revalidate.refresh(self.cache.clone(), key, fetch);
+                    }
+                    return Ok(value);
+                }
+            }
 
+            let cache = self.cache.clone();
             let result = self
                 .cache
                 .get_or_insert_with(key.clone(), || {
                     async move {
                         metrics.cache_miss(&method);
+                        cache.counters().miss();
                         next(request, context).await
                     }
                     .boxed()
                 })
                 .await;
 
+            if let Some(negative) = &self.negative {
+                if negative.accepts(&result, tag.is_some()) {
+                    negative.results.insert(key.clone(), result.clone()).await;
+                }
+            }
+
             if let Ok(ref value) = result {
                 // avoid caching null value because it usually means data not available
                 // but it could be available in the future
                 if value.is_null() {
                     self.cache.remove(&key).await;
+                } else if let (Some(revalidate), None) = (&self.revalidate, &tag) {
+                    revalidate.fresh.insert(key.clone(), ()).await;
+                } else if let (Some(tags), Some(tag)) = (&self.tags, &tag) {
+                    if !self::tag(tags, &key, tag) {
+                        // the block was orphaned while the request was in flight
+                        self.cache.remove(&key).await;
+                    }
                 }
             }
 
@@ -226,6 +644,75 @@ mod tests {
         assert_eq!(res.unwrap(), json!(2));
     }
 
+    #[tokio::test]
+    async fn negative_caching() {
+        use crate::utils::errors;
+        use jsonrpsee::types::error::INVALID_PARAMS_CODE;
+
+        let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None), RpcMetrics::noop())
+            .with_negative(
+                Capacity::Entries(NonZeroUsize::new(3).unwrap()),
+                Duration::from_millis(20),
+                [INVALID_PARAMS_CODE],
+                true,
+            );
+
+        async fn call(middleware: &CacheMiddleware, n: u64, tagged: bool, result: CallResult) -> CallResult {
+            let mut context = TypeRegistry::new();
+            if tagged {
+                context.insert(CacheTag {
+                    hash: json!("0xaa"),
+                    number: n,
+                });
+            }
+            middleware
+                .call(
+                    CallRequest::new("test", vec![json!(n)]),
+                    context,
+                    Box::new(move |_, _| async move { result }.boxed()),
+                )
+                .await
+        }
+
+        // deterministic errors and nulls are cached
+        assert!(call(&middleware, 1, false, Err(errors::invalid_params("bad")))
+            .await
+            .is_err());
+        assert!(call(&middleware, 1, false, Ok(json!(1))).await.is_err());
+        assert_eq!(
+            call(&middleware, 2, false, Ok(JsonValue::Null)).await.unwrap(),
+            JsonValue::Null
+        );
+        assert_eq!(
+            call(&middleware, 2, false, Ok(json!(2))).await.unwrap(),
+            JsonValue::Null
+        );
+
+        // transient errors and other error codes are not
+        assert!(call(&middleware, 3, false, Err(errors::internal_error("timeout")))
+            .await
+            .is_err());
+        assert_eq!(call(&middleware, 3, false, Ok(json!(3))).await.unwrap(), json!(3));
+        assert!(call(&middleware, 4, false, Err(errors::failed("reverted")))
+            .await
+            .is_err());
+        assert_eq!(call(&middleware, 4, false, Ok(json!(4))).await.unwrap(), json!(4));
+
+        // nor nulls for unfinalized blocks
+        let (_tx, rx) = broadcast::channel(1);
+        let middleware = middleware.with_orphaned_heads(rx);
+        assert_eq!(
+            call(&middleware, 5, true, Ok(JsonValue::Null)).await.unwrap(),
+            JsonValue::Null
+        );
+        assert_eq!(call(&middleware, 5, true, Ok(json!(5))).await.unwrap(), json!(5));
+
+        // until the ttl expires
+        tokio::time::sleep(Duration::from_millis(30)).await;
+        assert_eq!(call(&middleware, 1, false, Ok(json!(1))).await.unwrap(), json!(1));
+        assert_eq!(call(&middleware, 2, false, Ok(json!(2))).await.unwrap(), json!(2));
+    }
+
     #[tokio::test]
     async fn cache_ttl_works() {
         let middleware = CacheMiddleware::new(
@@ -269,6 +756,147 @@ mod tests {
         assert_eq!(res.unwrap(), json!(2));
     }
 
+    #[tokio::test]
+    async fn stale_while_revalidate() {
+        let middleware = CacheMiddleware::new(
+            Cache::new(NonZeroUsize::new(1).unwrap(), Some(Duration::from_millis(100))),
+            RpcMetrics::noop(),
+        )
+        .with_revalidate(
+            Capacity::Entries(NonZeroUsize::new(1).unwrap()),
+            Duration::from_millis(20),
+        );
+
+        let call = |next: NextFn<CallRequest, CallResult>| {
+            middleware.call(CallRequest::new("test", vec![json!(11)]), Default::default(), next)
+        };
+
+        let res = call(Box::new(move |_, _| async move { Ok(json!(1)) }.boxed())).await;
+        assert_eq!(res.unwrap(), json!(1));
+
+        // fresh
+        let res = call(Box::new(move |_, _| async move { panic!() }.boxed())).await;
+        assert_eq!(res.unwrap(), json!(1));
+
+        tokio::time::sleep(Duration::from_millis(30)).await;
+
+        // stale value served right away while it is refreshed
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let res = call(Box::new(move |_, _| {
+            async move { Ok(rx.recv().await.unwrap()) }.boxed()
+        }))
+        .await;
+        assert_eq!(res.unwrap(), json!(1));
+
+        // only one refresh at a time
+        let res = call(Box::new(move |_, _| async move { panic!() }.boxed())).await;
+        assert_eq!(res.unwrap(), json!(1));
+
+        tx.send(json!(2)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        // refreshed value is fresh again
+        let res = call(Box::new(move |_, _| async move { panic!() }.boxed())).await;
+        assert_eq!(res.unwrap(), json!(2));
+
+        // the refresh went upstream, so it counts as a miss
+        let counters = middleware.cache.counters();
+        assert_eq!((counters.queries(), counters.misses()), (5, 2));
+
+        // dropped after the max age
+        tokio::time::sleep(Duration::from_millis(110)).await;
+        let res = call(Box::new(move |_, _| async move { Ok(json!(3)) }.boxed())).await;
+        assert_eq!(res.unwrap(), json!(3));
+    }
+
+    #[test]
+    fn canonical_params_works() {
+        let param = |ty: &str| MethodParam {
+            name: ty.to_string(),
+            ty: ty.to_string(),
+            optional: true,
+            inject: false,
+        };
+        let params = vec![
+            param("BlockHash"),
+            param("BlockNumber"),
+            param("BlockTag"),
+            param("String"),
+        ];
+
+        assert_eq!(
+            canonical_params(
+                &params,
+                &[json!("0xABcd"), json!("0x0A"), json!("0x00ff"), json!("0xAB")]
+            ),
+            vec![json!("0xabcd"), json!(10), json!("0xff"), json!("0xAB")]
+        );
+        // trailing nulls are the same as omitted params
+        assert_eq!(
+            canonical_params(&params, &[json!("0xabcd"), json!(10), JsonValue::Null, JsonValue::Null]),
+            vec![json!("0xabcd"), json!(10)]
+        );
+        // tags and non hex strings are kept
+        assert_eq!(
+            canonical_params(&params, &[json!("5GrwvaEF"), json!("latest"), json!("latest")]),
+            vec![json!("5GrwvaEF"), json!("latest"), json!("latest")]
+        );
+        // objects are canonicalised recursively, extra params too
+        assert_eq!(
+            canonical_params(
+                &[],
+                &[
+                    json!({ "to": "0xABCD", "data": "0xFF", "topics": [["0xAA"]] }),
+                    json!(false)
+                ]
+            )
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>(),
+            vec![
+                r#"{"data":"0xff","to":"0xabcd","topics":[["0xaa"]]}"#.to_string(),
+                "false".to_string()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn equivalent_params_share_entries() {
+        let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None), RpcMetrics::noop())
+            .with_params(vec![
+                MethodParam {
+                    name: "hash".to_string(),
+                    ty: "BlockHash".to_string(),
+                    optional: false,
+                    inject: false,
+                },
+                MethodParam {
+                    name: "full".to_string(),
+                    ty: "Boolean".to_string(),
+                    optional: true,
+                    inject: false,
+                },
+            ]);
+
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![json!("0xABCD")]),
+                Default::default(),
+                Box::new(move |_, _| async move { Ok(json!(1)) }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(1));
+
+        let res = middleware
+            .call(
+                CallRequest::new("test", vec![json!("0xabcd"), JsonValue::Null]),
+                Default::default(),
+                Box::new(move |_, _| async move { panic!() }.boxed()),
+            )
+            .await;
+        assert_eq!(res.unwrap(), json!(1));
+    }
+
     #[tokio::test]
     async fn bypass_cache() {
         let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None), RpcMetrics::noop());
@@ -312,6 +940,51 @@ mod tests {
         assert_eq!(res.unwrap(), json!(1));
     }
 
+    #[tokio::test]
+    async fn evicts_orphaned_entries() {
+        async fn call(middleware: &CacheMiddleware, number: u64, hash: &str, value: JsonValue) -> CallResult {
+            let mut context = TypeRegistry::new();
+            context.insert(CacheTag {
+                hash: json!(hash),
+                number,
+            });
+            middleware
+                .call(
+                    CallRequest::new("test", vec![json!(number)]),
+                    context,
+                    Box::new(move |_, _| async move { Ok(value) }.boxed()),
+                )
+                .await
+        }
+
+        // tagged requests bypass the cache without reorg notifications
+        let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None), RpcMetrics::noop());
+        assert_eq!(call(&middleware, 1, "0xaa", json!(1)).await.unwrap(), json!(1));
+        assert_eq!(call(&middleware, 1, "0xaa", json!(2)).await.unwrap(), json!(2));
+
+        let (tx, rx) = broadcast::channel(16);
+        let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None), RpcMetrics::noop())
+            .with_orphaned_heads(rx);
+
+        assert_eq!(call(&middleware, 1, "0xaa", json!(1)).await.unwrap(), json!(1));
+        assert_eq!(call(&middleware, 2, "0xbb", json!(2)).await.unwrap(), json!(2));
+        // cache hit
+        assert_eq!(call(&middleware, 1, "0xaa", json!(3)).await.unwrap(), json!(1));
+
+        tx.send((json!("0xaa"), 1)).unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        // orphaned entry evicted, other blocks are kept
+        assert_eq!(call(&middleware, 1, "0xa1", json!(4)).await.unwrap(), json!(4));
+        assert_eq!(call(&middleware, 2, "0xbb", json!(5)).await.unwrap(), json!(2));
+
+        // responses for blocks already orphaned are not kept
+        tx.send((json!("0xcc"), 3)).unwrap();
+        tokio::time::sleep(Duration::from_millis(1)).await;
+        assert_eq!(call(&middleware, 3, "0xcc", json!(6)).await.unwrap(), json!(6));
+        assert_eq!(call(&middleware, 3, "0xc3", json!(7)).await.unwrap(), json!(7));
+    }
+
     #[tokio::test]
     async fn avoid_repeated_requests() {
         let middleware = CacheMiddleware::new(Cache::new(NonZeroUsize::try_from(3).unwrap(), None), RpcMetrics::noop());
@@ -343,7 +1016,12 @@ mod tests {
         let ext = crate::extensions::ExtensionsConfig {
             cache: Some(crate::extensions::cache::CacheConfig {
                 default_size: 100,
+                default_max_bytes: None,
                 default_ttl_seconds: Some(10),
+                backend: Default::default(),
+                disk: None,
+                shared: None,
+                prewarm: None,
             }),
             ..Default::default()
         }
@@ -357,7 +1035,13 @@ mod tests {
                 method: "foo".to_string(),
                 cache: Some(CacheParams {
                     size: Some(0),
+                    max_bytes: None,
+                    min_bytes: None,
+                    weight: None,
                     ttl_seconds: None,
+                    persist: false,
+                    max_age_seconds: None,
+                    negative: None,
                 }),
                 params: vec![],
                 response: None,
@@ -375,7 +1059,13 @@ mod tests {
                 method: "foo".to_string(),
                 cache: Some(CacheParams {
                     size: None,
+                    max_bytes: None,
+                    min_bytes: None,
+                    weight: None,
                     ttl_seconds: None,
+                    persist: false,
+                    max_age_seconds: None,
+                    negative: None,
                 }),
                 params: vec![],
                 response: None,
@@ -393,7 +1083,13 @@ mod tests {
                 method: "foo".to_string(),
                 cache: Some(CacheParams {
                     size: Some(1),
+                    max_bytes: None,
+                    min_bytes: None,
+                    weight: None,
                     ttl_seconds: None,
+                    persist: false,
+                    max_age_seconds: None,
+                    negative: None,
                 }),
                 params: vec![],
                 response: None,
@@ -420,4 +1116,82 @@ mod tests {
         .await;
         assert!(cache_middleware.is_some(), "Cache should be enabled");
     }
+
+    #[tokio::test]
+    async fn shared_cache_across_methods() {
+        let ext = crate::extensions::ExtensionsConfig {
+            cache: Some(crate::extensions::cache::CacheConfig {
+                default_size: 100,
+                default_max_bytes: None,
+                default_ttl_seconds: None,
+                backend: Default::default(),
+                disk: None,
+                // 3 entries of 64 bytes keys and 1 byte values
+                shared: Some(crate::extensions::cache::SharedConfig { max_bytes: 200 }),
+                prewarm: None,
+            }),
+            ..Default::default()
+        }
+        .create_registry()
+        .await
+        .expect("Failed to create registry");
+
+        let build = |method: &str| {
+            let method = RpcMethod {
+                method: method.to_string(),
+                cache: None,
+                params: vec![],
+                response: None,
+                delay_ms: None,
+                rate_limit_weight: 1,
+            };
+            let ext = ext.clone();
+            async move { CacheMiddleware::build(&method, &ext).await.unwrap() }
+        };
+        let a = build("a").await;
+        let b = build("b").await;
+
+        // responds with `n` on a cache miss
+        async fn call(middleware: &dyn Middleware<CallRequest, CallResult>, method: &str, n: u64) -> CallResult {
+            middleware
+                .call(
+                    CallRequest::new(method, vec![json!(n)]),
+                    Default::default(),
+                    Box::new(move |_, _| async move { Ok(json!(n)) }.boxed()),
+                )
+                .await
+        }
+        async fn cached(middleware: &dyn Middleware<CallRequest, CallResult>, method: &str, n: u64) -> CallResult {
+            middleware
+                .call(
+                    CallRequest::new(method, vec![json!(n)]),
+                    Default::default(),
+                    Box::new(move |_, _| async move { Ok(json!("miss")) }.boxed()),
+                )
+                .await
+        }
+
+        call(a.as_ref(), "a", 1).await.unwrap();
+        call(b.as_ref(), "b", 2).await.unwrap();
+        call(b.as_ref(), "b", 3).await.unwrap();
+        assert_eq!(cached(a.as_ref(), "a", 1).await.unwrap(), json!(1));
+
+        // b uses the room a does not need
+        call(b.as_ref(), "b", 4).await.unwrap();
+        assert_eq!(cached(b.as_ref(), "b", 2).await.unwrap(), json!("miss"));
+        assert_eq!(cached(a.as_ref(), "a", 1).await.unwrap(), json!(1));
+        assert_eq!(cached(b.as_ref(), "b", 4).await.unwrap(), json!(4));
+    }
+
+    #[test]
+    fn block_number_of_responses() {
+        assert_eq!(block_number(&json!({"number": "0x10", "hash": "0x01"})), Some(16));
+        assert_eq!(block_number(&json!({"blockNumber": "0x2", "status": "0x1"})), Some(2));
+        assert_eq!(
+            block_number(&json!({"block": {"header": {"number": "0xff"}, "extrinsics": []}})),
+            Some(255)
+        );
+        assert_eq!(block_number(&json!("0x10")), None);
+        assert_eq!(block_number(&json!({"number": 16})), None);
+    }
 }
@@ -7,7 +7,8 @@ use crate::{
     config::MethodParam,
     extensions::api::{SubstrateApi, ValueHandle},
     middlewares::{
-        methods::cache::BypassCache, CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER,
+        methods::cache::{BypassCache, CacheTag},
+        CallRequest, CallResult, Middleware, MiddlewareBuilder, NextFn, RpcMethod, TRACER,
     },
     utils::errors,
     utils::{TypeRegistry, TypeRegistryRef},
@@ -19,6 +20,7 @@ pub enum InjectType {
 }
 
 pub struct InjectParamsMiddleware {
+    api: Arc<SubstrateApi>,
     head: ValueHandle<(JsonValue, u64)>,
     finalized: ValueHandle<(JsonValue, u64)>,
     inject: InjectType,
@@ -62,6 +64,7 @@ impl InjectParamsMiddleware {
         Self {
             head: api.get_head(),
             finalized: api.get_finalized_head(),
+            api,
             inject,
             params,
         }
@@ -109,9 +112,13 @@ impl Middleware<CallRequest, CallResult> for InjectParamsMiddleware {
                 if param.ty == "BlockNumber" {
                     if let Some(number) = request.params.get(idx).and_then(|x| x.as_u64()) {
                         let (_, finalized) = self.finalized.read().await;
-                        // avoid cache unfinalized data
+                        // tag unfinalized data so it is evicted if the block is orphaned,
+                        // avoid caching it if the block is unknown
                         if number > finalized {
-                            context.insert(BypassCache(true));
+                            match self.api.head_hash(number) {
+                                Some(hash) => context.insert(CacheTag { hash, number }),
+                                None => context.insert(BypassCache(true)),
+                            }
                         }
                     }
                 }
@@ -195,6 +202,10 @@ mod tests {
         context.get::<BypassCache>().map_or(false, |x| x.0)
     }
 
+    fn cache_tag(context: &TypeRegistry) -> Option<(JsonValue, u64)> {
+        context.get::<CacheTag>().map(|tag| (tag.hash.clone(), tag.number))
+    }
+
     async fn create_client() -> ExecutionContext {
         let mut builder = TestServerBuilder::new();
 
@@ -537,7 +548,7 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn skip_cache_if_block_number_not_finalized() {
+    async fn tag_cache_if_block_number_not_finalized() {
         let (middleware, mut context) = create_inject_middleware(
             InjectType::BlockNumberAt(1),
             vec![
@@ -579,7 +590,7 @@ mod tests {
             assert_eq!(result, json!("0x1111"));
         }
 
-        // block head is updated but not finalized, cache should be skipped
+        // block head is updated but not finalized, cache should be tagged with the head
         {
             // head updated but not finalized
             context
@@ -600,8 +611,9 @@ mod tests {
                     Default::default(),
                     Box::new(move |req: CallRequest, context| {
                         async move {
-                            // cache bypassed
-                            assert!(bypass_cache(&context));
+                            // cache tagged
+                            assert!(!bypass_cache(&context));
+                            assert_eq!(cache_tag(&context), Some((json!("0xbcde"), 0x5432)));
                             // block number is injected
                             assert_eq!(req.params, vec![json!("0x1234"), json!(0x5432)]);
                             Ok(json!("0x1111"))
@@ -614,7 +626,7 @@ mod tests {
             assert_eq!(result, json!("0x1111"));
         }
 
-        // request with head block number should skip cache
+        // request with head block number should be tagged
         {
             let result = middleware
                 .call(
@@ -622,8 +634,9 @@ mod tests {
                     Default::default(),
                     Box::new(move |req: CallRequest, context| {
                         async move {
-                            // cache bypassed
-                            assert!(bypass_cache(&context));
+                            // cache tagged
+                            assert!(!bypass_cache(&context));
+                            assert_eq!(cache_tag(&context), Some((json!("0xbcde"), 0x5432)));
                             // params not changed
                             assert_eq!(req.params, vec![json!("0x1234"), json!(0x5432)]);
                             Ok(json!("0x1111"))
@@ -683,7 +696,7 @@ mod tests {
                 .unwrap();
             assert_eq!(result, json!("0x1111"));
 
-            // block is not finalized, cache should be skipped
+            // block is not finalized, cache should be tagged
             let result = middleware
                 .call(
                     CallRequest::new(
@@ -693,8 +706,9 @@ mod tests {
                     Default::default(),
                     Box::new(move |req: CallRequest, context| {
                         async move {
-                            // cache bypassed
-                            assert!(bypass_cache(&context));
+                            // cache tagged
+                            assert!(!bypass_cache(&context));
+                            assert_eq!(cache_tag(&context), Some((json!("0xbcde"), 0x5432)));
                             // params not changed
                             assert_eq!(req.params, vec![json!("0x1234"), json!(0x5432), json!("0xabcd")]);
                             Ok(json!("0x1111"))
@@ -706,5 +720,26 @@ mod tests {
                 .unwrap();
             assert_eq!(result, json!("0x1111"));
         }
+
+        // request with unknown unfinalized block number should skip cache
+        {
+            let result = middleware
+                .call(
+                    CallRequest::new("state_getStorage", vec![json!("0x1234"), json!(0x6543)]),
+                    Default::default(),
+                    Box::new(move |_, context| {
+                        async move {
+                            // cache bypassed
+                            assert!(bypass_cache(&context));
+                            assert_eq!(cache_tag(&context), None);
+                            Ok(json!("0x1111"))
+                        }
+                        .boxed()
+                    }),
+                )
+                .await
+                .unwrap();
+            assert_eq!(result, json!("0x1111"));
+        }
     }
 }
@@ -11,6 +11,7 @@ use std::{
     fmt::{Debug, Formatter},
     sync::Arc,
 };
+use tokio::sync::oneshot;
 
 use crate::{
     config::{RpcMethod, RpcSubscription},
@@ -46,6 +47,8 @@ pub struct SubscriptionRequest {
     pub params: Vec<JsonValue>,
     pub unsubscribe: String,
     pub pending_sink: PendingSubscriptionSink,
+    /// Ends an accepted subscription with an error notification.
+    pub close: oneshot::Sender<StringError>,
 }
 
 impl Debug for SubscriptionRequest {
@@ -13,7 +13,11 @@ use tokio::sync::{broadcast, RwLock};
 
 use crate::{
     config::MergeStrategy,
-    extensions::{client::Client, merge_subscription::MergeSubscription},
+    extensions::{
+        client::Client,
+        merge_subscription::MergeSubscription,
+        rate_limit::{BufferConfig, NotificationSink, RateLimitBuilder},
+    },
     middlewares::{
         Middleware, MiddlewareBuilder, NextFn, RpcSubscription, SubscriptionRequest, SubscriptionResult, TRACER,
     },
@@ -67,6 +71,8 @@ pub struct MergeSubscriptionMiddleware {
     keep_alive_seconds: u64,
     upstream_subs: Arc<RwLock<HashMap<CacheKey<Blake2b512>, UpstreamSubscription>>>,
     current_values: Arc<RwLock<HashMap<CacheKey<Blake2b512>, JsonValue>>>,
+    // without it a subscriber too slow to keep up with the broadcast channel is dropped
+    buffer: Option<BufferConfig>,
 }
 
 impl MergeSubscriptionMiddleware {
@@ -77,9 +83,15 @@ impl MergeSubscriptionMiddleware {
             keep_alive_seconds: keep_alive_seconds.unwrap_or(60), // 60s
             upstream_subs: Arc::new(RwLock::new(HashMap::new())),
             current_values: Arc::new(RwLock::new(HashMap::new())),
+            buffer: None,
         }
     }
 
+    pub fn with_buffer(mut self, buffer: Option<BufferConfig>) -> Self {
+        self.buffer = buffer;
+        self
+    }
+
     async fn get_upstream_subscription(
         &self,
         key: CacheKey<Blake2b512>,
@@ -185,11 +197,11 @@ impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult>
             .get::<MergeSubscription>()
             .expect("MergeSubscription extension not found");
 
-        Some(Box::new(Self::new(
-            client,
-            merge_strategy,
-            merge_subscription.config.keep_alive_seconds,
-        )))
+        let buffer = ext.get::<RateLimitBuilder>().and_then(|r| r.subscription_buffer());
+
+        Some(Box::new(
+            Self::new(client, merge_strategy, merge_subscription.config.keep_alive_seconds).with_buffer(buffer),
+        ))
     }
 }
 
@@ -209,6 +221,7 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for MergeSubscriptionMi
                 params,
                 unsubscribe,
                 pending_sink,
+                close,
             } = request;
 
             let subscribe = match self
@@ -231,6 +244,7 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for MergeSubscriptionMi
                 }
             };
 
+            let sink = NotificationSink::new(sink, self.buffer.clone(), close);
             let current_values = self.current_values.clone();
 
             // send any current value and broadcast new values
@@ -245,8 +259,8 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for MergeSubscriptionMi
                     .map(|x| SubscriptionMessage::from_json(&x).ok())
                     .unwrap_or(None)
                 {
-                    if let Err(e) = sink.send(current_value).await {
-                        tracing::trace!("subscription sink closed {e:?}");
+                    if sink.send(current_value).await.is_err() {
+                        tracing::trace!("subscription sink closed");
                         return;
                     }
                 }
@@ -257,8 +271,8 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for MergeSubscriptionMi
                         resp = stream.recv() => {
                             match resp {
                                 Ok(new_value) => {
-                                    if let Err(e) = sink.send(new_value).await {
-                                        tracing::trace!("subscription sink closed {e:?}");
+                                    if sink.send(new_value).await.is_err() {
+                                        tracing::trace!("subscription sink closed");
                                         break;
                                     }
                                 }
@@ -5,7 +5,10 @@ use jsonrpsee::SubscriptionMessage;
 use opentelemetry::trace::FutureExt;
 
 use crate::{
-    extensions::client::Client,
+    extensions::{
+        client::Client,
+        rate_limit::{BufferConfig, NotificationSink, RateLimitBuilder},
+    },
     middlewares::{
         Middleware, MiddlewareBuilder, NextFn, RpcSubscription, SubscriptionRequest, SubscriptionResult, TRACER,
     },
@@ -14,11 +17,17 @@ use crate::{
 
 pub struct UpstreamMiddleware {
     client: Arc<Client>,
+    buffer: Option<BufferConfig>,
 }
 
 impl UpstreamMiddleware {
     pub fn new(client: Arc<Client>) -> Self {
-        Self { client }
+        Self { client, buffer: None }
+    }
+
+    pub fn with_buffer(mut self, buffer: Option<BufferConfig>) -> Self {
+        self.buffer = buffer;
+        self
     }
 }
 
@@ -28,12 +37,12 @@ impl MiddlewareBuilder<RpcSubscription, SubscriptionRequest, SubscriptionResult>
         _method: &RpcSubscription,
         extensions: &TypeRegistryRef,
     ) -> Option<Box<dyn Middleware<SubscriptionRequest, SubscriptionResult>>> {
-        let client = extensions
-            .read()
-            .await
-            .get::<Client>()
-            .expect("Client extension not found");
-        Some(Box::new(UpstreamMiddleware::new(client)))
+        let extensions = extensions.read().await;
+        let client = extensions.get::<Client>().expect("Client extension not found");
+        let buffer = extensions
+            .get::<RateLimitBuilder>()
+            .and_then(|r| r.subscription_buffer());
+        Some(Box::new(UpstreamMiddleware::new(client).with_buffer(buffer)))
     }
 }
 
@@ -51,6 +60,7 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for UpstreamMiddleware
                 params,
                 unsubscribe,
                 pending_sink,
+                close,
             } = request;
 
             let result = self.client.subscribe(&subscribe, params, &unsubscribe).await;
@@ -75,6 +85,7 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for UpstreamMiddleware
                 }
             };
 
+            let sink = NotificationSink::new(sink, self.buffer.clone(), close);
             tokio::spawn(async move {
                 loop {
                     tokio::select! {
@@ -95,8 +106,11 @@ impl Middleware<SubscriptionRequest, SubscriptionResult> for UpstreamMiddleware
                                             continue;
                                         }
                                     };
-                                    if let Err(e) = sink.send(resp).await {
-                                        tracing::error!("Failed to send subscription response: {}", e);
+                                    if sink.send(resp).await.is_err() {
+                                        tracing::debug!("Subscription closed, unsubscribing");
+                                        if let Err(err) = subscription.unsubscribe().await {
+                                            tracing::error!("Failed to unsubscribe: {}", err);
+                                        }
                                         break;
                                     }
                                 }
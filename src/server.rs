@@ -13,8 +13,10 @@ use serde_json::json;
 use crate::{
     config::Config,
     extensions::{
+        admin::{self, Admin},
+        cache::{new_heads, Cache, Prewarmer},
         prometheus::get_rpc_metrics,
-        rate_limit::{MethodWeights, RateLimitBuilder},
+        rate_limit::{quota_methods, MethodWeights, RateLimitBuilder},
         server::SubwayServerBuilder,
     },
     middlewares::{factory, CallRequest, Middlewares, SubscriptionRequest},
@@ -51,6 +53,11 @@ pub async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
 
     let rpc_method_weights = MethodWeights::from_config(&config.rpcs.methods);
 
+    let admin = extensions_registry.read().await.get::<Admin>();
+    let cache = extensions_registry.read().await.get::<Cache>();
+    let ban_list = rate_limit_builder.as_ref().and_then(|r| r.ban_list());
+    let quotas = rate_limit_builder.as_ref().and_then(|r| r.quotas());
+
     let request_timeout_seconds = server_builder.config.request_timeout_seconds;
 
     let metrics = get_rpc_metrics(&extensions_registry).await;
@@ -122,6 +129,14 @@ pub async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
                 })?;
             }
 
+            // prewarm requests go through the same middlewares as the clients' ones
+            if let Some(cache) = &cache {
+                if let Some(prewarm) = &cache.config.prewarm {
+                    Prewarmer::new(cache.clone(), module.clone(), prewarm.concurrency)
+                        .start(prewarm, new_heads(&registry).await);
+                }
+            }
+
             // register subscriptions from config
             for subscription in config.rpcs.subscriptions {
                 let subscribe_name = string_to_static_str(subscription.subscribe.clone());
@@ -147,9 +162,12 @@ pub async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
                     subscribe_name,
                     name,
                     unsubscribe_name,
-                    move |params, pending_sink, _, _| {
+                    move |params, pending_sink, _, extensions| {
                         let subscription_middlewares = subscription_middlewares.clone();
                         async move {
+                            // held until the subscription ends, e.g. for the ip subscription limit
+                            let _extensions = extensions;
+
                             let parsed = params.parse::<JsonValue>()?;
                             let params = if parsed == JsonValue::Null {
                                 vec![]
@@ -158,6 +176,7 @@ pub async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
                             };
 
                             let (result_tx, result_rx) = tokio::sync::oneshot::channel();
+                            let (close_tx, close_rx) = tokio::sync::oneshot::channel();
                             let timeout = tokio::time::Duration::from_secs(request_timeout_seconds);
 
                             subscription_middlewares
@@ -167,6 +186,7 @@ pub async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
                                         params,
                                         unsubscribe: unsubscribe_name.into(),
                                         pending_sink,
+                                        close: close_tx,
                                     },
                                     result_tx,
                                     timeout,
@@ -186,7 +206,13 @@ pub async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
                                 }
                             };
 
-                            result
+                            result?;
+
+                            // the subscription ends with an error notification if it gets closed with one
+                            match close_rx.await {
+                                Ok(err) => Err(err),
+                                Err(_) => Ok(()),
+                            }
                         }
                         .with_context(tracer.context(name))
                     },
@@ -200,6 +226,10 @@ pub async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
                 module.register_alias(alias_new, alias_old)?;
             }
 
+            if let Some(quotas) = quotas {
+                module.merge(quota_methods(quotas)?)?;
+            }
+
             let mut rpc_methods = module.method_names().map(|x| x.to_owned()).collect::<Vec<_>>();
 
             rpc_methods.sort();
@@ -211,6 +241,14 @@ pub async fn build(config: Config) -> anyhow::Result<SubwayServerHandle> {
                 }))
             })?;
 
+            // admin methods are registered last so they are not listed in rpc_methods
+            if let (Some(_), Some(ban_list)) = (&admin, ban_list) {
+                module.merge(admin::ban_methods(ban_list)?)?;
+            }
+            if let (Some(_), Some(cache)) = (&admin, cache) {
+                module.merge(admin::cache_methods(cache)?)?;
+            }
+
             Ok(module)
         })
         .await?;
@@ -263,6 +301,11 @@ mod tests {
                     request_timeout_seconds: request_timeout_seconds.unwrap_or(10),
                     http_methods: Vec::new(),
                     cors: None,
+                    health: Default::default(),
+                    sse: None,
+                    max_subscriptions_per_connection: None,
+                    max_subscriptions_per_ip: None,
+                    ws_ping: None,
                 }),
                 ..Default::default()
             },
@@ -0,0 +1,172 @@
+use jsonrpsee::{
+    core::{client::ClientT, JsonValue},
+    http_client::{HeaderMap, HttpClient, HttpClientBuilder},
+    rpc_params,
+};
+
+use crate::{
+    config::Config,
+    extensions::{
+        admin::AdminConfig,
+        rate_limit::{BanConfig, OnLimit, RateLimitConfig, Rule},
+        ExtensionsConfig,
+    },
+    server,
+};
+
+fn config() -> Config {
+    super::config(ExtensionsConfig {
+        rate_limit: Some(RateLimitConfig {
+            ip: Some(Rule {
+                burst: 2,
+                period_secs: 1,
+                jitter_up_to_millis: 0,
+            }),
+            use_xff: true,
+            trusted_proxies: vec!["127.0.0.0/8".to_string()],
+            on_limit: OnLimit::Reject,
+            ban: Some(BanConfig {
+                threshold: 1,
+                window_secs: 60,
+                duration_secs: 600,
+            }),
+            ..Default::default()
+        }),
+        admin: Some(AdminConfig {
+            auth_token: "secret".to_string(),
+        }),
+        ..Default::default()
+    })
+}
+
+fn client(url: &str, header: (&'static str, &str)) -> HttpClient {
+    let mut headers = HeaderMap::new();
+    headers.insert(header.0, header.1.parse().unwrap());
+    HttpClientBuilder::default().set_headers(headers).build(url).unwrap()
+}
+
+#[tokio::test]
+async fn repeat_offenders_are_banned() {
+    let subway_server = server::build(config()).await.unwrap();
+    let url = format!("http://{}", subway_server.addr);
+
+    let offender = client(&url, ("x-forwarded-for", "1.2.3.4"));
+    let admin = client(&url, ("authorization", "Bearer secret"));
+    let anonymous = client(&url, ("x-forwarded-for", "5.6.7.8"));
+
+    // the 3rd request is rejected and gets the ip banned
+    for _ in 0..2 {
+        offender
+            .request::<JsonValue, _>("rpc_methods", rpc_params!())
+            .await
+            .unwrap();
+    }
+    let err = offender
+        .request::<JsonValue, _>("rpc_methods", rpc_params!())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("Rate limit exceeded"), "{err}");
+    let err = offender
+        .request::<JsonValue, _>("rpc_methods", rpc_params!())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("429"), "{err}");
+
+    // admin methods are hidden and require the token
+    let methods = anonymous
+        .request::<JsonValue, _>("rpc_methods", rpc_params!())
+        .await
+        .unwrap();
+    assert!(!methods.to_string().contains("subway_listBans"));
+    assert!(anonymous
+        .request::<JsonValue, _>("subway_listBans", rpc_params!())
+        .await
+        .is_err());
+
+    let bans = admin
+        .request::<JsonValue, _>("subway_listBans", rpc_params!())
+        .await
+        .unwrap();
+    assert_eq!(bans.as_array().unwrap().len(), 1);
+    assert_eq!(bans[0]["ip"], "1.2.3.4");
+
+    let cleared = admin
+        .request::<usize, _>("subway_clearBans", rpc_params!("1.2.3.4"))
+        .await
+        .unwrap();
+    assert_eq!(cleared, 1);
+
+    // once its quota is refilled
+    tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+    offender
+        .request::<JsonValue, _>("rpc_methods", rpc_params!())
+        .await
+        .unwrap();
+
+    subway_server.handle.stop().unwrap();
+}
+
+#[tokio::test]
+async fn bans_behind_a_trusted_proxy_apply_to_forwarded_ips() {
+    let subway_server = server::build(config()).await.unwrap();
+    let url = format!("http://{}", subway_server.addr);
+
+    // requests from the proxy itself get it banned
+    let proxy = client(&url, ("user-agent", "proxy"));
+    for _ in 0..3 {
+        let _ = proxy.request::<JsonValue, _>("rpc_methods", rpc_params!()).await;
+    }
+    let offender = client(&url, ("x-forwarded-for", "1.2.3.4"));
+    for _ in 0..3 {
+        let _ = offender.request::<JsonValue, _>("rpc_methods", rpc_params!()).await;
+    }
+
+    let admin = client(&url, ("authorization", "Bearer secret"));
+    let bans = admin
+        .request::<JsonValue, _>("subway_listBans", rpc_params!())
+        .await
+        .unwrap();
+    let mut banned: Vec<_> = bans.as_array().unwrap().iter().map(|b| b["ip"].clone()).collect();
+    banned.sort_by_key(|ip| ip.to_string());
+    assert_eq!(banned, vec!["1.2.3.4", "127.0.0.1"]);
+
+    // the banned forwarded ip is rejected on new connections too
+    let err = client(&url, ("x-forwarded-for", "1.2.3.4"))
+        .request::<JsonValue, _>("rpc_methods", rpc_params!())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("429"), "{err}");
+
+    // other clients of the banned proxy are not dropped when connecting
+    client(&url, ("x-forwarded-for", "5.6.7.8"))
+        .request::<JsonValue, _>("rpc_methods", rpc_params!())
+        .await
+        .unwrap();
+
+    subway_server.handle.stop().unwrap();
+}
+
+#[tokio::test]
+async fn queued_calls_are_not_offenses() {
+    let mut config = config();
+    config.extensions.rate_limit.as_mut().unwrap().on_limit = OnLimit::Queue;
+    let subway_server = server::build(config).await.unwrap();
+    let url = format!("http://{}", subway_server.addr);
+
+    let client = client(&url, ("x-forwarded-for", "1.2.3.4"));
+    for _ in 0..4 {
+        client
+            .request::<JsonValue, _>("rpc_methods", rpc_params!())
+            .await
+            .unwrap();
+    }
+
+    let admin = self::client(&url, ("authorization", "Bearer secret"));
+    let bans = admin
+        .request::<JsonValue, _>("subway_listBans", rpc_params!())
+        .await
+        .unwrap();
+    assert_eq!(bans, serde_json::json!([]));
+
+    subway_server.handle.stop().unwrap();
+}
@@ -0,0 +1,124 @@
+use jsonrpsee::{
+    core::{client::ClientT, JsonValue},
+    http_client::{HeaderMap, HttpClientBuilder},
+    rpc_params,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{
+    config::Config,
+    extensions::{
+        ip_filter::{Action, IpFilterConfig},
+        rate_limit::{OnLimit, RateLimitConfig, Rule},
+        ExtensionsConfig,
+    },
+    server,
+};
+
+fn config(ip_filter: IpFilterConfig, rate_limit: Option<RateLimitConfig>) -> Config {
+    super::config(ExtensionsConfig {
+        ip_filter: Some(ip_filter),
+        rate_limit,
+        ..Default::default()
+    })
+}
+
+async fn status_line(addr: std::net::SocketAddr, headers: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(format!("GET /live HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n").as_bytes())
+        .await
+        .unwrap();
+    BufReader::new(stream).lines().next_line().await.unwrap().unwrap()
+}
+
+#[tokio::test]
+async fn ip_filter_rejects_denied_ips() {
+    let denied = server::build(config(
+        IpFilterConfig {
+            allow: vec![],
+            deny: vec!["127.0.0.0/8".to_string()],
+            allow_file: None,
+            deny_file: None,
+            default_action: Action::Allow,
+            bypass_rate_limit: false,
+            reload_interval_seconds: 10,
+        },
+        None,
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(status_line(denied.addr, "").await, "HTTP/1.1 403 Forbidden");
+
+    let allowed = server::build(config(
+        IpFilterConfig {
+            allow: vec!["127.0.0.1".to_string()],
+            deny: vec![],
+            allow_file: None,
+            deny_file: None,
+            default_action: Action::Deny,
+            bypass_rate_limit: false,
+            reload_interval_seconds: 10,
+        },
+        None,
+    ))
+    .await
+    .unwrap();
+
+    assert_eq!(status_line(allowed.addr, "").await, "HTTP/1.1 200 OK");
+
+    denied.handle.stop().unwrap();
+    allowed.handle.stop().unwrap();
+}
+
+#[tokio::test]
+async fn ip_filter_ignores_forged_forwarding_headers() {
+    let subway_server = server::build(config(
+        IpFilterConfig {
+            allow: vec!["10.0.0.0/8".to_string()],
+            deny: vec!["127.0.0.2".to_string()],
+            allow_file: None,
+            deny_file: None,
+            default_action: Action::Allow,
+            bypass_rate_limit: true,
+            reload_interval_seconds: 10,
+        },
+        Some(RateLimitConfig {
+            ip: Some(Rule {
+                burst: 1,
+                period_secs: 60,
+                jitter_up_to_millis: 0,
+            }),
+            use_xff: true,
+            on_limit: OnLimit::Reject,
+            ..Default::default()
+        }),
+    ))
+    .await
+    .unwrap();
+    let addr = subway_server.addr;
+
+    // no trusted proxies, so the forwarded ips are never checked against the lists
+    assert_eq!(
+        status_line(addr, "X-Forwarded-For: 127.0.0.2\r\n").await,
+        "HTTP/1.1 200 OK"
+    );
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-forwarded-for", "10.0.0.1".parse().unwrap());
+    let client = HttpClientBuilder::default()
+        .set_headers(headers)
+        .build(format!("http://{addr}"))
+        .unwrap();
+    let res: Result<JsonValue, _> = client.request("rpc_methods", rpc_params!()).await;
+    assert!(res.is_ok());
+    // the allowlisted forwarded ip does not bypass the rate limit
+    let res: Result<JsonValue, _> = client.request("rpc_methods", rpc_params!()).await;
+    assert!(res.is_err());
+
+    subway_server.handle.stop().unwrap();
+}
@@ -1,2 +1,49 @@
+use crate::{
+    config::{Config, MiddlewaresConfig, RpcDefinitions},
+    extensions::{server::ServerConfig, ExtensionsConfig},
+};
+
+mod ban;
+mod ip_filter;
 mod merge_subscription;
+mod rate_limit;
+mod sse;
+mod subscription_limit;
 mod upstream;
+
+/// A server listening on a random local port.
+fn server_config() -> ServerConfig {
+    ServerConfig {
+        listen_address: "127.0.0.1".to_string(),
+        port: 0,
+        max_connections: 10,
+        max_batch_size: None,
+        request_timeout_seconds: 120,
+        http_methods: Vec::new(),
+        cors: None,
+        health: Default::default(),
+        sse: None,
+        max_subscriptions_per_connection: None,
+        max_subscriptions_per_ip: None,
+        ws_ping: None,
+    }
+}
+
+/// Serves no rpcs through no middlewares, with [`server_config`] unless `extensions` has a server.
+fn config(extensions: ExtensionsConfig) -> Config {
+    Config {
+        extensions: ExtensionsConfig {
+            server: extensions.server.or_else(|| Some(server_config())),
+            ..extensions
+        },
+        middlewares: MiddlewaresConfig {
+            methods: vec![],
+            subscriptions: vec![],
+        },
+        rpcs: RpcDefinitions {
+            methods: vec![],
+            subscriptions: vec![],
+            aliases: vec![],
+        },
+    }
+}
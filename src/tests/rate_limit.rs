@@ -0,0 +1,132 @@
+use jsonrpsee::{
+    core::{client::ClientT, JsonValue},
+    http_client::{HeaderMap, HttpClientBuilder},
+    rpc_params,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    config::Config,
+    extensions::{
+        rate_limit::{OnLimit, QuotaConfig, RateLimitConfig, Rule},
+        ExtensionsConfig,
+    },
+    server,
+};
+
+fn config(rate_limit: RateLimitConfig) -> Config {
+    super::config(ExtensionsConfig {
+        rate_limit: Some(rate_limit),
+        ..Default::default()
+    })
+}
+
+async fn post(addr: std::net::SocketAddr) -> String {
+    let body = r#"{"jsonrpc":"2.0","id":1,"method":"rpc_methods","params":[]}"#;
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response.to_lowercase()
+}
+
+#[tokio::test]
+async fn rate_limit_headers_and_error_data() {
+    let subway_server = server::build(config(RateLimitConfig {
+        ip: Some(Rule {
+            burst: 2,
+            period_secs: 10,
+            jitter_up_to_millis: 0,
+        }),
+        on_limit: OnLimit::Reject,
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+
+    let response = post(subway_server.addr).await;
+    assert!(response.contains("x-ratelimit-limit: 2\r\n"), "{response}");
+    assert!(response.contains("x-ratelimit-remaining: 1\r\n"), "{response}");
+    assert!(!response.contains("retry-after"), "{response}");
+
+    let response = post(subway_server.addr).await;
+    assert!(response.contains("x-ratelimit-remaining: 0\r\n"), "{response}");
+
+    // 1 cell is replenished every 5 seconds
+    let response = post(subway_server.addr).await;
+    assert!(response.contains("x-ratelimit-remaining: 0\r\n"), "{response}");
+    assert!(response.contains("retry-after: 5\r\n"), "{response}");
+    assert!(response.contains(r#""message":"rate limit exceeded""#), "{response}");
+    assert!(response.contains(r#""retry_after_millis":"#), "{response}");
+
+    subway_server.handle.stop().unwrap();
+}
+
+#[tokio::test]
+async fn quota_per_api_key() {
+    let quota: QuotaConfig = serde_yaml::from_str(
+        r#"
+        daily: 1
+        keys:
+          gold:
+            daily: 100
+        "#,
+    )
+    .unwrap();
+    let subway_server = server::build(config(RateLimitConfig {
+        quota: Some(quota),
+        ..Default::default()
+    }))
+    .await
+    .unwrap();
+    let url = format!("http://{}", subway_server.addr);
+
+    let mut headers = HeaderMap::new();
+    headers.insert("x-api-key", "gold".parse().unwrap());
+    let gold = HttpClientBuilder::default().set_headers(headers).build(&url).unwrap();
+    let anonymous = HttpClientBuilder::default().build(&url).unwrap();
+
+    for _ in 0..3 {
+        gold.request::<JsonValue, _>("rpc_methods", rpc_params!())
+            .await
+            .unwrap();
+    }
+    anonymous
+        .request::<JsonValue, _>("rpc_methods", rpc_params!())
+        .await
+        .unwrap();
+    let err = anonymous
+        .request::<JsonValue, _>("rpc_methods", rpc_params!())
+        .await
+        .unwrap_err();
+    assert!(err.to_string().contains("Rate limit exceeded"), "{err}");
+
+    // checking the quota doesn't use it
+    let status = gold
+        .request::<JsonValue, _>("subway_quota", rpc_params!())
+        .await
+        .unwrap();
+    assert_eq!(status["daily"]["used"], 3);
+    assert_eq!(status["daily"]["remaining"], 97);
+    assert_eq!(status["monthly"], JsonValue::Null);
+
+    let status = anonymous
+        .request::<JsonValue, _>("subway_quota", rpc_params!())
+        .await
+        .unwrap();
+    assert_eq!(status["daily"]["remaining"], 0);
+
+    subway_server.handle.stop().unwrap();
+}
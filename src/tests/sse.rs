@@ -0,0 +1,162 @@
+use serde_json::json;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::TcpStream,
+};
+
+use crate::{
+    config::{Config, MergeStrategy, MiddlewaresConfig, RpcDefinitions, RpcSubscription},
+    extensions::{
+        client::{
+            mock::{SinkTask, TestServerBuilder},
+            Client, ClientConfig,
+        },
+        merge_subscription::MergeSubscriptionConfig,
+        server::{ServerConfig, SseConfig},
+        ExtensionsConfig,
+    },
+    server,
+};
+
+const SUBSCRIBE: &str = "mock_sub";
+const UNSUBSCRIBE: &str = "mock_unsub";
+const NOTIFICATION: &str = "mock";
+
+fn config(
+    upstream_addr: SocketAddr,
+    subscription_middlewares: Vec<String>,
+    max_subscriptions_per_ip: Option<u32>,
+) -> Config {
+    Config {
+        middlewares: MiddlewaresConfig {
+            methods: vec![],
+            subscriptions: subscription_middlewares,
+        },
+        rpcs: RpcDefinitions {
+            methods: vec![],
+            subscriptions: vec![RpcSubscription {
+                subscribe: SUBSCRIBE.to_string(),
+                unsubscribe: UNSUBSCRIBE.to_string(),
+                name: NOTIFICATION.to_string(),
+                merge_strategy: Some(MergeStrategy::Replace),
+            }],
+            aliases: vec![],
+        },
+        ..super::config(ExtensionsConfig {
+            client: Some(ClientConfig {
+                endpoints: vec![format!("ws://{upstream_addr}")],
+                shuffle_endpoints: false,
+            }),
+            server: Some(ServerConfig {
+                sse: Some(SseConfig {
+                    path: "/subscribe".to_string(),
+                    keep_alive_seconds: 15,
+                }),
+                max_subscriptions_per_ip,
+                ..super::server_config()
+            }),
+            merge_subscription: Some(MergeSubscriptionConfig {
+                keep_alive_seconds: Some(1),
+            }),
+            ..Default::default()
+        })
+    }
+}
+
+// opens an SSE subscription, returns the response status line and the stream
+async fn sse_subscribe(addr: SocketAddr, params: &str) -> (String, Lines<BufReader<TcpStream>>) {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET /subscribe/{SUBSCRIBE}?params={params} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut lines = BufReader::new(stream).lines();
+    let status = lines.next_line().await.unwrap().unwrap();
+    (status, lines)
+}
+
+// reads the SSE stream until the next `data:` line
+async fn next_data(lines: &mut Lines<BufReader<TcpStream>>) -> serde_json::Value {
+    loop {
+        let line = lines.next_line().await.unwrap().unwrap();
+        if let Some(data) = line.strip_prefix("data: ") {
+            return serde_json::from_str(data).unwrap();
+        }
+    }
+}
+
+#[tokio::test]
+async fn sse_shares_merged_subscription() {
+    let mut builder = TestServerBuilder::new();
+
+    let mut mock_sub_rx = builder.register_subscription(SUBSCRIBE, NOTIFICATION, UNSUBSCRIBE);
+
+    let (addr, _upstream_handle) = builder.build().await;
+
+    let config = config(
+        addr,
+        vec!["merge_subscription".to_string(), "upstream".to_string()],
+        None,
+    );
+
+    let subway_server = server::build(config).await.unwrap();
+    let addr = subway_server.addr;
+
+    // WS client creates the upstream subscription
+    let client = Client::with_endpoints([format!("ws://{addr}")]).unwrap();
+    let mut ws_sub = client.subscribe(SUBSCRIBE, vec![json!(1)], UNSUBSCRIBE).await.unwrap();
+    let upstream_sub = mock_sub_rx.recv().await.unwrap();
+    upstream_sub.run_sink_tasks(vec![SinkTask::Send(json!(1))]).await;
+    assert_eq!(ws_sub.next().await.unwrap().unwrap(), json!(1));
+
+    // SSE client shares the same upstream subscription and receives the current value first
+    let (status, mut lines) = sse_subscribe(addr, "%5B1%5D").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+
+    assert_eq!(next_data(&mut lines).await, json!(1));
+
+    upstream_sub.run_sink_tasks(vec![SinkTask::Send(json!(2))]).await;
+    assert_eq!(next_data(&mut lines).await, json!(2));
+    assert_eq!(ws_sub.next().await.unwrap().unwrap(), json!(2));
+
+    // no new upstream subscription is created
+    assert!(mock_sub_rx.try_recv().is_err());
+
+    subway_server.handle.stop().unwrap();
+}
+
+#[tokio::test]
+async fn sse_subscriptions_count_towards_ip_limit() {
+    let mut builder = TestServerBuilder::new();
+    let mut upstream_subs = builder.register_subscription(SUBSCRIBE, NOTIFICATION, UNSUBSCRIBE);
+    let (addr, _upstream_handle) = builder.build().await;
+
+    let subway_server = server::build(config(addr, vec!["upstream".to_string()], Some(1)))
+        .await
+        .unwrap();
+    let addr = subway_server.addr;
+
+    let (status, mut lines) = sse_subscribe(addr, "%5B1%5D").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    let upstream_sub = upstream_subs.recv().await.unwrap();
+    upstream_sub.run_sink_tasks(vec![SinkTask::Send(json!(1))]).await;
+    assert_eq!(next_data(&mut lines).await, json!(1));
+
+    // the SSE subscription holds the only slot of this ip, for SSE and WS alike
+    let (status, _) = sse_subscribe(addr, "%5B2%5D").await;
+    assert_eq!(status, "HTTP/1.1 429 Too Many Requests");
+    let client = Client::with_endpoints([format!("ws://{addr}")]).unwrap();
+    let err = client
+        .subscribe(SUBSCRIBE, vec![json!(3)], UNSUBSCRIBE)
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("subscriptions"), "{err}");
+
+    // closing the SSE stream frees the slot
+    drop(lines);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let (status, _lines) = sse_subscribe(addr, "%5B4%5D").await;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+
+    subway_server.handle.stop().unwrap();
+}
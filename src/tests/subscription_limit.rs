@@ -0,0 +1,67 @@
+use serde_json::json;
+use std::time::Duration;
+
+use crate::{
+    config::{Config, MiddlewaresConfig, RpcDefinitions, RpcSubscription},
+    extensions::{
+        client::{mock::TestServerBuilder, Client, ClientConfig},
+        server::ServerConfig,
+        ExtensionsConfig,
+    },
+    server,
+};
+
+#[tokio::test]
+async fn subscriptions_closed_upstream_release_ip_slots() {
+    let (subscribe, unsubscribe, notification) = ("mock_sub", "mock_unsub", "mock");
+
+    let mut builder = TestServerBuilder::new();
+    let mut upstream_subs = builder.register_subscription(subscribe, notification, unsubscribe);
+    let (upstream_addr, _upstream_handle) = builder.build().await;
+
+    let config = Config {
+        middlewares: MiddlewaresConfig {
+            methods: vec![],
+            subscriptions: vec!["upstream".to_string()],
+        },
+        rpcs: RpcDefinitions {
+            methods: vec![],
+            subscriptions: vec![RpcSubscription {
+                subscribe: subscribe.to_string(),
+                unsubscribe: unsubscribe.to_string(),
+                name: notification.to_string(),
+                merge_strategy: None,
+            }],
+            aliases: vec![],
+        },
+        ..super::config(ExtensionsConfig {
+            client: Some(ClientConfig {
+                endpoints: vec![format!("ws://{upstream_addr}")],
+                shuffle_endpoints: false,
+            }),
+            server: Some(ServerConfig {
+                max_subscriptions_per_ip: Some(1),
+                ..super::server_config()
+            }),
+            ..Default::default()
+        })
+    };
+
+    let subway_server = server::build(config).await.unwrap();
+    let client = Client::with_endpoints([format!("ws://{}", subway_server.addr)]).unwrap();
+
+    let _sub = client.subscribe(subscribe, vec![json!(1)], unsubscribe).await.unwrap();
+    let err = client
+        .subscribe(subscribe, vec![json!(2)], unsubscribe)
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("subscriptions"), "{err}");
+
+    // the upstream ends the subscription, on the same connection the slot is free again
+    upstream_subs.recv().await.unwrap().close("upstream gone");
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    client.subscribe(subscribe, vec![json!(3)], unsubscribe).await.unwrap();
+
+    subway_server.handle.stop().unwrap();
+}
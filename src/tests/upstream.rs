@@ -3,7 +3,6 @@ use crate::{
     extensions::{
         client::{mock::TestServerBuilder, Client, ClientConfig},
         merge_subscription::MergeSubscriptionConfig,
-        server::ServerConfig,
         ExtensionsConfig,
     },
     server,
@@ -27,25 +26,6 @@ async fn upstream_error_propagate() {
     let (addr, _upstream_handle) = builder.build().await;
 
     let config = Config {
-        extensions: ExtensionsConfig {
-            client: Some(ClientConfig {
-                endpoints: vec![format!("ws://{addr}")],
-                shuffle_endpoints: false,
-            }),
-            server: Some(ServerConfig {
-                listen_address: "0.0.0.0".to_string(),
-                port: 0,
-                max_connections: 10,
-                max_batch_size: None,
-                request_timeout_seconds: 120,
-                http_methods: Vec::new(),
-                cors: None,
-            }),
-            merge_subscription: Some(MergeSubscriptionConfig {
-                keep_alive_seconds: Some(1),
-            }),
-            ..Default::default()
-        },
         middlewares: MiddlewaresConfig {
             methods: vec![],
             subscriptions: vec!["merge_subscription".to_string(), "upstream".to_string()],
@@ -68,6 +48,16 @@ async fn upstream_error_propagate() {
             ],
             aliases: vec![],
         },
+        ..super::config(ExtensionsConfig {
+            client: Some(ClientConfig {
+                endpoints: vec![format!("ws://{addr}")],
+                shuffle_endpoints: false,
+            }),
+            merge_subscription: Some(MergeSubscriptionConfig {
+                keep_alive_seconds: Some(1),
+            }),
+            ..Default::default()
+        })
     };
 
     let subway_server = server::build(config).await.unwrap();
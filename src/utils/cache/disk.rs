@@ -0,0 +1,460 @@
+use alloy_primitives::hex;
+use async_trait::async_trait;
+use blake2::Digest;
+use jsonrpsee::core::JsonValue;
+use redb::{Database, Durability, ReadableTable, TableDefinition};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use super::{CacheBackend, CacheKey, CacheUsage};
+
+// name => (order of the write, value)
+const ENTRIES: TableDefinition<&str, (u64, &[u8])> = TableDefinition::new("entries");
+
+const DB_FILE: &str = "cache.redb";
+
+/// An embedded database in a directory, evicting the least recently used
+/// entries once their names and values exceed `max_bytes`. Entries survive restarts.
+///
+/// redb doesn't evict on its own, so the size and last use of the entries
+/// are tracked in memory.
+pub struct DiskStore {
+    db: Arc<Database>,
+    max_bytes: u64,
+    index: Arc<Mutex<Index>>,
+}
+
+#[derive(Default)]
+struct Index {
+    // name => (size, last use)
+    entries: HashMap<String, (u64, u64)>,
+    // last use => name
+    lru: BTreeMap<u64, String>,
+    bytes: u64,
+    tick: u64,
+}
+
+impl Index {
+    fn touch(&mut self, name: &str) -> bool {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(name) {
+            Some((_, used)) => {
+                self.lru.remove(used);
+                *used = tick;
+                self.lru.insert(tick, name.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the tick of the insert.
+    fn insert(&mut self, name: &str, size: u64) -> u64 {
+        self.remove(name);
+        self.tick += 1;
+        self.entries.insert(name.to_string(), (size, self.tick));
+        self.lru.insert(self.tick, name.to_string());
+        self.bytes += size;
+        self.tick
+    }
+
+    fn remove(&mut self, name: &str) -> bool {
+        match self.entries.remove(name) {
+            Some((size, used)) => {
+                self.lru.remove(&used);
+                self.bytes -= size;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drops the least recently used entries until at most `max_bytes` are used.
+    fn evict(&mut self, max_bytes: u64) -> Vec<String> {
+        let mut evicted = vec![];
+        while self.bytes > max_bytes {
+            let Some((_, name)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some((size, _)) = self.entries.remove(&name) {
+                self.bytes -= size;
+            }
+            evicted.push(name);
+        }
+        evicted
+    }
+}
+
+fn entry_size(name: &str, value: &[u8]) -> u64 {
+    (name.len() + value.len()) as u64
+}
+
+impl DiskStore {
+    /// Opens the store in `dir`, creating it if needed. Entries already in the
+    /// store are loaded, the most recently written ones are evicted last.
+    pub async fn open(dir: impl AsRef<Path>, max_bytes: u64) -> anyhow::Result<Self> {
+        let path = dir.as_ref().join(DB_FILE);
+        let dir = dir.as_ref().to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            std::fs::create_dir_all(&dir)?;
+            let db = Database::create(path)?;
+
+            let mut entries = vec![];
+            let txn = db.begin_write()?;
+            {
+                let table = txn.open_table(ENTRIES)?;
+                for entry in table.iter()? {
+                    let (name, value) = entry?;
+                    let (written, bytes) = value.value();
+                    entries.push((written, name.value().to_string(), entry_size(name.value(), bytes)));
+                }
+            }
+            txn.commit()?;
+            entries.sort();
+
+            let mut index = Index::default();
+            for (written, name, size) in entries {
+                // later writes continue the order of the loaded ones
+                index.tick = written.saturating_sub(1);
+                index.insert(&name, size);
+            }
+            let evicted = index.evict(max_bytes);
+            remove_entries(&db, &evicted)?;
+
+            Ok(Self {
+                db: Arc::new(db),
+                max_bytes,
+                index: Arc::new(Mutex::new(index)),
+            })
+        })
+        .await?
+    }
+
+    pub async fn get(&self, name: &str) -> Option<Vec<u8>> {
+        if !self.index.lock().expect("disk cache lock poisoned").touch(name) {
+            return None;
+        }
+        let db = self.db.clone();
+        let key = name.to_string();
+        let read = tokio::task::spawn_blocking(move || -> anyhow::Result<Option<Vec<u8>>> {
+            let txn = db.begin_read()?;
+            let table = txn.open_table(ENTRIES)?;
+            let value = table.get(key.as_str())?;
+            Ok(value.map(|value| value.value().1.to_vec()))
+        })
+        .await;
+        match read {
+            Ok(Ok(value)) => value,
+            Ok(Err(err)) => {
+                tracing::warn!("Failed to read disk cache entry {name}: {err}");
+                self.index.lock().expect("disk cache lock poisoned").remove(name);
+                None
+            }
+            Err(err) => {
+                tracing::warn!("Failed to read disk cache entry {name}: {err}");
+                None
+            }
+        }
+    }
+
+    pub async fn insert(&self, name: &str, bytes: &[u8]) {
+        let size = entry_size(name, bytes);
+        if size > self.max_bytes {
+            return;
+        }
+        let db = self.db.clone();
+        let index = self.index.clone();
+        let max_bytes = self.max_bytes;
+        let key = name.to_string();
+        let value = bytes.to_vec();
+        let written = tokio::task::spawn_blocking(move || -> anyhow::Result<()> {
+            // write transactions are exclusive, so the index is updated in the order of the writes
+            let mut txn = db.begin_write()?;
+            // losing the last entries on a crash is fine for a cache
+            txn.set_durability(Durability::Eventual);
+            let (written, evicted) = {
+                let mut index = index.lock().expect("disk cache lock poisoned");
+                let written = index.insert(&key, size);
+                (written, index.evict(max_bytes))
+            };
+            let result = (|| {
+                {
+                    let mut table = txn.open_table(ENTRIES)?;
+                    table.insert(key.as_str(), (written, value.as_slice()))?;
+                    for name in &evicted {
+                        table.remove(name.as_str())?;
+                    }
+                }
+                txn.commit()?;
+                Ok(())
+            })();
+            if result.is_err() {
+                index.lock().expect("disk cache lock poisoned").remove(&key);
+            }
+            result
+        })
+        .await;
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::warn!("Failed to write disk cache entry {name}: {err}"),
+            Err(err) => tracing::warn!("Failed to write disk cache entry {name}: {err}"),
+        }
+    }
+
+    pub async fn remove(&self, name: &str) {
+        if self.index.lock().expect("disk cache lock poisoned").remove(name) {
+            self.remove_entries(vec![name.to_string()]).await;
+        }
+    }
+
+    /// Removes the entries whose name starts with `prefix`.
+    pub async fn remove_prefix(&self, prefix: &str) {
+        let names: Vec<String> = {
+            let mut index = self.index.lock().expect("disk cache lock poisoned");
+            let names: Vec<String> = index
+                .entries
+                .keys()
+                .filter(|name| name.starts_with(prefix))
+                .cloned()
+                .collect();
+            for name in &names {
+                index.remove(name);
+            }
+            names
+        };
+        self.remove_entries(names).await;
+    }
+
+    async fn remove_entries(&self, names: Vec<String>) {
+        if names.is_empty() {
+            return;
+        }
+        let db = self.db.clone();
+        match tokio::task::spawn_blocking(move || remove_entries(&db, &names)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::warn!("Failed to remove disk cache entries: {err}"),
+            Err(err) => tracing::warn!("Failed to remove disk cache entries: {err}"),
+        }
+    }
+
+    /// Bytes taken by the names and values of the entries.
+    pub fn size(&self) -> u64 {
+        self.index.lock().expect("disk cache lock poisoned").bytes
+    }
+}
+
+fn remove_entries(db: &Database, names: &[String]) -> anyhow::Result<()> {
+    if names.is_empty() {
+        return Ok(());
+    }
+    let mut txn = db.begin_write()?;
+    txn.set_durability(Durability::Eventual);
+    {
+        let mut table = txn.open_table(ENTRIES)?;
+        for name in names {
+            table.remove(name.as_str())?;
+        }
+    }
+    txn.commit()?;
+    Ok(())
+}
+
+/// Decides whether a value never changes and may be kept on disk.
+pub type Persist = Arc<dyn Fn(&JsonValue) -> bool + Send + Sync>;
+
+/// Keeps the values accepted by `persist` on disk too, so they are still
+/// available after they are evicted from `inner` or after a restart.
+/// Entries are named with `prefix`, so they can be cleared apart from the
+/// entries of other tiers sharing the disk store.
+pub struct DiskTier<D: Digest> {
+    prefix: String,
+    inner: Arc<dyn CacheBackend<D>>,
+    disk: Arc<DiskStore>,
+    persist: Persist,
+}
+
+impl<D: Digest> DiskTier<D> {
+    pub fn new(prefix: &str, inner: Arc<dyn CacheBackend<D>>, disk: Arc<DiskStore>, persist: Persist) -> Self {
+        Self {
+            prefix: format!("{prefix}-"),
+            inner,
+            disk,
+            persist,
+        }
+    }
+
+    fn name(&self, key: &CacheKey<D>) -> String {
+        format!("{}{}", self.prefix, hex::encode(key.0.as_slice()))
+    }
+}
+
+#[async_trait]
+impl<D: Digest + Send + Sync + 'static> CacheBackend<D> for DiskTier<D> {
+    async fn get(&self, key: &CacheKey<D>) -> Option<JsonValue> {
+        if let Some(value) = self.inner.get(key).await {
+            return Some(value);
+        }
+        let bytes = self.disk.get(&self.name(key)).await?;
+        let value: JsonValue = serde_json::from_slice(&bytes).ok()?;
+        self.inner.insert(key, value.clone()).await;
+        Some(value)
+    }
+
+    async fn insert(&self, key: &CacheKey<D>, value: JsonValue) {
+        if (self.persist)(&value) {
+            self.disk.insert(&self.name(key), value.to_string().as_bytes()).await;
+        }
+        self.inner.insert(key, value).await;
+    }
+
+    async fn remove(&self, key: &CacheKey<D>) {
+        self.disk.remove(&self.name(key)).await;
+        self.inner.remove(key).await;
+    }
+
+    async fn clear(&self) {
+        self.disk.remove_prefix(&self.prefix).await;
+        self.inner.clear().await;
+    }
+
+    /// Only the values in `inner`, the disk store is shared.
+    async fn usage(&self) -> Option<CacheUsage> {
+        self.inner.usage().await
+    }
+
+    async fn sync(&self) {
+        self.inner.sync().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("subway-disk-cache-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn evicts_least_recently_used() {
+        let dir = dir("lru");
+        let store = DiskStore::open(&dir, 10).await.unwrap();
+
+        store.insert("a", b"1234").await;
+        store.insert("b", b"1234").await;
+        assert_eq!(store.get("a").await, Some(b"1234".to_vec()));
+        store.insert("c", b"1234").await;
+
+        // b was used least recently
+        assert_eq!(store.get("b").await, None);
+        assert_eq!(store.get("a").await, Some(b"1234".to_vec()));
+        assert_eq!(store.get("c").await, Some(b"1234".to_vec()));
+        assert_eq!(store.size(), 10);
+
+        // too big to ever fit
+        store.insert("d", &[0; 10]).await;
+        assert_eq!(store.get("d").await, None);
+
+        store.remove("a").await;
+        assert_eq!(store.get("a").await, None);
+        assert_eq!(store.size(), 5);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn concurrent_writes_of_an_entry() {
+        let dir = dir("concurrent");
+        let store = Arc::new(DiskStore::open(&dir, 1000).await.unwrap());
+
+        let writes: Vec<_> = (0..10u8)
+            .map(|i| {
+                let store = store.clone();
+                tokio::spawn(async move { store.insert("a", &[i; 10]).await })
+            })
+            .collect();
+        for write in writes {
+            write.await.unwrap();
+        }
+
+        let value = store.get("a").await.unwrap();
+        assert_eq!(value.len(), 10);
+        assert!(value.iter().all(|b| *b == value[0]));
+        assert_eq!(store.size(), 11);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn survives_restarts() {
+        let dir = dir("restart");
+        let store = DiskStore::open(&dir, 100).await.unwrap();
+        store.insert("a", b"value").await;
+        store.insert("b", b"value").await;
+        store.insert("a", b"value").await;
+        drop(store);
+
+        let store = DiskStore::open(&dir, 100).await.unwrap();
+        assert_eq!(store.get("a").await, Some(b"value".to_vec()));
+        assert_eq!(store.size(), 12);
+        store.insert("c", b"value").await;
+
+        // a smaller bound evicts the least recently written on open
+        drop(store);
+        let store = DiskStore::open(&dir, 12).await.unwrap();
+        assert_eq!(store.get("b").await, None);
+        assert_eq!(store.get("a").await, Some(b"value".to_vec()));
+        assert_eq!(store.get("c").await, Some(b"value".to_vec()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn disk_tier_keeps_persisted_values() {
+        use crate::utils::{Cache, MemoryBackend};
+        use blake2::Blake2b512;
+        use serde_json::json;
+        use std::num::NonZeroUsize;
+
+        let dir = dir("tier");
+        let disk = Arc::new(DiskStore::open(&dir, 1 << 20).await.unwrap());
+        let persist: Persist = Arc::new(|value| value["final"] == json!(true));
+        let cache = |disk: Arc<DiskStore>| {
+            let memory = Arc::new(MemoryBackend::<Blake2b512>::new(NonZeroUsize::new(1).unwrap(), None));
+            Cache::with_backend(Arc::new(DiskTier::new("foo", memory, disk, persist.clone())))
+        };
+        let key = |n: u64| CacheKey::<Blake2b512>::new(&"foo".to_string(), &[json!(n)]);
+
+        let first = cache(disk.clone());
+        first.insert(key(1), json!({"final": true})).await;
+        first.insert(key(2), json!({"final": false})).await;
+        first.sync().await;
+
+        // a new cache, like after a restart, only has the persisted values
+        drop(first);
+        drop(disk);
+        let disk = Arc::new(DiskStore::open(&dir, 1 << 20).await.unwrap());
+        let second = cache(disk.clone());
+        assert_eq!(second.get(&key(1)).await, Some(json!({"final": true})));
+        assert_eq!(second.get(&key(2)).await, None);
+
+        second.remove(&key(1)).await;
+        assert_eq!(second.get(&key(1)).await, None);
+
+        // only the tier's own entries are cleared
+        disk.insert("other", b"1").await;
+        second.insert(key(3), json!({"final": true})).await;
+        second.clear().await;
+        assert_eq!(second.get(&key(3)).await, None);
+        assert_eq!(disk.get("other").await, Some(b"1".to_vec()));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,582 @@
+use crate::middlewares::CallResult;
+use async_trait::async_trait;
+use blake2::{digest::Output, Digest};
+use futures::future::BoxFuture;
+use jsonrpsee::core::JsonValue;
+use jsonrpsee::types::ErrorObjectOwned;
+use std::collections::HashMap;
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+use std::time::Duration;
+use tokio::sync::watch;
+
+mod disk;
+mod redis;
+mod shared;
+
+pub use self::disk::{DiskStore, DiskTier, Persist};
+pub use self::redis::RedisBackend;
+pub use self::shared::{SharedBackend, SharedLimits, SharedStore};
+
+#[derive(Debug)]
+pub struct CacheKey<D: Digest>(pub Output<D>);
+
+impl<D: Digest> Clone for CacheKey<D> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<D: Digest> CacheKey<D> {
+    pub fn new(method: &String, params: &[JsonValue]) -> Self {
+        let mut hasher = D::new();
+        hasher.update(method.as_bytes());
+        for p in params {
+            hasher.update(p.to_string().as_bytes());
+        }
+
+        Self(hasher.finalize())
+    }
+}
+
+impl<D: Digest> PartialEq for CacheKey<D> {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_slice() == other.0.as_slice()
+    }
+}
+
+impl<D: Digest> Eq for CacheKey<D> {}
+
+impl<D: Digest> std::hash::Hash for CacheKey<D> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.0.as_slice().hash(state);
+    }
+}
+
+type Pending = watch::Receiver<Option<Result<JsonValue, ErrorObjectOwned>>>;
+
+/// Where the cached values are kept.
+#[async_trait]
+pub trait CacheBackend<D: Digest>: Send + Sync {
+    async fn get(&self, key: &CacheKey<D>) -> Option<JsonValue>;
+
+    async fn insert(&self, key: &CacheKey<D>, value: JsonValue);
+
+    async fn remove(&self, key: &CacheKey<D>);
+
+    /// Removes all the values.
+    async fn clear(&self);
+
+    /// The values held, if the backend can tell.
+    async fn usage(&self) -> Option<CacheUsage> {
+        None
+    }
+
+    /// Applies the pending evictions, only needed by tests.
+    async fn sync(&self) {}
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheUsage {
+    pub entries: u64,
+    /// Serialized size of the keys and values.
+    pub bytes: u64,
+}
+
+/// Lookups of a cache.
+#[derive(Debug, Default)]
+pub struct CacheCounters {
+    queries: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheCounters {
+    pub fn query(&self) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn miss(&self) {
+        self.misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.queries().saturating_sub(self.misses())
+    }
+
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+/// How much a cache may hold.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capacity {
+    Entries(NonZeroUsize),
+    /// Serialized size of the keys and values.
+    Bytes(NonZeroU64),
+}
+
+/// Values kept in process, evicting the least recently used ones.
+pub struct MemoryBackend<D: Digest> {
+    cache: moka::future::Cache<CacheKey<D>, SizedValue>,
+    // serialized size of the keys and values, moka already tracks it when bounded by bytes
+    bytes: Option<Arc<AtomicU64>>,
+}
+
+/// A value with the serialized size of its entry, computed once on insert.
+#[derive(Clone)]
+struct SizedValue {
+    value: JsonValue,
+    bytes: u64,
+}
+
+impl<D: Digest + 'static> MemoryBackend<D> {
+    pub fn new(size: NonZeroUsize, ttl: Option<Duration>) -> Self {
+        Self::with_capacity(Capacity::Entries(size), ttl)
+    }
+
+    pub fn with_capacity(capacity: Capacity, ttl: Option<Duration>) -> Self {
+        let (mut builder, bytes) = match capacity {
+            Capacity::Entries(size) => {
+                let size = size.get();
+                let bytes = Arc::new(AtomicU64::new(0));
+                let evicted = bytes.clone();
+                let builder = moka::future::Cache::<CacheKey<D>, SizedValue>::builder()
+                    .max_capacity(size as u64)
+                    .initial_capacity(size)
+                    .eviction_listener(move |_, sized: SizedValue, _| {
+                        evicted.fetch_sub(sized.bytes, Ordering::Relaxed);
+                    });
+                (builder, Some(bytes))
+            }
+            Capacity::Bytes(bytes) => {
+                let builder = moka::future::Cache::<CacheKey<D>, SizedValue>::builder()
+                    .max_capacity(bytes.get())
+                    .weigher(|_, sized: &SizedValue| sized.bytes.try_into().unwrap_or(u32::MAX));
+                (builder, None)
+            }
+        };
+
+        if let Some(duration) = ttl {
+            builder = builder.time_to_live(duration);
+        }
+
+        Self {
+            cache: builder.build(),
+            bytes,
+        }
+    }
+
+    /// Bytes used by the entries, or their number if the capacity is in entries.
+    pub fn weighted_size(&self) -> u64 {
+        self.cache.weighted_size()
+    }
+}
+
+/// Length of the value serialized as JSON.
+pub fn json_size(value: &JsonValue) -> usize {
+    struct Counter(usize);
+
+    impl std::io::Write for Counter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0 += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let mut counter = Counter(0);
+    // writing to the counter cannot fail
+    let _ = serde_json::to_writer(&mut counter, value);
+    counter.0
+}
+
+#[async_trait]
+impl<D: Digest + Send + Sync + 'static> CacheBackend<D> for MemoryBackend<D> {
+    async fn get(&self, key: &CacheKey<D>) -> Option<JsonValue> {
+        self.cache.get(key).await.map(|sized| sized.value)
+    }
+
+    async fn insert(&self, key: &CacheKey<D>, value: JsonValue) {
+        let bytes = (key.0.len() + json_size(&value)) as u64;
+        if let Some(total) = &self.bytes {
+            total.fetch_add(bytes, Ordering::Relaxed);
+        }
+        self.cache.insert(key.clone(), SizedValue { value, bytes }).await;
+    }
+
+    async fn remove(&self, key: &CacheKey<D>) {
+        self.cache.remove(key).await;
+    }
+
+    async fn clear(&self) {
+        self.cache.invalidate_all();
+        self.cache.run_pending_tasks().await;
+    }
+
+    async fn usage(&self) -> Option<CacheUsage> {
+        self.cache.run_pending_tasks().await;
+        let bytes = match &self.bytes {
+            Some(bytes) => bytes.load(Ordering::Relaxed),
+            None => self.cache.weighted_size(),
+        };
+        Some(CacheUsage {
+            entries: self.cache.entry_count(),
+            bytes,
+        })
+    }
+
+    async fn sync(&self) {
+        self.cache.run_pending_tasks().await;
+    }
+}
+
+/// Caches values in a backend. Concurrent requests for a missing value are
+/// deduplicated locally, only one of them calls the upstream.
+pub struct Cache<D: Digest> {
+    backend: Arc<dyn CacheBackend<D>>,
+    pending: Arc<Mutex<HashMap<CacheKey<D>, Pending>>>,
+    counters: Arc<CacheCounters>,
+}
+
+impl<D: Digest> Clone for Cache<D> {
+    fn clone(&self) -> Self {
+        Self {
+            backend: self.backend.clone(),
+            pending: self.pending.clone(),
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+/// Removes the pending entry once the fetch completes or is canceled.
+struct PendingGuard<'a, D: Digest> {
+    pending: &'a Mutex<HashMap<CacheKey<D>, Pending>>,
+    key: CacheKey<D>,
+    rx: Pending,
+}
+
+impl<D: Digest> Drop for PendingGuard<'_, D> {
+    fn drop(&mut self) {
+        let mut pending = self.pending.lock().expect("cache pending lock poisoned");
+        if pending.get(&self.key).is_some_and(|rx| rx.same_channel(&self.rx)) {
+            pending.remove(&self.key);
+        }
+    }
+}
+
+impl<D: Digest + Send + Sync + 'static> Cache<D> {
+    /// An in process cache.
+    pub fn new(size: NonZeroUsize, ttl: Option<Duration>) -> Self {
+        Self::with_backend(Arc::new(MemoryBackend::new(size, ttl)))
+    }
+
+    pub fn with_backend(backend: Arc<dyn CacheBackend<D>>) -> Self {
+        Self {
+            backend,
+            pending: Default::default(),
+            counters: Default::default(),
+        }
+    }
+
+    /// Hits and misses, as counted by the cache's users.
+    pub fn counters(&self) -> &CacheCounters {
+        &self.counters
+    }
+
+    fn pending(&self, key: &CacheKey<D>) -> Option<Pending> {
+        self.pending
+            .lock()
+            .expect("cache pending lock poisoned")
+            .get(key)
+            .cloned()
+    }
+
+    pub async fn get(&self, key: &CacheKey<D>) -> Option<JsonValue> {
+        match self.pending(key) {
+            Some(mut rx) => {
+                if let Some(value) = rx.borrow().clone() {
+                    return value.ok();
+                }
+                let _ = rx.changed().await;
+                let value = rx.borrow().clone();
+                if let Some(value) = value {
+                    value.ok()
+                } else {
+                    tracing::error!("Cache: Unreachable code");
+                    None
+                }
+            }
+            None => self.backend.get(key).await,
+        }
+    }
+
+    pub async fn insert(&self, key: CacheKey<D>, value: JsonValue) {
+        self.backend.insert(&key, value).await;
+    }
+
+    pub async fn get_or_insert_with<F>(&self, key: CacheKey<D>, f: F) -> CallResult
+    where
+        F: FnOnce() -> BoxFuture<'static, CallResult>,
+    {
+        loop {
+            if let Some(mut rx) = self.pending(&key) {
+                let value = rx.borrow().clone();
+                if let Some(value) = value {
+                    return value;
+                }
+
+                let _ = rx.changed().await;
+
+                let value = rx.borrow().clone();
+                if let Some(value) = value {
+                    return value;
+                }
+
+                // this only happens when initial fetch request got canceled for some reason
+                // in that case we need to fetch again
+                continue;
+            }
+
+            if let Some(value) = self.backend.get(&key).await {
+                return Ok(value);
+            }
+
+            let (tx, rx) = watch::channel(None);
+            {
+                let mut pending = self.pending.lock().expect("cache pending lock poisoned");
+                if pending.contains_key(&key) {
+                    // another request started fetching meanwhile
+                    continue;
+                }
+                pending.insert(key.clone(), rx.clone());
+            }
+            let _guard = PendingGuard {
+                pending: &self.pending,
+                key: key.clone(),
+                rx,
+            };
+
+            let value = f().await;
+            let _ = tx.send(Some(value.clone()));
+            if let Ok(value) = &value {
+                self.backend.insert(&key, value.clone()).await;
+            }
+            return value;
+        }
+    }
+
+    pub async fn remove(&self, key: &CacheKey<D>) {
+        self.backend.remove(key).await;
+    }
+
+    pub async fn clear(&self) {
+        self.backend.clear().await;
+    }
+
+    pub async fn usage(&self) -> Option<CacheUsage> {
+        self.backend.usage().await
+    }
+
+    pub async fn sync(&self) {
+        self.backend.sync().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt as _;
+    use jsonrpsee::types::error::reject_too_big_request;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn get_insert_remove() {
+        let cache = Cache::<blake2::Blake2b512>::new(NonZeroUsize::new(1).unwrap(), None);
+
+        let key = CacheKey::<blake2::Blake2b512>::new(&"key".to_string(), &[]);
+
+        assert_eq!(cache.get(&key).await, None);
+
+        cache.insert(key.clone(), json!("value")).await;
+
+        assert_eq!(cache.get(&key).await, Some(json!("value")));
+
+        cache.remove(&key).await;
+
+        assert_eq!(cache.get(&key).await, None);
+    }
+
+    #[tokio::test]
+    async fn bounded_by_bytes() {
+        type Key = CacheKey<blake2::Blake2b512>;
+        let key = |n: u64| Key::new(&"key".to_string(), &[json!(n)]);
+        // 64 bytes per key
+        let backend = MemoryBackend::with_capacity(Capacity::Bytes(NonZeroU64::new(300).unwrap()), None);
+
+        assert_eq!(json_size(&json!({"a": [1, "b"]})), r#"{"a":[1,"b"]}"#.len());
+
+        // many small entries fit
+        for n in 0..4 {
+            backend.insert(&key(n), json!(n)).await;
+        }
+        backend.sync().await;
+        assert_eq!(backend.weighted_size(), 4 * 65);
+
+        // a big entry is only kept if others are evicted
+        backend.insert(&key(10), json!("x".repeat(150))).await;
+        backend.sync().await;
+        assert!(backend.weighted_size() <= 300);
+
+        // too big to ever fit
+        backend.insert(&key(11), json!("x".repeat(300))).await;
+        backend.sync().await;
+        assert_eq!(backend.get(&key(11)).await, None);
+    }
+
+    #[tokio::test]
+    async fn usage_tracks_replaced_and_evicted_entries() {
+        type Key = CacheKey<blake2::Blake2b512>;
+        let key = |n: u64| Key::new(&"key".to_string(), &[json!(n)]);
+        let backend = MemoryBackend::new(NonZeroUsize::new(2).unwrap(), None);
+
+        backend.insert(&key(0), json!(0)).await;
+        backend.insert(&key(1), json!(1)).await;
+        let usage = backend.usage().await.unwrap();
+        assert_eq!((usage.entries, usage.bytes), (2, 2 * 65));
+
+        // replacing an entry only counts the new value
+        backend.insert(&key(0), json!("00")).await;
+        assert_eq!(backend.usage().await.unwrap().bytes, 65 + 68);
+
+        backend.remove(&key(1)).await;
+        assert_eq!(backend.usage().await.unwrap().bytes, 68);
+
+        // evicted entries are no longer counted
+        for n in 2..6 {
+            backend.insert(&key(n), json!(n)).await;
+        }
+        let usage = backend.usage().await.unwrap();
+        assert_eq!(
+            usage.bytes,
+            usage.entries * 65 + (backend.get(&key(0)).await.is_some() as u64) * 3
+        );
+    }
+
+    #[tokio::test]
+    async fn get_or_insert_with_basic() {
+        let cache = Cache::<blake2::Blake2b512>::new(NonZeroUsize::new(1).unwrap(), None);
+
+        let key = CacheKey::<blake2::Blake2b512>::new(&"key".to_string(), &[]);
+
+        let (tx, rx) = tokio::sync::oneshot::channel::<()>();
+
+        let cache2 = cache.clone();
+        let key2 = key.clone();
+        let h1 = tokio::spawn(async move {
+            let value = cache2
+                .get_or_insert_with(key2.clone(), || {
+                    async move {
+                        let _ = rx.await;
+                        Ok(json!("value"))
+                    }
+                    .boxed()
+                })
+                .await;
+            assert_eq!(value, Ok(json!("value")));
+        });
+
+        tokio::task::yield_now().await;
+
+        let cache2 = cache.clone();
+        let key2 = key.clone();
+        let h2 = tokio::spawn(async move {
+            let value = cache2
+                .get_or_insert_with(key2, || {
+                    async {
+                        panic!();
+                    }
+                    .boxed()
+                })
+                .await;
+            assert_eq!(value, Ok(json!("value")));
+        });
+
+        tokio::task::yield_now().await;
+
+        tx.send(()).unwrap();
+
+        h1.await.unwrap();
+        h2.await.unwrap();
+
+        assert_eq!(cache.get(&key).await, Some(json!("value")));
+    }
+
+    #[tokio::test]
+    async fn get_or_insert_with_handle_canceled_request() {
+        let cache = Cache::<blake2::Blake2b512>::new(NonZeroUsize::new(1).unwrap(), None);
+
+        let key = CacheKey::<blake2::Blake2b512>::new(&"key".to_string(), &[]);
+
+        let (_tx, rx) = tokio::sync::oneshot::channel::<()>();
+
+        let cache2 = cache.clone();
+        let key2 = key.clone();
+        let h1 = tokio::spawn(async move {
+            let _ = cache2
+                .get_or_insert_with(key2.clone(), || {
+                    async move {
+                        let _ = rx.await;
+                        panic!();
+                    }
+                    .boxed()
+                })
+                .await;
+            unreachable!();
+        });
+
+        tokio::task::yield_now().await;
+
+        let cache2 = cache.clone();
+        let key2 = key.clone();
+        let h2 = tokio::spawn(async move {
+            let value = cache2
+                .get_or_insert_with(key2, || async { Ok(json!("value")) }.boxed())
+                .await;
+            assert_eq!(value, Ok(json!("value")));
+        });
+
+        tokio::task::yield_now().await;
+
+        h1.abort(); // first request failed for whatever reason
+
+        h1.await.unwrap_err();
+        h2.await.unwrap(); // second request should still work
+
+        assert_eq!(cache.get(&key).await, Some(json!("value")));
+    }
+
+    #[tokio::test]
+    async fn get_or_insert_with_error() {
+        let cache = Cache::<blake2::Blake2b512>::new(NonZeroUsize::new(1).unwrap(), None);
+
+        let key = CacheKey::<blake2::Blake2b512>::new(&"key".to_string(), &[]);
+
+        let value = cache
+            .get_or_insert_with(key.clone(), || async move { Err(reject_too_big_request(100)) }.boxed())
+            .await;
+        assert_eq!(value, Err(reject_too_big_request(100)));
+
+        assert_eq!(cache.get(&key).await, None);
+    }
+}
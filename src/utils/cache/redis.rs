@@ -0,0 +1,200 @@
+use alloy_primitives::hex;
+use async_trait::async_trait;
+use blake2::Digest;
+use jsonrpsee::core::JsonValue;
+use std::{sync::Arc, time::Duration};
+
+use super::{CacheBackend, CacheKey};
+use crate::utils::redis::RedisClient;
+use crate::utils::redis::RedisValue;
+
+/// Values shared through a Redis-protocol server, so replicas don't each warm
+/// their own cache. The server evicts entries according to its own memory policy.
+///
+/// If the server can't be reached, lookups miss and values are not stored.
+pub struct RedisBackend {
+    client: Arc<RedisClient>,
+    key_prefix: String,
+    ttl: Option<Duration>,
+}
+
+impl RedisBackend {
+    pub fn new(client: Arc<RedisClient>, key_prefix: String, ttl: Option<Duration>) -> Self {
+        Self {
+            client,
+            key_prefix,
+            ttl,
+        }
+    }
+
+    fn key<D: Digest>(&self, key: &CacheKey<D>) -> String {
+        format!("{}:{}", self.key_prefix, hex::encode(key.0.as_slice()))
+    }
+}
+
+#[async_trait]
+impl<D: Digest + Send + Sync + 'static> CacheBackend<D> for RedisBackend {
+    async fn get(&self, key: &CacheKey<D>) -> Option<JsonValue> {
+        let key = self.key(key);
+        let bytes = match self.client.query(&[b"GET", key.as_bytes()]).await {
+            Ok(reply) => reply.into_bytes(),
+            Err(err) => Err(err),
+        };
+        match bytes {
+            Ok(bytes) => bytes.and_then(|bytes| serde_json::from_slice(&bytes).ok()),
+            Err(err) => {
+                tracing::warn!("Failed to read {key} from redis cache: {err}");
+                None
+            }
+        }
+    }
+
+    async fn insert(&self, key: &CacheKey<D>, value: JsonValue) {
+        let key = self.key(key);
+        let value = value.to_string();
+        let ttl = self.ttl.map(|ttl| ttl.as_millis().max(1).to_string());
+        let mut command: Vec<&[u8]> = vec![b"SET", key.as_bytes(), value.as_bytes()];
+        if let Some(ttl) = &ttl {
+            command.extend([b"PX".as_slice(), ttl.as_bytes()]);
+        }
+        if let Err(err) = self.client.query(&command).await {
+            tracing::warn!("Failed to write {key} to redis cache: {err}");
+        }
+    }
+
+    async fn remove(&self, key: &CacheKey<D>) {
+        let key = self.key(key);
+        if let Err(err) = self.client.query(&[b"DEL", key.as_bytes()]).await {
+            tracing::warn!("Failed to remove {key} from redis cache: {err}");
+        }
+    }
+
+    async fn clear(&self) {
+        if let Err(err) = self.clear_prefix().await {
+            tracing::warn!("Failed to clear {} from redis cache: {err}", self.key_prefix);
+        }
+    }
+}
+
+impl RedisBackend {
+    /// Deletes the keys starting with the prefix, a batch at a time.
+    async fn clear_prefix(&self) -> anyhow::Result<()> {
+        let pattern = format!("{}:*", self.key_prefix);
+        let mut cursor = b"0".to_vec();
+        loop {
+            let reply = self
+                .client
+                .query(&[b"SCAN", &cursor, b"MATCH", pattern.as_bytes(), b"COUNT", b"1000"])
+                .await?;
+            let RedisValue::Array(mut reply) = reply else {
+                anyhow::bail!("Unexpected SCAN reply {reply:?}");
+            };
+            let keys = match reply.pop() {
+                Some(RedisValue::Array(keys)) => keys,
+                other => anyhow::bail!("Unexpected SCAN keys {other:?}"),
+            };
+            let keys = keys
+                .into_iter()
+                .filter_map(|key| key.into_bytes().ok().flatten())
+                .collect::<Vec<_>>();
+            if !keys.is_empty() {
+                let mut command: Vec<&[u8]> = vec![b"DEL"];
+                command.extend(keys.iter().map(Vec::as_slice));
+                self.client.query(&command).await?;
+            }
+            cursor = match reply.pop().map(RedisValue::into_bytes) {
+                Some(Ok(Some(cursor))) => cursor,
+                other => anyhow::bail!("Unexpected SCAN cursor {other:?}"),
+            };
+            if cursor == b"0" {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{redis::mock, Cache};
+    use blake2::Blake2b512;
+    use futures::FutureExt as _;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn shared_between_caches() {
+        let (addr, _handle) = mock::redis_server().await;
+        let client = Arc::new(RedisClient::new(&format!("redis://{addr}"), Duration::from_secs(1)).unwrap());
+        let backend = || Arc::new(RedisBackend::new(client.clone(), "test".to_string(), None));
+        let cache1 = Cache::<Blake2b512>::with_backend(backend());
+        let cache2 = Cache::<Blake2b512>::with_backend(backend());
+
+        let key = CacheKey::<Blake2b512>::new(&"key".to_string(), &[json!(1)]);
+        let value = cache1
+            .get_or_insert_with(key.clone(), || async { Ok(json!({"foo": "bar"})) }.boxed())
+            .await;
+        assert_eq!(value, Ok(json!({"foo": "bar"})));
+
+        // served by the other replica's cache
+        let value = cache2
+            .get_or_insert_with(key.clone(), || async { panic!() }.boxed())
+            .await;
+        assert_eq!(value, Ok(json!({"foo": "bar"})));
+
+        cache2.remove(&key).await;
+        assert_eq!(cache1.get(&key).await, None);
+    }
+
+    #[tokio::test]
+    async fn clears_own_prefix() {
+        let (addr, _handle) = mock::redis_server().await;
+        let client = Arc::new(RedisClient::new(&format!("redis://{addr}"), Duration::from_secs(1)).unwrap());
+        let cache = |prefix: &str| {
+            Cache::<Blake2b512>::with_backend(Arc::new(RedisBackend::new(client.clone(), prefix.to_string(), None)))
+        };
+        let (foo, foobar) = (cache("test:foo"), cache("test:foobar"));
+
+        let key = CacheKey::<Blake2b512>::new(&"key".to_string(), &[]);
+        foo.insert(key.clone(), json!(1)).await;
+        foobar.insert(key.clone(), json!(2)).await;
+
+        foo.clear().await;
+        assert_eq!(foo.get(&key).await, None);
+        assert_eq!(foobar.get(&key).await, Some(json!(2)));
+    }
+
+    #[tokio::test]
+    async fn expires() {
+        let (addr, _handle) = mock::redis_server().await;
+        let client = Arc::new(RedisClient::new(&format!("redis://{addr}"), Duration::from_secs(1)).unwrap());
+        let cache = Cache::<Blake2b512>::with_backend(Arc::new(RedisBackend::new(
+            client,
+            "test".to_string(),
+            Some(Duration::from_millis(50)),
+        )));
+
+        let key = CacheKey::<Blake2b512>::new(&"key".to_string(), &[]);
+        cache.insert(key.clone(), json!(1)).await;
+        assert_eq!(cache.get(&key).await, Some(json!(1)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(cache.get(&key).await, None);
+    }
+
+    #[tokio::test]
+    async fn misses_when_unreachable() {
+        let addr = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let client = Arc::new(RedisClient::new(&format!("redis://{addr}"), Duration::from_millis(100)).unwrap());
+        let cache = Cache::<Blake2b512>::with_backend(Arc::new(RedisBackend::new(client, "test".to_string(), None)));
+
+        let key = CacheKey::<Blake2b512>::new(&"key".to_string(), &[]);
+        let value = cache
+            .get_or_insert_with(key.clone(), || async { Ok(json!(2)) }.boxed())
+            .await;
+        assert_eq!(value, Ok(json!(2)));
+        assert_eq!(cache.get(&key).await, None);
+    }
+}
@@ -0,0 +1,508 @@
+use async_trait::async_trait;
+use blake2::Digest;
+use jsonrpsee::core::JsonValue;
+use std::{
+    collections::{BTreeMap, HashMap},
+    marker::PhantomData,
+    num::NonZeroU32,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use super::{json_size, CacheBackend, CacheKey, CacheUsage};
+
+/// How a method uses the shared budget.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SharedLimits {
+    /// Entries of methods with a higher weight are kept longer, with equal
+    /// weights the least recently used entries are evicted first.
+    pub weight: NonZeroU32,
+    /// Bytes kept for the method even if other methods need room.
+    pub min_bytes: Option<u64>,
+    /// Bytes the method may use at most.
+    pub max_bytes: Option<u64>,
+}
+
+impl Default for SharedLimits {
+    fn default() -> Self {
+        Self {
+            weight: NonZeroU32::MIN,
+            min_bytes: None,
+            max_bytes: None,
+        }
+    }
+}
+
+/// Entries are evicted in this order, (priority, last use).
+type Order = (u64, u64);
+
+/// Eviction bookkeeping of an entry, its value is kept in a [`Shard`].
+struct Entry {
+    method: usize,
+    bytes: u64,
+    // (expiry, insertion), unique among entries
+    expiry: Option<(Instant, u64)>,
+    order: Order,
+}
+
+struct Usage {
+    name: String,
+    limits: SharedLimits,
+    bytes: u64,
+    order: BTreeMap<Order, Box<[u8]>>,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<Box<[u8]>, Entry>,
+    expiry: BTreeMap<(Instant, u64), Box<[u8]>>,
+    methods: Vec<Usage>,
+    bytes: u64,
+    tick: u64,
+    // priority of the last evicted entry, entries used since then outrank the ones that were not
+    inflation: u64,
+}
+
+impl Index {
+    fn next_order(&mut self, method: usize) -> Order {
+        self.tick += 1;
+        let weight = self.methods[method].limits.weight.get() as u64;
+        (self.inflation + weight, self.tick)
+    }
+
+    fn touch(&mut self, key: &[u8]) {
+        let Some(method) = self.entries.get(key).map(|entry| entry.method) else {
+            return;
+        };
+        let order = self.next_order(method);
+        let entry = self.entries.get_mut(key).expect("entry exists");
+        let old = std::mem::replace(&mut entry.order, order);
+        let usage = &mut self.methods[method];
+        let key = usage.order.remove(&old).expect("entry is ordered");
+        usage.order.insert(order, key);
+    }
+
+    fn insert(&mut self, key: &[u8], method: usize, bytes: u64, expires_at: Option<Instant>) {
+        self.remove(key);
+        let order = self.next_order(method);
+        let key: Box<[u8]> = key.into();
+        let expiry = expires_at.map(|at| (at, self.tick));
+        if let Some(expiry) = expiry {
+            self.expiry.insert(expiry, key.clone());
+        }
+        let usage = &mut self.methods[method];
+        usage.order.insert(order, key.clone());
+        usage.bytes += bytes;
+        self.bytes += bytes;
+        self.entries.insert(
+            key,
+            Entry {
+                method,
+                bytes,
+                expiry,
+                order,
+            },
+        );
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        if let Some(expiry) = entry.expiry {
+            self.expiry.remove(&expiry);
+        }
+        let usage = &mut self.methods[entry.method];
+        usage.order.remove(&entry.order);
+        usage.bytes -= entry.bytes;
+        self.bytes -= entry.bytes;
+        Some(entry)
+    }
+
+    /// Evicts expired entries, then entries of methods above their ceiling, then
+    /// the lowest priority entries until `max_bytes` are used, keeping the floor
+    /// of each method. Returns the evicted keys.
+    fn evict(&mut self, method: usize, max_bytes: u64) -> Vec<Box<[u8]>> {
+        let mut evicted = vec![];
+
+        let now = Instant::now();
+        while let Some(entry) = self.expiry.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+            let key = entry.remove();
+            self.remove(&key);
+            evicted.push(key);
+        }
+
+        if let Some(ceiling) = self.methods[method].limits.max_bytes {
+            while self.methods[method].bytes > ceiling {
+                let Some((_, key)) = self.methods[method].order.first_key_value() else {
+                    break;
+                };
+                let key = key.clone();
+                self.remove(&key);
+                evicted.push(key);
+            }
+        }
+
+        while self.bytes > max_bytes {
+            // the lowest priority entry among methods that can give up their lowest one
+            let victim = self
+                .methods
+                .iter()
+                .filter_map(|usage| {
+                    let (order, key) = usage.order.first_key_value()?;
+                    let floor = usage.limits.min_bytes.unwrap_or_default();
+                    (usage.bytes - self.entries[key].bytes >= floor).then_some((*order, key))
+                })
+                .min_by_key(|(order, _)| *order)
+                .map(|(order, key)| (order, key.clone()));
+            let Some((order, key)) = victim else {
+                // the rest is kept by floors
+                break;
+            };
+            self.inflation = order.0;
+            self.remove(&key);
+            evicted.push(key);
+        }
+
+        evicted
+    }
+}
+
+const SHARDS: usize = 16;
+
+/// Reads remembered per shard until the next write, further reads don't change the order.
+const MAX_PENDING_READS: usize = 128;
+
+struct Value {
+    value: JsonValue,
+    expires_at: Option<Instant>,
+}
+
+/// Values of a part of the keys. Reads only lock their shard, the reads are
+/// applied to the eviction order on the next write.
+#[derive(Default)]
+struct Shard {
+    values: HashMap<Box<[u8]>, Value>,
+    reads: Vec<Box<[u8]>>,
+}
+
+/// One in process cache shared by the methods, bounded by the serialized size
+/// of all their entries.
+///
+/// Locks are taken in order: the index, then the shards.
+pub struct SharedStore {
+    max_bytes: u64,
+    index: Mutex<Index>,
+    shards: [Mutex<Shard>; SHARDS],
+}
+
+impl SharedStore {
+    pub fn new(max_bytes: u64) -> Self {
+        Self {
+            max_bytes,
+            index: Default::default(),
+            shards: Default::default(),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> MutexGuard<'_, Shard> {
+        let shard = key.last().copied().unwrap_or_default() as usize % SHARDS;
+        self.shards[shard].lock().expect("shared cache shard lock poisoned")
+    }
+
+    fn lock_index(&self) -> MutexGuard<'_, Index> {
+        let mut index = self.index.lock().expect("shared cache lock poisoned");
+        for shard in &self.shards {
+            let reads = std::mem::take(&mut shard.lock().expect("shared cache shard lock poisoned").reads);
+            for key in reads {
+                index.touch(&key);
+            }
+        }
+        index
+    }
+
+    fn remove_values(&self, keys: &[Box<[u8]>]) {
+        for key in keys {
+            self.shard(key).values.remove(key);
+        }
+    }
+
+    /// Registers a method using the store, returns its id.
+    pub fn register(&self, method: &str, limits: SharedLimits) -> usize {
+        let mut index = self.index.lock().expect("shared cache lock poisoned");
+        let id = match index.methods.iter().position(|usage| usage.name == method) {
+            Some(id) => {
+                index.methods[id].limits = limits;
+                id
+            }
+            None => {
+                index.methods.push(Usage {
+                    name: method.to_string(),
+                    limits,
+                    bytes: 0,
+                    order: Default::default(),
+                });
+                index.methods.len() - 1
+            }
+        };
+        let floors: u64 = index.methods.iter().filter_map(|usage| usage.limits.min_bytes).sum();
+        assert!(
+            floors <= self.max_bytes,
+            "Cache min_bytes of all methods exceed the shared max_bytes"
+        );
+        id
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<JsonValue> {
+        let now = Instant::now();
+        {
+            let mut shard = self.shard(key);
+            let value = shard.values.get(key)?;
+            if value.expires_at.map_or(true, |at| at > now) {
+                let value = value.value.clone();
+                if shard.reads.len() < MAX_PENDING_READS {
+                    shard.reads.push(key.into());
+                }
+                return Some(value);
+            }
+        }
+
+        // expired, unless it was replaced in the meantime
+        let mut index = self.lock_index();
+        let expired = index
+            .entries
+            .get(key)
+            .and_then(|entry| entry.expiry)
+            .map_or(false, |(at, _)| at <= now);
+        if expired {
+            index.remove(key);
+            self.shard(key).values.remove(key);
+        }
+        None
+    }
+
+    pub fn insert(&self, key: &[u8], method: usize, value: JsonValue, ttl: Option<Duration>) {
+        let bytes = (key.len() + json_size(&value)) as u64;
+        let mut index = self.lock_index();
+        let ceiling = index.methods[method].limits.max_bytes.unwrap_or(u64::MAX);
+        if bytes > self.max_bytes.min(ceiling) {
+            // too big to ever fit
+            index.remove(key);
+            self.shard(key).values.remove(key);
+            return;
+        }
+        let expires_at = ttl.map(|ttl| Instant::now() + ttl);
+        index.insert(key, method, bytes, expires_at);
+        self.shard(key).values.insert(key.into(), Value { value, expires_at });
+        let evicted = index.evict(method, self.max_bytes);
+        self.remove_values(&evicted);
+    }
+
+    pub fn remove(&self, key: &[u8]) {
+        let mut index = self.lock_index();
+        index.remove(key);
+        self.shard(key).values.remove(key);
+    }
+
+    /// Bytes used by all the entries.
+    pub fn size(&self) -> u64 {
+        self.index.lock().expect("shared cache lock poisoned").bytes
+    }
+
+    /// Bytes used by the entries of a method.
+    pub fn method_size(&self, method: usize) -> u64 {
+        self.index.lock().expect("shared cache lock poisoned").methods[method].bytes
+    }
+
+    pub fn method_usage(&self, method: usize) -> CacheUsage {
+        let index = self.index.lock().expect("shared cache lock poisoned");
+        let usage = &index.methods[method];
+        CacheUsage {
+            entries: usage.order.len() as u64,
+            bytes: usage.bytes,
+        }
+    }
+
+    /// Removes the entries of a method.
+    pub fn clear_method(&self, method: usize) {
+        let mut index = self.lock_index();
+        let keys: Vec<_> = index.methods[method].order.values().cloned().collect();
+        for key in &keys {
+            index.remove(key);
+        }
+        self.remove_values(&keys);
+    }
+}
+
+/// The entries of one method in a [`SharedStore`].
+pub struct SharedBackend<D: Digest> {
+    store: Arc<SharedStore>,
+    method: usize,
+    ttl: Option<Duration>,
+    _digest: PhantomData<fn() -> D>,
+}
+
+impl<D: Digest> SharedBackend<D> {
+    pub fn new(store: Arc<SharedStore>, method: &str, limits: SharedLimits, ttl: Option<Duration>) -> Self {
+        let method = store.register(method, limits);
+        Self {
+            store,
+            method,
+            ttl,
+            _digest: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<D: Digest + Send + Sync + 'static> CacheBackend<D> for SharedBackend<D> {
+    async fn get(&self, key: &CacheKey<D>) -> Option<JsonValue> {
+        self.store.get(key.0.as_slice())
+    }
+
+    async fn insert(&self, key: &CacheKey<D>, value: JsonValue) {
+        self.store.insert(key.0.as_slice(), self.method, value, self.ttl);
+    }
+
+    async fn remove(&self, key: &CacheKey<D>) {
+        self.store.remove(key.0.as_slice());
+    }
+
+    async fn clear(&self) {
+        self.store.clear_method(self.method);
+    }
+
+    async fn usage(&self) -> Option<CacheUsage> {
+        Some(self.store.method_usage(self.method))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    // 2 bytes key, 3 bytes value
+    fn entry(n: u8) -> ([u8; 2], JsonValue) {
+        ([n, n], json!(100 + n as u64))
+    }
+
+    fn limits(weight: u32, min_bytes: Option<u64>, max_bytes: Option<u64>) -> SharedLimits {
+        SharedLimits {
+            weight: NonZeroU32::new(weight).unwrap(),
+            min_bytes,
+            max_bytes,
+        }
+    }
+
+    #[test]
+    fn evicts_least_recently_used_across_methods() {
+        let store = SharedStore::new(15);
+        let a = store.register("a", Default::default());
+        let b = store.register("b", Default::default());
+        assert_eq!(store.register("a", Default::default()), a);
+
+        let (k1, v1) = entry(1);
+        let (k2, v2) = entry(2);
+        let (k3, v3) = entry(3);
+        let (k4, v4) = entry(4);
+        store.insert(&k1, a, v1.clone(), None);
+        store.insert(&k2, a, v2, None);
+        store.insert(&k3, b, v3.clone(), None);
+        assert_eq!(store.get(&k1), Some(v1.clone()));
+
+        // the idle method's capacity is used by the busy one
+        store.insert(&k4, b, v4.clone(), None);
+        assert_eq!(store.get(&k2), None);
+        assert_eq!(store.get(&k1), Some(v1));
+        assert_eq!(store.get(&k3), Some(v3));
+        assert_eq!(store.get(&k4), Some(v4));
+        assert_eq!(store.size(), 15);
+        assert_eq!(store.method_size(a), 5);
+        assert_eq!(store.method_size(b), 10);
+
+        store.remove(&k1);
+        assert_eq!(store.get(&k1), None);
+        assert_eq!(store.size(), 10);
+
+        store.clear_method(b);
+        assert_eq!(store.method_usage(b), CacheUsage::default());
+        assert_eq!(store.size(), 0);
+    }
+
+    #[test]
+    fn weights_floors_and_ceilings() {
+        let store = SharedStore::new(15);
+        let heavy = store.register("heavy", limits(3, None, None));
+        let floor = store.register("floor", limits(1, Some(5), None));
+        let plain = store.register("plain", Default::default());
+
+        let (k1, v1) = entry(1);
+        let (k2, v2) = entry(2);
+        let (k3, v3) = entry(3);
+        let (k4, v4) = entry(4);
+        let (k5, v5) = entry(5);
+        store.insert(&k1, heavy, v1.clone(), None);
+        store.insert(&k2, floor, v2.clone(), None);
+        store.insert(&k3, plain, v3, None);
+        store.insert(&k4, plain, v4, None);
+        store.insert(&k5, plain, v5.clone(), None);
+
+        // the weight outranks more recent entries, the floor is kept
+        assert_eq!(store.get(&k1), Some(v1));
+        assert_eq!(store.get(&k2), Some(v2));
+        assert_eq!(store.get(&k3), None);
+        assert_eq!(store.get(&k4), None);
+        assert_eq!(store.get(&k5), Some(v5.clone()));
+
+        // over its ceiling, a method evicts its own entries
+        let ceiling = store.register("ceiling", limits(1, None, Some(5)));
+        let (k6, v6) = entry(6);
+        let (k7, v7) = entry(7);
+        store.insert(&k6, ceiling, v6, None);
+        store.insert(&k7, ceiling, v7.clone(), None);
+        assert_eq!(store.get(&k6), None);
+        assert_eq!(store.get(&k7), Some(v7));
+        assert_eq!(store.method_size(ceiling), 5);
+    }
+
+    #[test]
+    fn expires_per_method() {
+        let store = SharedStore::new(100);
+        let method = store.register("a", Default::default());
+        let (k1, v1) = entry(1);
+        let (k2, v2) = entry(2);
+        store.insert(&k1, method, v1, Some(Duration::ZERO));
+        store.insert(&k2, method, v2.clone(), Some(Duration::from_secs(60)));
+        assert_eq!(store.get(&k1), None);
+        assert_eq!(store.get(&k2), Some(v2));
+        assert_eq!(store.size(), 5);
+    }
+
+    #[test]
+    fn evicts_expired_entries_first() {
+        let store = SharedStore::new(10);
+        let method = store.register("a", Default::default());
+        let (k1, v1) = entry(1);
+        let (k2, v2) = entry(2);
+        let (k3, v3) = entry(3);
+        store.insert(&k1, method, v1.clone(), Some(Duration::from_secs(60)));
+        store.insert(&k2, method, v2, Some(Duration::ZERO));
+
+        // k1 is the least recently used, but k2 expired
+        store.insert(&k3, method, v3.clone(), Some(Duration::from_secs(60)));
+        assert_eq!(store.size(), 10);
+        assert_eq!(store.get(&k1), Some(v1));
+        assert_eq!(store.get(&k2), None);
+        assert_eq!(store.get(&k3), Some(v3));
+    }
+
+    #[test]
+    #[should_panic(expected = "exceed the shared max_bytes")]
+    fn floors_must_fit() {
+        let store = SharedStore::new(10);
+        store.register("a", limits(1, Some(6), None));
+        store.register("b", limits(1, Some(6), None));
+    }
+}
@@ -0,0 +1,15 @@
+use ipnet::IpNet;
+use std::{net::IpAddr, str::FromStr};
+
+/// Parses a list of CIDR ranges. Plain IPs are treated as single-address ranges.
+pub fn parse_ip_nets<'a>(entries: impl IntoIterator<Item = &'a str>) -> anyhow::Result<Vec<IpNet>> {
+    entries
+        .into_iter()
+        .map(|entry| {
+            IpNet::from_str(entry)
+                .or_else(|_| IpAddr::from_str(entry).map(IpNet::from))
+                .map(|net| net.trunc())
+                .map_err(|_| anyhow::anyhow!("Invalid IP or CIDR: {entry}"))
+        })
+        .collect()
+}
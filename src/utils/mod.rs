@@ -1,10 +1,13 @@
 mod address_rule;
 mod cache;
+mod ip_net;
+pub mod redis;
 mod to_address;
 mod type_registry;
 
 pub use address_rule::*;
 pub use cache::*;
+pub use ip_net::*;
 pub use to_address::*;
 pub use type_registry::*;
 
@@ -12,7 +15,7 @@ pub mod errors {
     use jsonrpsee::types::{
         error::{
             CALL_EXECUTION_FAILED_CODE, INTERNAL_ERROR_CODE, INTERNAL_ERROR_MSG, INVALID_PARAMS_CODE,
-            INVALID_PARAMS_MSG,
+            INVALID_PARAMS_MSG, SERVER_IS_BUSY_CODE, SERVER_IS_BUSY_MSG,
         },
         ErrorObjectOwned,
     };
@@ -33,6 +36,21 @@ pub mod errors {
         ErrorObjectOwned::owned(INTERNAL_ERROR_CODE, INTERNAL_ERROR_MSG, Some(msg.to_string()))
     }
 
+    pub fn server_busy<T: ToString>(msg: T) -> ErrorObjectOwned {
+        ErrorObjectOwned::owned(SERVER_IS_BUSY_CODE, SERVER_IS_BUSY_MSG, Some(msg.to_string()))
+    }
+
+    pub fn rate_limited(limit: u32, retry_after: std::time::Duration) -> ErrorObjectOwned {
+        ErrorObjectOwned::owned(
+            SERVER_IS_BUSY_CODE,
+            "Rate limit exceeded",
+            Some(serde_json::json!({
+                "limit": limit,
+                "retry_after_millis": retry_after.as_millis() as u64,
+            })),
+        )
+    }
+
     pub fn map_error(err: jsonrpsee::core::client::Error) -> ErrorObjectOwned {
         use jsonrpsee::core::client::Error::*;
         match err {